@@ -0,0 +1,95 @@
+//! # Admin Server
+//! Small HTTP server exposing liveness and readiness of the client for orchestration, e.g.
+//! Kubernetes probes. Both endpoints return the full `HealthReport` as JSON, with a 503 status when
+//! the check fails.
+
+use std::net::SocketAddr;
+
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use eyre::{Context, Result};
+
+use crate::health::{HealthReport, HealthState};
+
+pub const DEFAULT_ADMIN_SERVER_ADDR: &str = "0.0.0.0:9201";
+
+async fn healthz(State(health): State<HealthState>) -> (StatusCode, Json<HealthReport>) {
+    to_response(health.liveness())
+}
+
+async fn readyz(State(health): State<HealthState>) -> (StatusCode, Json<HealthReport>) {
+    to_response(health.readiness())
+}
+
+fn to_response(report: HealthReport) -> (StatusCode, Json<HealthReport>) {
+    let status = if report.ok {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
+
+/// Serves `/healthz` and `/readyz` from `health`.
+pub fn router(health: HealthState) -> Router {
+    Router::new()
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
+        .with_state(health)
+}
+
+/// Serves `router` on `addr` until the server fails.
+pub async fn serve(addr: SocketAddr, health: HealthState) -> Result<()> {
+    log::info!("Starting admin server on: {}", addr);
+    axum::Server::try_bind(&addr)
+        .wrap_err_with(|| format!("Failed to bind admin server to {}", addr))?
+        .serve(router(health).into_make_service())
+        .await
+        .wrap_err("Admin server stopped")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{health::Subsystem, slot::Slot};
+
+    async fn spawn_admin_server(health: HealthState) -> SocketAddr {
+        let server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap())
+            .serve(router(health).into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test]
+    async fn healthz_reports_live_scheduler() -> Result<()> {
+        let health = HealthState::new();
+        health.record_tick(Slot(42));
+        let addr = spawn_admin_server(health).await;
+
+        let response = reqwest::get(format!("http://{}/healthz", addr)).await?;
+
+        assert_eq!(response.status(), reqwest::StatusCode::OK);
+        let body: serde_json::Value = response.json().await?;
+        assert_eq!(body["current_slot"], 42);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn readyz_is_unavailable_without_broadcasts() -> Result<()> {
+        let health = HealthState::new();
+        health.record_success(Subsystem::Signer);
+        health.record_error(Subsystem::PriceProvider, &eyre::eyre!("gofer down"));
+        let addr = spawn_admin_server(health).await;
+
+        let response = reqwest::get(format!("http://{}/readyz", addr)).await?;
+
+        assert_eq!(response.status(), reqwest::StatusCode::SERVICE_UNAVAILABLE);
+        let body: serde_json::Value = response.json().await?;
+        assert_eq!(
+            body["subsystems"]["price_provider"]["last_error"]["message"],
+            "gofer down"
+        );
+        assert!(body["subsystems"]["signer"]["last_success"].is_string());
+        Ok(())
+    }
+}
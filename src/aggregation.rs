@@ -0,0 +1,339 @@
+//! # Aggregation
+//! Combines the interval inclusion signatures of many validators for one slot into a single BLS
+//! signature per interval value, the form a contract would check consensus on. Only values signed
+//! by at least a threshold of validators are kept, each with a bitfield of who took part.
+//! `MessageVersion::V2` messages only sign their interval bounds, which can't be folded into per
+//! value aggregates, so they're returned alongside as they are. Signatures aren't checked while
+//! aggregating, callers should only pass verified messages, e.g. those accepted by
+//! `verify_oracle_message`.
+
+use std::collections::BTreeMap;
+
+use bls::{AggregateSignature, PublicKey};
+use eyre::Result;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use ssz::Encode;
+
+use crate::{
+    message_broadcaster::{IntervalInclusionMessage, OracleMessage, SignedIntervalBoundsMessage},
+    signature_provider::private_key::message_digest,
+};
+
+/// Which validators took part, bit `i` is set when validator `i` did. Bits are ordered least
+/// significant first within each byte, like the aggregation bits of beacon chain attestations.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParticipantBitfield(Vec<u8>);
+
+impl ParticipantBitfield {
+    pub fn with_capacity(len: usize) -> ParticipantBitfield {
+        ParticipantBitfield(vec![0; len.div_ceil(8)])
+    }
+
+    pub fn set(&mut self, i: usize) {
+        self.0[i / 8] |= 1 << (i % 8);
+    }
+
+    pub fn get(&self, i: usize) -> bool {
+        self.0
+            .get(i / 8)
+            .is_some_and(|byte| byte & (1 << (i % 8)) != 0)
+    }
+
+    pub fn count(&self) -> usize {
+        self.0.iter().map(|byte| byte.count_ones() as usize).sum()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl Serialize for ParticipantBitfield {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("0x{}", hex::encode(&self.0)))
+    }
+}
+
+impl<'de> Deserialize<'de> for ParticipantBitfield {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        hex::decode(s.trim_start_matches("0x"))
+            .map(ParticipantBitfield)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct IntervalValueAggregate {
+    /// The message every participant signed.
+    pub message: IntervalInclusionMessage,
+    /// Indexes into `SlotAggregate::validator_public_keys`.
+    pub participants: ParticipantBitfield,
+    pub aggregate_signature: AggregateSignature,
+}
+
+/// The signed bounds of a `MessageVersion::V2` message.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ValidatorIntervalBounds {
+    pub validator_public_key: PublicKey,
+    pub interval_bounds_message: SignedIntervalBoundsMessage,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SlotAggregate {
+    pub slot: u64,
+    /// Every validator with interval inclusion signatures for the slot, sorted by key.
+    pub validator_public_keys: Vec<PublicKey>,
+    /// Ordered by interval size, then value.
+    pub interval_value_aggregates: Vec<IntervalValueAggregate>,
+    /// Validators that only signed their interval bounds, sorted by key.
+    #[serde(default)]
+    pub interval_bounds: Vec<ValidatorIntervalBounds>,
+}
+
+impl IntervalValueAggregate {
+    pub fn participant_public_keys<'a>(
+        &'a self,
+        validator_public_keys: &'a [PublicKey],
+    ) -> impl Iterator<Item = &'a PublicKey> {
+        validator_public_keys
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| self.participants.get(*i))
+            .map(|(_, public_key)| public_key)
+    }
+}
+
+impl SlotAggregate {
+    /// Checks every aggregate signature against its participants, returns the values that fail.
+    pub fn verify(&self) -> Vec<u64> {
+        self.interval_value_aggregates
+            .iter()
+            .filter(|aggregate| {
+                let public_keys = aggregate
+                    .participant_public_keys(&self.validator_public_keys)
+                    .collect::<Vec<_>>();
+                public_keys.is_empty()
+                    || aggregate.message.slot_number != self.slot
+                    || !aggregate.aggregate_signature.fast_aggregate_verify(
+                        message_digest(&aggregate.message.as_ssz_bytes()),
+                        &public_keys,
+                    )
+            })
+            .map(|aggregate| aggregate.message.value)
+            .collect()
+    }
+}
+
+/// Aggregates the interval inclusion signatures of all messages for `slot`, keeping the values
+/// signed by at least `threshold` validators. Validators only agree on a value when they also
+/// used the same interval size, so each size is aggregated separately.
+pub fn aggregate_slot(
+    slot: u64,
+    oracle_messages: &[OracleMessage],
+    threshold: usize,
+) -> Result<SlotAggregate> {
+    let mut validators = BTreeMap::new();
+    for oracle_message in oracle_messages.iter() {
+        let message_slot = oracle_message.value_message.message.slot_number;
+        if message_slot != slot {
+            eyre::bail!(
+                "message from {} is for slot {}, expected {}",
+                oracle_message.validator_public_key,
+                message_slot,
+                slot
+            );
+        }
+        let public_key_bytes = oracle_message.validator_public_key.serialize();
+        if validators
+            .insert(public_key_bytes, oracle_message)
+            .is_some()
+        {
+            eyre::bail!(
+                "more than one message from {} for slot {}",
+                oracle_message.validator_public_key,
+                slot
+            );
+        }
+    }
+
+    let (bounds_validators, validators): (BTreeMap<_, _>, BTreeMap<_, _>) =
+        validators.into_iter().partition(|(_, oracle_message)| {
+            oracle_message.interval_inclusion_messages.is_empty()
+                && oracle_message.interval_bounds_message.is_some()
+        });
+
+    let mut aggregates: BTreeMap<(u64, u64), IntervalValueAggregate> = BTreeMap::new();
+    for (i, oracle_message) in validators.values().enumerate() {
+        for signed_message in oracle_message.interval_inclusion_messages.iter() {
+            let message = &signed_message.message;
+            let aggregate = aggregates
+                .entry((message.interval_size, message.value))
+                .or_insert_with(|| IntervalValueAggregate {
+                    message: message.clone(),
+                    participants: ParticipantBitfield::with_capacity(validators.len()),
+                    aggregate_signature: AggregateSignature::infinity(),
+                });
+            if message.slot_number != slot || aggregate.participants.get(i) {
+                eyre::bail!(
+                    "invalid interval inclusion message from {} for value {}",
+                    oracle_message.validator_public_key,
+                    message.value
+                );
+            }
+            aggregate.participants.set(i);
+            aggregate
+                .aggregate_signature
+                .add_assign(&signed_message.signature);
+        }
+    }
+
+    Ok(SlotAggregate {
+        slot,
+        validator_public_keys: validators
+            .values()
+            .map(|oracle_message| oracle_message.validator_public_key.clone())
+            .collect(),
+        interval_value_aggregates: aggregates
+            .into_values()
+            .filter(|aggregate| aggregate.participants.count() >= threshold)
+            .collect(),
+        interval_bounds: bounds_validators
+            .values()
+            .map(|oracle_message| ValidatorIntervalBounds {
+                validator_public_key: oracle_message.validator_public_key.clone(),
+                interval_bounds_message: oracle_message
+                    .interval_bounds_message
+                    .clone()
+                    .expect("expect bounds validators to have signed bounds"),
+            })
+            .collect(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        message_broadcaster::MessageVersion,
+        message_generator::{MessageGenerator, MessageGeneratorConfig},
+        price_provider::{Price, PRECISION_FACTOR},
+        signature_provider::private_key::PrivateKeySignatureProvider,
+        slot::Slot,
+    };
+
+    fn generate_messages(prices: &[u64]) -> Vec<OracleMessage> {
+        prices
+            .iter()
+            .map(|price| {
+                MessageGenerator::new(Box::new(PrivateKeySignatureProvider::random()))
+                    .generate_oracle_message(
+                        Price {
+                            value: price * PRECISION_FACTOR,
+                        },
+                        Slot(7),
+                    )
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sets_participant_bits() {
+        let mut participants = ParticipantBitfield::with_capacity(10);
+        participants.set(0);
+        participants.set(9);
+
+        assert_eq!(participants.as_bytes(), &[0b0000_0001, 0b0000_0010]);
+        assert!(participants.get(9));
+        assert!(!participants.get(8));
+        assert!(!participants.get(100));
+        assert_eq!(participants.count(), 2);
+        assert_eq!(
+            serde_json::to_value(&participants).unwrap(),
+            serde_json::json!("0x0102")
+        );
+    }
+
+    #[test]
+    fn keeps_values_signed_by_threshold() {
+        let oracle_messages = generate_messages(&[1000, 1001, 1002]);
+
+        let slot_aggregate = aggregate_slot(7, &oracle_messages, 2).unwrap();
+
+        // At least two of the three intervals cover 998.99 to 1003.00.
+        let values = slot_aggregate
+            .interval_value_aggregates
+            .iter()
+            .map(|aggregate| aggregate.message.value)
+            .collect::<Vec<_>>();
+        assert_eq!(values.first(), Some(&99_899));
+        assert_eq!(values.last(), Some(&100_299));
+        assert_eq!(values.len(), 401);
+        let full_participation = slot_aggregate
+            .interval_value_aggregates
+            .iter()
+            .filter(|aggregate| aggregate.participants.count() == 3)
+            .count();
+        assert_eq!(full_participation, 201);
+        assert!(slot_aggregate.verify().is_empty());
+    }
+
+    #[test]
+    fn detects_invalid_aggregates() {
+        let oracle_messages = generate_messages(&[1000, 1000]);
+        let mut slot_aggregate = aggregate_slot(7, &oracle_messages, 2).unwrap();
+
+        let forged_aggregate = &mut slot_aggregate.interval_value_aggregates[0];
+        forged_aggregate.participants = ParticipantBitfield::with_capacity(2);
+        forged_aggregate.participants.set(0);
+
+        assert_eq!(slot_aggregate.verify(), vec![99_800]);
+    }
+
+    #[test]
+    fn returns_interval_bounds_next_to_aggregates() {
+        let mut oracle_messages = generate_messages(&[1000, 1000]);
+        let v2_message = MessageGenerator::new_with_config(
+            Box::new(PrivateKeySignatureProvider::random()),
+            MessageGeneratorConfig {
+                message_version: MessageVersion::V2,
+                ..MessageGeneratorConfig::default()
+            },
+        )
+        .generate_oracle_message(
+            Price {
+                value: 1000 * PRECISION_FACTOR,
+            },
+            Slot(7),
+        )
+        .unwrap();
+        oracle_messages.push(v2_message.clone());
+
+        let slot_aggregate = aggregate_slot(7, &oracle_messages, 2).unwrap();
+
+        assert_eq!(slot_aggregate.validator_public_keys.len(), 2);
+        assert!(!slot_aggregate
+            .validator_public_keys
+            .contains(&v2_message.validator_public_key));
+        assert!(slot_aggregate
+            .interval_value_aggregates
+            .iter()
+            .all(|aggregate| aggregate.participants.count() == 2));
+        assert_eq!(slot_aggregate.interval_bounds.len(), 1);
+        assert_eq!(
+            slot_aggregate.interval_bounds[0].validator_public_key,
+            v2_message.validator_public_key
+        );
+        assert!(slot_aggregate.verify().is_empty());
+    }
+
+    #[test]
+    fn rejects_duplicate_validators_and_other_slots() {
+        let oracle_messages = generate_messages(&[1000]);
+        assert!(aggregate_slot(8, &oracle_messages, 1).is_err());
+
+        let duplicated = vec![oracle_messages[0].clone(), oracle_messages[0].clone()];
+        assert!(aggregate_slot(7, &duplicated, 1).is_err());
+    }
+}
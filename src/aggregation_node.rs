@@ -0,0 +1,562 @@
+//! # Aggregation Node
+//! Lets every operator work out the network's consensus price themselves instead of trusting the
+//! oracle-server. The `MessageCollector` keeps the messages of all validators for the most recent
+//! slots, our own and those gossiped by peers, which the p2p node only passes on after
+//! `verify_oracle_message` accepted them. Anyone can make up keys, so only keys the beacon node
+//! confirms as active validators are kept. Per slot it finds the interval most validators agree
+//! on, like the server's aggregation, and serves it over HTTP together with the aggregate
+//! signatures of the agreeing validators, so clients can check it without trusting this node.
+//! Validators only agree when they used the same interval size, and between equally supported
+//! intervals the lowest wins.
+
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    num::NonZeroU64,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::get,
+    Json, Router,
+};
+use bls::PublicKey;
+use eyre::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::{
+    aggregation::{aggregate_slot, SlotAggregate},
+    beacon_node::ActiveValidatorCache,
+    message_broadcaster::{
+        response::ErrorResponse, MessageBroadcaster, OracleMessage, SignedOracleRegistration,
+    },
+};
+
+pub const DEFAULT_AGGREGATION_NODE_ADDR: &str = "0.0.0.0:9202";
+/// About ten minutes.
+pub const DEFAULT_RETAINED_SLOTS: u64 = 50;
+
+/// The interval most validators agree on for a slot.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ConsensusInterval {
+    pub slot: u64,
+    pub interval_size: u64,
+    pub lower_bound: u64,
+    /// Exclusive, like `IntervalBoundsMessage::upper_bound`.
+    pub upper_bound: u64,
+    /// Validators whose interval covers the whole consensus interval, sorted by key.
+    pub validator_public_keys: Vec<PublicKey>,
+    /// Validators with a message for the slot, agreeing or not.
+    pub total_validators: usize,
+    /// The signatures of the agreeing validators, per value within the interval, and the signed
+    /// bounds of those that only signed their bounds.
+    pub aggregate: SlotAggregate,
+}
+
+/// Finds the interval covered by the most messages, `None` without any. Messages are expected to
+/// be verified and for `slot`, at most one per validator, aggregating them errors otherwise.
+pub fn consensus_interval(
+    slot: u64,
+    oracle_messages: &[OracleMessage],
+) -> Result<Option<ConsensusInterval>> {
+    let mut by_interval_size = BTreeMap::new();
+    for oracle_message in oracle_messages {
+        if let Some(bounds) = oracle_message.interval_bounds() {
+            by_interval_size
+                .entry(bounds.interval_size)
+                .or_insert_with(Vec::new)
+                .push((bounds, oracle_message));
+        }
+    }
+
+    let mut best: Option<(usize, u64, u64, u64)> = None;
+    for (interval_size, intervals) in by_interval_size.iter() {
+        // How the number of covering intervals changes at each bound.
+        let mut changes: BTreeMap<u64, i64> = BTreeMap::new();
+        for (bounds, _) in intervals {
+            *changes.entry(bounds.lower_bound).or_default() += 1;
+            *changes.entry(bounds.upper_bound).or_default() -= 1;
+        }
+        let mut count = 0;
+        let mut run_start = None;
+        for (bound, change) in changes {
+            if let Some(start) = run_start.take() {
+                // The run of the best count so far ends here.
+                let (best_count, ..) = best.expect("expect a run to be the best");
+                if count + change != best_count as i64 {
+                    best = Some((best_count, *interval_size, start, bound));
+                } else {
+                    run_start = Some(start);
+                }
+            }
+            count += change;
+            if count > 0 && !matches!(best, Some((best_count, ..)) if best_count >= count as usize)
+            {
+                best = Some((count as usize, *interval_size, bound, bound));
+                run_start = Some(bound);
+            }
+        }
+    }
+
+    let Some((_, interval_size, lower_bound, upper_bound)) = best else {
+        return Ok(None);
+    };
+    let agreeing_messages = by_interval_size[&interval_size]
+        .iter()
+        .filter(|(bounds, _)| {
+            bounds.lower_bound <= lower_bound && bounds.upper_bound >= upper_bound
+        })
+        .map(|(_, oracle_message)| (*oracle_message).clone())
+        .collect::<Vec<_>>();
+    let mut validator_public_keys = agreeing_messages
+        .iter()
+        .map(|oracle_message| oracle_message.validator_public_key.clone())
+        .collect::<Vec<_>>();
+    validator_public_keys.sort_by_key(|public_key| public_key.serialize());
+    // Every agreeing validator signed each value within the interval.
+    let mut aggregate = aggregate_slot(slot, &agreeing_messages, 1)?;
+    aggregate.interval_value_aggregates.retain(|aggregate| {
+        aggregate.message.interval_size == interval_size
+            && (lower_bound..upper_bound).contains(&aggregate.message.value)
+    });
+    Ok(Some(ConsensusInterval {
+        slot,
+        interval_size,
+        lower_bound,
+        upper_bound,
+        validator_public_keys,
+        total_validators: oracle_messages.len(),
+        aggregate,
+    }))
+}
+
+/// Messages of active validators for the most recent slots, by slot and then by the validator's
+/// public key.
+#[derive(Clone)]
+pub struct MessageCollector {
+    slots: Arc<Mutex<BTreeMap<u64, BTreeMap<String, OracleMessage>>>>,
+    retained_slots: NonZeroU64,
+    active_validators: ActiveValidatorCache,
+}
+
+impl MessageCollector {
+    /// Keeps the messages of the last `retained_slots` slots, from active validators only.
+    pub fn new(
+        retained_slots: NonZeroU64,
+        active_validators: ActiveValidatorCache,
+    ) -> MessageCollector {
+        MessageCollector {
+            slots: Arc::new(Mutex::new(BTreeMap::new())),
+            retained_slots,
+            active_validators,
+        }
+    }
+
+    /// Keeps the first message of every active validator per slot, returns whether `msg` was
+    /// kept.
+    pub async fn collect(&self, msg: OracleMessage) -> bool {
+        match self
+            .active_validators
+            .is_active(&msg.validator_public_key)
+            .await
+        {
+            Ok(true) => self.record(msg),
+            Ok(false) => {
+                log::debug!(
+                    "Ignoring message of {}, not an active validator",
+                    msg.validator_public_key
+                );
+                false
+            }
+            Err(e) => {
+                log::warn!(
+                    "Ignoring message of {}, failed to check whether it's an active validator - {:?}",
+                    msg.validator_public_key,
+                    e
+                );
+                false
+            }
+        }
+    }
+
+    /// Keeps the first message of every validator per slot, returns whether `msg` was new.
+    fn record(&self, msg: OracleMessage) -> bool {
+        let slot = msg.value_message.message.slot_number;
+        let mut slots = self.slots.lock().unwrap();
+        let newest_slot = slots
+            .keys()
+            .next_back()
+            .map_or(slot, |&newest| newest.max(slot));
+        let oldest_slot = newest_slot.saturating_sub(self.retained_slots.get() - 1);
+        if slot < oldest_slot {
+            return false;
+        }
+        *slots = slots.split_off(&oldest_slot);
+
+        let messages = slots.entry(slot).or_default();
+        let validator = msg.validator_public_key.to_string();
+        if let Some(existing) = messages.get(&validator) {
+            if existing.value_message.message.price.value != msg.value_message.message.price.value {
+                log::warn!(
+                    "{} signed more than one price for slot {}, keeping the first",
+                    validator,
+                    slot
+                );
+            }
+            return false;
+        }
+        messages.insert(validator, msg);
+        true
+    }
+
+    /// The slots with messages, oldest first.
+    pub fn slots(&self) -> Vec<u64> {
+        self.slots.lock().unwrap().keys().copied().collect()
+    }
+
+    /// Every message collected for `slot`, sorted by key.
+    pub fn messages(&self, slot: u64) -> Vec<OracleMessage> {
+        self.slots
+            .lock()
+            .unwrap()
+            .get(&slot)
+            .map(|messages| messages.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// See `consensus_interval`.
+    pub fn consensus(&self, slot: u64) -> Result<Option<ConsensusInterval>> {
+        consensus_interval(slot, &self.messages(slot))
+    }
+
+    /// Records the messages received from peers until the sender is gone.
+    pub async fn run(&self, mut received: broadcast::Receiver<OracleMessage>) {
+        loop {
+            match received.recv().await {
+                Ok(msg) => {
+                    self.collect(msg).await;
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    log::warn!("Collector fell behind, missed {} peer messages", skipped);
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+/// Records our own messages in the collector before handing them to `inner`, peers don't gossip
+/// them back to us.
+pub struct CollectingMessageBroadcaster<B> {
+    inner: B,
+    collector: MessageCollector,
+}
+
+impl<B> CollectingMessageBroadcaster<B> {
+    pub fn new(inner: B, collector: MessageCollector) -> CollectingMessageBroadcaster<B> {
+        CollectingMessageBroadcaster { inner, collector }
+    }
+}
+
+#[async_trait]
+impl<B: MessageBroadcaster + Send + Sync> MessageBroadcaster for CollectingMessageBroadcaster<B> {
+    async fn broadcast(&self, msg: &OracleMessage) -> Result<()> {
+        self.collector.collect(msg.clone()).await;
+        self.inner.broadcast(msg).await
+    }
+
+    async fn broadcast_registration(&self, registration: &SignedOracleRegistration) -> Result<()> {
+        self.inner.broadcast_registration(registration).await
+    }
+}
+
+fn error_response(status: StatusCode, message: String) -> (StatusCode, Json<ErrorResponse>) {
+    (
+        status,
+        Json(ErrorResponse {
+            error: message,
+            violations: Vec::new(),
+        }),
+    )
+}
+
+/// The consensus of `slot`, or the response explaining why there is none.
+fn consensus_response(
+    collector: &MessageCollector,
+    slot: u64,
+) -> std::result::Result<Json<ConsensusInterval>, (StatusCode, Json<ErrorResponse>)> {
+    match collector.consensus(slot) {
+        Ok(Some(consensus)) => Ok(Json(consensus)),
+        Ok(None) => Err(error_response(
+            StatusCode::NOT_FOUND,
+            format!("no messages for slot {}", slot),
+        )),
+        Err(e) => Err(error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("failed to aggregate slot {}: {}", slot, e),
+        )),
+    }
+}
+
+async fn get_slots(State(collector): State<MessageCollector>) -> Json<Vec<u64>> {
+    Json(collector.slots())
+}
+
+async fn get_latest(
+    State(collector): State<MessageCollector>,
+) -> std::result::Result<Json<ConsensusInterval>, (StatusCode, Json<ErrorResponse>)> {
+    match collector.slots().last() {
+        Some(&slot) => consensus_response(&collector, slot),
+        None => Err(error_response(
+            StatusCode::NOT_FOUND,
+            "no messages yet".to_string(),
+        )),
+    }
+}
+
+async fn get_slot(
+    State(collector): State<MessageCollector>,
+    Path(slot): Path<u64>,
+) -> std::result::Result<Json<ConsensusInterval>, (StatusCode, Json<ErrorResponse>)> {
+    consensus_response(&collector, slot)
+}
+
+async fn get_slot_messages(
+    State(collector): State<MessageCollector>,
+    Path(slot): Path<u64>,
+) -> Json<Vec<OracleMessage>> {
+    Json(collector.messages(slot))
+}
+
+/// Serves the collected messages and their consensus.
+pub fn router(collector: MessageCollector) -> Router {
+    Router::new()
+        .route("/latest", get(get_latest))
+        .route("/slots", get(get_slots))
+        .route("/slots/:slot", get(get_slot))
+        .route("/slots/:slot/messages", get(get_slot_messages))
+        .with_state(collector)
+}
+
+/// Serves `router` on `addr` until the server fails.
+pub async fn serve(addr: SocketAddr, collector: MessageCollector) -> Result<()> {
+    log::info!("Starting aggregation node API on: {}", addr);
+    axum::Server::try_bind(&addr)
+        .wrap_err_with(|| format!("Failed to bind aggregation node API to {}", addr))?
+        .serve(router(collector).into_make_service())
+        .await
+        .wrap_err("Aggregation node API stopped")
+}
+
+#[cfg(test)]
+mod tests {
+    use mockito::Matcher;
+
+    use super::*;
+    use crate::{
+        beacon_node::BeaconNodeClient,
+        message_generator::{MessageGenerator, MessageGeneratorConfig},
+        price_provider::{Price, PRECISION_FACTOR},
+        signature_provider::private_key::PrivateKeySignatureProvider,
+        slot::Slot,
+        MessageVersion,
+    };
+
+    fn generate_message_with_version(
+        price: u64,
+        slot: u64,
+        message_version: MessageVersion,
+    ) -> OracleMessage {
+        MessageGenerator::new_with_config(
+            Box::new(PrivateKeySignatureProvider::random()),
+            MessageGeneratorConfig {
+                message_version,
+                ..MessageGeneratorConfig::default()
+            },
+        )
+        .generate_oracle_message(
+            Price {
+                value: price * PRECISION_FACTOR,
+            },
+            Slot(slot),
+        )
+        .unwrap()
+    }
+
+    fn generate_message(price: u64, slot: u64) -> OracleMessage {
+        generate_message_with_version(price, slot, MessageVersion::V2)
+    }
+
+    /// Answers whether each key is an active validator, like a beacon node.
+    async fn mock_beacon_node(
+        server: &mut mockito::Server,
+        validators: &[(&PublicKey, bool)],
+    ) -> ActiveValidatorCache {
+        for (public_key, is_active) in validators {
+            let body = if *is_active {
+                format!(
+                    r#"{{"data": [{{"index": "1", "status": "active_ongoing", "validator": {{ "pubkey": "{}" }}}}]}}"#,
+                    public_key
+                )
+            } else {
+                r#"{"data": []}"#.to_string()
+            };
+            server
+                .mock("GET", "/eth/v1/beacon/states/head/validators")
+                .match_query(Matcher::UrlEncoded(
+                    "id".to_string(),
+                    public_key.to_string(),
+                ))
+                .with_header("content-type", "application/json")
+                .with_body(body)
+                .create_async()
+                .await;
+        }
+        ActiveValidatorCache::new(BeaconNodeClient::new(&server.url()))
+    }
+
+    #[test]
+    fn finds_interval_with_most_agreement() {
+        let oracle_messages = [1000, 1001, 1002, 2000]
+            .iter()
+            .map(|&price| generate_message(price, 7))
+            .collect::<Vec<_>>();
+
+        let consensus = consensus_interval(7, &oracle_messages).unwrap().unwrap();
+
+        let bounds = oracle_messages
+            .iter()
+            .map(|msg| msg.interval_bounds().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(consensus.lower_bound, bounds[2].lower_bound);
+        assert_eq!(consensus.upper_bound, bounds[0].upper_bound);
+        assert_eq!(consensus.interval_size, bounds[0].interval_size);
+        assert_eq!(consensus.validator_public_keys.len(), 3);
+        assert!(!consensus
+            .validator_public_keys
+            .contains(&oracle_messages[3].validator_public_key));
+        assert_eq!(consensus.total_validators, 4);
+        assert_eq!(consensus.aggregate.interval_bounds.len(), 3);
+        assert!(consensus_interval(7, &[]).unwrap().is_none());
+    }
+
+    #[test]
+    fn attaches_aggregate_of_agreeing_validators() {
+        let oracle_messages = [1000, 1001, 2000]
+            .iter()
+            .map(|&price| generate_message_with_version(price, 7, MessageVersion::V1))
+            .collect::<Vec<_>>();
+
+        let consensus = consensus_interval(7, &oracle_messages).unwrap().unwrap();
+
+        let aggregate = &consensus.aggregate;
+        assert_eq!(
+            aggregate.validator_public_keys,
+            consensus.validator_public_keys
+        );
+        assert_eq!(
+            aggregate.interval_value_aggregates.len() as u64,
+            consensus.upper_bound - consensus.lower_bound
+        );
+        for value_aggregate in aggregate.interval_value_aggregates.iter() {
+            assert!((consensus.lower_bound..consensus.upper_bound)
+                .contains(&value_aggregate.message.value));
+            assert_eq!(value_aggregate.participants.count(), 2);
+        }
+        assert!(aggregate.verify().is_empty());
+    }
+
+    #[test]
+    fn prefers_lowest_of_equally_supported_intervals() {
+        let oracle_messages = [1000, 2000]
+            .iter()
+            .map(|&price| generate_message(price, 7))
+            .collect::<Vec<_>>();
+
+        let consensus = consensus_interval(7, &oracle_messages).unwrap().unwrap();
+
+        let bounds = oracle_messages[0].interval_bounds().unwrap();
+        assert_eq!(
+            (consensus.lower_bound, consensus.upper_bound),
+            (bounds.lower_bound, bounds.upper_bound)
+        );
+        assert_eq!(
+            consensus.validator_public_keys,
+            vec![oracle_messages[0].validator_public_key.clone()]
+        );
+    }
+
+    #[test]
+    fn keeps_recent_slots_only() {
+        // Never asked, messages are recorded directly.
+        let active_validators =
+            ActiveValidatorCache::new(BeaconNodeClient::new("http://localhost"));
+        let collector = MessageCollector::new(NonZeroU64::new(2).unwrap(), active_validators);
+        let msg = generate_message(1000, 7);
+
+        assert!(collector.record(msg.clone()));
+        assert!(!collector.record(msg));
+        assert!(collector.record(generate_message(1000, 8)));
+        assert!(collector.record(generate_message(1000, 9)));
+        assert!(!collector.record(generate_message(1000, 7)));
+
+        assert_eq!(collector.slots(), vec![8, 9]);
+    }
+
+    #[tokio::test]
+    async fn serves_consensus_of_active_validators() -> Result<()> {
+        let oracle_messages = [1000, 1001, 1002]
+            .iter()
+            .map(|&price| generate_message(price, 7))
+            .collect::<Vec<_>>();
+        let mut beacon_node = mockito::Server::new_async().await;
+        let active_validators = mock_beacon_node(
+            &mut beacon_node,
+            &[
+                (&oracle_messages[0].validator_public_key, true),
+                (&oracle_messages[1].validator_public_key, true),
+                (&oracle_messages[2].validator_public_key, false),
+            ],
+        )
+        .await;
+        let collector = MessageCollector::new(
+            NonZeroU64::new(DEFAULT_RETAINED_SLOTS).unwrap(),
+            active_validators,
+        );
+        let (sender, receiver) = broadcast::channel(16);
+        let running_collector = collector.clone();
+        let collecting = tokio::spawn(async move { running_collector.run(receiver).await });
+        for oracle_message in oracle_messages.iter() {
+            sender.send(oracle_message.clone())?;
+        }
+        drop(sender);
+        collecting.await?;
+        let server = axum::Server::bind(&"127.0.0.1:0".parse()?)
+            .serve(router(collector.clone()).into_make_service());
+        let addr = server.local_addr();
+        tokio::spawn(server);
+
+        let latest: ConsensusInterval = reqwest::get(format!("http://{}/latest", addr))
+            .await?
+            .json()
+            .await?;
+        assert_eq!(
+            serde_json::to_value(&latest)?,
+            serde_json::to_value(collector.consensus(7)?)?
+        );
+        assert_eq!(latest.total_validators, 2);
+        let messages: Vec<OracleMessage> =
+            reqwest::get(format!("http://{}/slots/7/messages", addr))
+                .await?
+                .json()
+                .await?;
+        assert_eq!(messages.len(), 2);
+        let response = reqwest::get(format!("http://{}/slots/8", addr)).await?;
+        assert_eq!(response.status(), reqwest::StatusCode::NOT_FOUND);
+        Ok(())
+    }
+}
@@ -1,7 +1,7 @@
 //! # Attestation Scheduler
 //! Starts attributing to a price at the start of each slot.
-//! Depending on how we set RUN_SLOT_LIMIT_SECS we never hit the case of trying to schedule a third
-//! slot, while one is still running.
+//! Depending on how we set the attestation timeout we never hit the case of trying to schedule a
+//! third slot, while one is still running.
 
 use std::sync::{Arc, Mutex};
 
@@ -13,6 +13,8 @@ use tokio::time::{interval, timeout};
 use tokio_stream::wrappers::IntervalStream;
 
 use crate::{
+    doppelganger::DoppelgangerDetection,
+    health::{HealthState, Subsystem},
     message_broadcaster::MessageBroadcaster,
     message_generator::MessageGenerator,
     price_provider::{PriceProvider, PRECISION_FACTOR},
@@ -22,16 +24,35 @@ use crate::{
 // We set a limit,, although the fact slots appear every 12s, and attestations can take at most
 // 24s to process, means we run at most 2 attestations at any time as long as timeouts are handled
 // quickly.
-const MAX_CONCURRENT_SLOTS: usize = 2;
-const ATTESTATION_TIMEOUT: u64 = 24;
+pub const MAX_CONCURRENT_SLOTS: usize = 2;
+pub const ATTESTATION_TIMEOUT: u64 = 24;
+pub const DELAYED_START_LIMIT_MILLIS: i64 = 1000;
 const SLOT_PERIOD_DURATION_SECS: u64 = 12;
 
 lazy_static! {
     static ref SLOT_PERIOD: tokio::time::Duration =
         tokio::time::Duration::from_secs(SLOT_PERIOD_DURATION_SECS);
-    static ref ATTESTATION_TIMEOUT_DURATION: tokio::time::Duration =
-        tokio::time::Duration::from_secs(ATTESTATION_TIMEOUT);
-    static ref DELAYED_START_LIMIT: Duration = Duration::milliseconds(1000);
+}
+
+/// How much slot work may overlap and how long it may take.
+#[derive(Clone, Debug)]
+pub struct SchedulerConfig {
+    /// Slots worked on at once, a slot still running when the next starts takes one up.
+    pub max_concurrent_slots: usize,
+    /// Slots taking longer are given up on.
+    pub attestation_timeout: tokio::time::Duration,
+    /// Slots we only get to later than this into the slot are skipped.
+    pub delayed_start_limit: Duration,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        SchedulerConfig {
+            max_concurrent_slots: MAX_CONCURRENT_SLOTS,
+            attestation_timeout: tokio::time::Duration::from_secs(ATTESTATION_TIMEOUT),
+            delayed_start_limit: Duration::milliseconds(DELAYED_START_LIMIT_MILLIS),
+        }
+    }
 }
 
 /// Waits until the start of the next slot.
@@ -51,55 +72,89 @@ async fn wait_until_next_slot() {
     .await;
 }
 
+/// Fetches, signs and broadcasts the price at the start of every slot, following the system clock.
 pub struct SystemClockAttestationScheduler<A: MessageBroadcaster, B: PriceProvider> {
     message_broadcaster: A,
     message_generator: MessageGenerator,
     price_provider: B,
     slots_to_run: Arc<Mutex<Option<u64>>>,
+    health: HealthState,
+    config: SchedulerConfig,
 }
 
 impl<A: MessageBroadcaster, B: PriceProvider> SystemClockAttestationScheduler<A, B> {
+    /// Runs every slot until stopped, or for `slots_to_run` slots, with the default config.
     pub fn new(
         message_broadcaster: A,
         message_generator: MessageGenerator,
         price_provider: B,
         slots_to_run: Option<u64>,
+    ) -> Self {
+        Self::new_with_config(
+            message_broadcaster,
+            message_generator,
+            price_provider,
+            slots_to_run,
+            SchedulerConfig::default(),
+        )
+    }
+
+    pub fn new_with_config(
+        message_broadcaster: A,
+        message_generator: MessageGenerator,
+        price_provider: B,
+        slots_to_run: Option<u64>,
+        config: SchedulerConfig,
     ) -> Self {
         Self {
             message_broadcaster,
             message_generator,
             price_provider,
             slots_to_run: Arc::new(Mutex::new(slots_to_run)),
+            health: HealthState::new(),
+            config,
         }
     }
 
+    /// Handle on the health the scheduler records while running, e.g. to serve it to probes.
+    pub fn health(&self) -> HealthState {
+        self.health.clone()
+    }
+
     async fn run_single_slot(&self, slot: Slot) -> Result<()> {
         log::info!("Running for slot: {}", slot);
         let start_time = chrono::Utc::now().timestamp();
-        let price = self
-            .price_provider
-            .get_price()
-            .await
-            .wrap_err("Failed to get price data")?;
+        let price = self.health.track(
+            Subsystem::PriceProvider,
+            self.price_provider
+                .get_price()
+                .await
+                .wrap_err("Failed to get price data"),
+        )?;
         log::info!(
             "Sucessfully obtained current Eth Price: {:?} for slot {} after {} seconds",
             price.value as f64 / PRECISION_FACTOR as f64,
             slot,
             chrono::Utc::now().timestamp() - start_time,
         );
-        let oracle_message = self
-            .message_generator
-            .generate_oracle_message(price.clone(), slot)
-            .wrap_err("Failed to generated signed price message")?;
+        let oracle_message = self.health.track(
+            Subsystem::Signer,
+            self.message_generator
+                .generate_oracle_message(price.clone(), slot)
+                .wrap_err("Failed to generated signed price message"),
+        )?;
         log::info!(
             "Sucessfully generated signed price message for slot {} after {} seconds",
             slot,
             chrono::Utc::now().timestamp() - start_time
         );
-        self.message_broadcaster
-            .broadcast(&oracle_message)
-            .await
-            .wrap_err("Failed to broadcast message")?;
+        self.health.track(
+            Subsystem::Broadcaster,
+            self.message_broadcaster
+                .broadcast(&oracle_message)
+                .await
+                .wrap_err("Failed to broadcast message"),
+        )?;
         log::info!(
             "Sucessfully finished for slot {} after {} seconds",
             slot,
@@ -108,7 +163,31 @@ impl<A: MessageBroadcaster, B: PriceProvider> SystemClockAttestationScheduler<A,
         Ok(())
     }
 
+    /// Runs `doppelganger_detection` for our key, errors when another client is using it. Call it
+    /// before `run`, and before redelivering anything from an outbox, our own redelivered messages
+    /// would look like a doppelganger.
+    pub async fn detect_doppelganger(
+        &self,
+        doppelganger_detection: &DoppelgangerDetection,
+    ) -> Result<()> {
+        let public_key = self
+            .health
+            .track(Subsystem::Signer, self.message_generator.get_public_key())?;
+        doppelganger_detection.run(&public_key, &self.health).await
+    }
+
+    /// Attests every slot from the next one on, returns once `slots_to_run` slots are done.
     pub async fn run(&self) {
+        // Fail loudly early on a signer that can't even produce its public key, rather than at the
+        // first slot.
+        match self
+            .health
+            .track(Subsystem::Signer, self.message_generator.get_public_key())
+        {
+            Ok(public_key) => log::info!("Attesting with public key: {}", public_key),
+            Err(e) => log::error!("Signer failed to provide public key - {:?}", e),
+        }
+
         log::debug!("Waiting until next slot to start interval stream");
         wait_until_next_slot().await;
 
@@ -138,17 +217,19 @@ impl<A: MessageBroadcaster, B: PriceProvider> SystemClockAttestationScheduler<A,
                 }
                 futures::future::ready(should_continue)
             })
-            .for_each_concurrent(MAX_CONCURRENT_SLOTS, |_| async {
+            .for_each_concurrent(self.config.max_concurrent_slots, |_| async {
                 let slot = Slot::now();
                 let now = Utc::now();
+                self.health.record_tick(slot);
 
                 // This means the previous two slots failed to complete within their 24 available
                 // seconds. Because we want to start attesting as early as possible and use
                 // resources sparingly, we use a limit.
                 let millis_into_slot = now - slot.to_date_time();
-                if millis_into_slot > *DELAYED_START_LIMIT {
+                if millis_into_slot > self.config.delayed_start_limit {
                     log::warn!(
-                        "Slot started more than 1000ms into the slot, skipping. Slot: {}, millis_into_slot: {}ms",
+                        "Slot started more than {}ms into the slot, skipping. Slot: {}, millis_into_slot: {}ms",
+                        self.config.delayed_start_limit.num_milliseconds(),
                         slot,
                         millis_into_slot.num_milliseconds()
                     );
@@ -163,15 +244,21 @@ impl<A: MessageBroadcaster, B: PriceProvider> SystemClockAttestationScheduler<A,
                     (now - slot.to_date_time()).num_milliseconds()
                 );
 
-                timeout(
-                    *ATTESTATION_TIMEOUT_DURATION,
+                let result = timeout(
+                    self.config.attestation_timeout,
                     self.run_single_slot(slot),
                 )
                     .await
                     .unwrap_or_else(|_| {
-                        log::error!("Hit {}s timeout for slot: {}", ATTESTATION_TIMEOUT, slot);
-                        Ok(())
-                    })
+                        Err(eyre::eyre!(
+                            "Hit {}s timeout for slot: {}",
+                            self.config.attestation_timeout.as_secs(),
+                            slot
+                        ))
+                    });
+                self.health.record_slot_outcome(slot, result.is_ok());
+                self.health
+                    .track(Subsystem::Scheduler, result)
                     .unwrap_or_else(|e| {
                         log::error!("Error when running for slot: {} - {:?}", slot, e);
                     });
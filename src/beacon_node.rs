@@ -0,0 +1,298 @@
+//! # Beacon Node
+//! Looks up our signing key through the standard beacon API, so messages can tell servers which
+//! validator signed them and we stop signing once the key isn't an active validator anymore.
+//! Validator status only changes at epoch boundaries, so we check once per epoch. The
+//! `ActiveValidatorCache` does the same for the keys of other validators.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use bls::PublicKey;
+use eyre::{Context, Result};
+use serde::Deserialize;
+
+use crate::{
+    http_client::default_client,
+    message_broadcaster::{ValidatorInfo, ValidatorStatus},
+    slot::Slot,
+};
+
+pub const SLOTS_PER_EPOCH: u64 = 32;
+
+#[derive(Debug, Deserialize)]
+struct ValidatorsResponse {
+    data: Vec<ValidatorResponse>,
+}
+
+#[derive(Debug, Deserialize)]
+struct ValidatorResponse {
+    /// The beacon API encodes integers as strings.
+    index: String,
+    status: ValidatorStatus,
+}
+
+#[derive(Clone)]
+pub struct BeaconNodeClient {
+    url: String,
+    client: reqwest::Client,
+}
+
+impl BeaconNodeClient {
+    /// `url` is the base url of the beacon node, without a path.
+    pub fn new(url: &str) -> BeaconNodeClient {
+        Self::new_with_client(url, default_client())
+    }
+
+    pub fn new_with_client(url: &str, client: reqwest::Client) -> BeaconNodeClient {
+        BeaconNodeClient {
+            url: url.trim_end_matches('/').to_string(),
+            client,
+        }
+    }
+
+    /// Looks the key up in the head state, `None` when it isn't a validator at all.
+    pub async fn get_validator_info(
+        &self,
+        public_key: &PublicKey,
+    ) -> Result<Option<ValidatorInfo>> {
+        let url = format!("{}/eth/v1/beacon/states/head/validators", self.url);
+        let response: ValidatorsResponse = self
+            .client
+            .get(&url)
+            .query(&[("id", public_key.to_string())])
+            .send()
+            .await
+            .wrap_err_with(|| format!("Error querying beacon node at {}", url))?
+            .error_for_status()
+            .wrap_err("Non-Success response from beacon node")?
+            .json()
+            .await
+            .wrap_err("Invalid validators response from beacon node")?;
+        response
+            .data
+            .first()
+            .map(|validator| {
+                Ok(ValidatorInfo {
+                    index: validator
+                        .index
+                        .parse()
+                        .wrap_err_with(|| format!("Invalid validator index {}", validator.index))?,
+                    status: validator.status,
+                })
+            })
+            .transpose()
+    }
+}
+
+/// Keeps what the beacon node last told us about our key.
+#[derive(Clone)]
+pub struct ValidatorMonitor {
+    client: BeaconNodeClient,
+    public_key: PublicKey,
+    validator_info: Arc<Mutex<Option<ValidatorInfo>>>,
+}
+
+impl ValidatorMonitor {
+    pub fn new(client: BeaconNodeClient, public_key: PublicKey) -> ValidatorMonitor {
+        ValidatorMonitor {
+            client,
+            public_key,
+            validator_info: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Asks the beacon node for the current status of our key, errors unless it's active. When
+    /// the beacon node can't be reached we keep what it told us before.
+    pub async fn refresh(&self) -> Result<ValidatorInfo> {
+        let validator_info = self.client.get_validator_info(&self.public_key).await?;
+        *self.validator_info.lock().unwrap() = validator_info;
+        self.validator_info()
+    }
+
+    /// Our validator as last seen by the beacon node, errors unless it's active.
+    pub fn validator_info(&self) -> Result<ValidatorInfo> {
+        match *self.validator_info.lock().unwrap() {
+            Some(validator_info) if validator_info.status.is_active() => Ok(validator_info),
+            Some(validator_info) => eyre::bail!(
+                "Validator {} with public key {} is not active, its status is {}",
+                validator_info.index,
+                self.public_key,
+                validator_info.status
+            ),
+            None => eyre::bail!(
+                "Public key {} is not a validator on the beacon chain",
+                self.public_key
+            ),
+        }
+    }
+
+    /// Refreshes at the start of every epoch.
+    pub async fn run(&self) {
+        loop {
+            let next_epoch = Slot((Slot::now().0 / SLOTS_PER_EPOCH + 1) * SLOTS_PER_EPOCH);
+            if let Ok(wait) = (next_epoch.to_date_time() - chrono::Utc::now()).to_std() {
+                tokio::time::sleep(wait).await;
+            }
+            match self.refresh().await {
+                Ok(validator_info) => log::debug!(
+                    "Validator {} is {}",
+                    validator_info.index,
+                    validator_info.status
+                ),
+                Err(e) => log::error!("Failed to confirm validator is active - {:?}", e),
+            }
+        }
+    }
+}
+
+/// Whether keys belong to active validators, asked once per key and epoch.
+#[derive(Clone)]
+pub struct ActiveValidatorCache {
+    client: BeaconNodeClient,
+    // The epoch the answers are for, by key.
+    epoch_answers: Arc<Mutex<(u64, HashMap<String, bool>)>>,
+}
+
+impl ActiveValidatorCache {
+    pub fn new(client: BeaconNodeClient) -> ActiveValidatorCache {
+        ActiveValidatorCache {
+            client,
+            epoch_answers: Arc::new(Mutex::new((0, HashMap::new()))),
+        }
+    }
+
+    /// Errors when the beacon node can't be asked, those answers aren't cached.
+    pub async fn is_active(&self, public_key: &PublicKey) -> Result<bool> {
+        let epoch = Slot::now().0 / SLOTS_PER_EPOCH;
+        let key = public_key.to_string();
+        {
+            let mut epoch_answers = self.epoch_answers.lock().unwrap();
+            if epoch_answers.0 != epoch {
+                *epoch_answers = (epoch, HashMap::new());
+            }
+            if let Some(is_active) = epoch_answers.1.get(&key) {
+                return Ok(*is_active);
+            }
+        }
+        let is_active = self
+            .client
+            .get_validator_info(public_key)
+            .await?
+            .is_some_and(|validator_info| validator_info.status.is_active());
+        let mut epoch_answers = self.epoch_answers.lock().unwrap();
+        if epoch_answers.0 == epoch {
+            epoch_answers.1.insert(key, is_active);
+        }
+        Ok(is_active)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mockito::Matcher;
+
+    use super::*;
+    use crate::signature_provider::{private_key::PrivateKeySignatureProvider, SignatureProvider};
+
+    async fn mock_validator(
+        server: &mut mockito::Server,
+        public_key: &PublicKey,
+        body: &str,
+    ) -> mockito::Mock {
+        server
+            .mock("GET", "/eth/v1/beacon/states/head/validators")
+            .match_query(Matcher::UrlEncoded(
+                "id".to_string(),
+                public_key.to_string(),
+            ))
+            .with_status(200)
+            .with_header("content-type", "application/json")
+            .with_body(body)
+            .create_async()
+            .await
+    }
+
+    #[tokio::test]
+    async fn tracks_validator_status() {
+        let mut server = mockito::Server::new_async().await;
+        let public_key = PrivateKeySignatureProvider::random()
+            .get_public_key()
+            .unwrap();
+        let monitor =
+            ValidatorMonitor::new(BeaconNodeClient::new(&server.url()), public_key.clone());
+        assert!(monitor.validator_info().is_err());
+
+        let active = format!(
+            r#"{{
+                "execution_optimistic": false,
+                "finalized": false,
+                "data": [{{
+                    "index": "1234",
+                    "balance": "32000000000",
+                    "status": "active_ongoing",
+                    "validator": {{ "pubkey": "{}" }}
+                }}]
+            }}"#,
+            public_key
+        );
+        let mock = mock_validator(&mut server, &public_key, &active).await;
+        let validator_info = monitor.refresh().await.unwrap();
+        assert_eq!(validator_info.index, 1234);
+        assert_eq!(validator_info.status, ValidatorStatus::ActiveOngoing);
+        mock.remove_async().await;
+
+        let exited = active.replace("active_ongoing", "exited_unslashed");
+        let mock = mock_validator(&mut server, &public_key, &exited).await;
+        assert!(monitor.refresh().await.is_err());
+        assert!(monitor.validator_info().is_err());
+        mock.remove_async().await;
+
+        mock_validator(&mut server, &public_key, r#"{"data": []}"#).await;
+        assert!(monitor.refresh().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn caches_active_validators_per_epoch() {
+        let mut server = mockito::Server::new_async().await;
+        let cache = ActiveValidatorCache::new(BeaconNodeClient::new(&server.url()));
+        let active_key = PrivateKeySignatureProvider::random()
+            .get_public_key()
+            .unwrap();
+        let unknown_key = PrivateKeySignatureProvider::random()
+            .get_public_key()
+            .unwrap();
+        let active = format!(
+            r#"{{"data": [{{"index": "7", "status": "active_ongoing", "validator": {{ "pubkey": "{}" }}}}]}}"#,
+            active_key
+        );
+        let mut mocks = Vec::new();
+        for (public_key, body) in [
+            (&active_key, active.as_str()),
+            (&unknown_key, r#"{"data": []}"#),
+        ] {
+            let mock = server
+                .mock("GET", "/eth/v1/beacon/states/head/validators")
+                .match_query(Matcher::UrlEncoded(
+                    "id".to_string(),
+                    public_key.to_string(),
+                ))
+                .with_status(200)
+                .with_header("content-type", "application/json")
+                .with_body(body)
+                .expect(1)
+                .create_async()
+                .await;
+            mocks.push(mock);
+        }
+
+        for _ in 0..2 {
+            assert!(cache.is_active(&active_key).await.unwrap());
+            assert!(!cache.is_active(&unknown_key).await.unwrap());
+        }
+        for mock in mocks {
+            mock.assert_async().await;
+        }
+    }
+}
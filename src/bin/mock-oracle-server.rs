@@ -0,0 +1,26 @@
+//! Runs the mock oracle server, see `oracle_client::mock_oracle_server`. Only built with the
+//! `mock-server` feature, e.g. `cargo run --features mock-server --bin mock-oracle-server`.
+
+use std::net::SocketAddr;
+
+use clap::Parser;
+use eyre::Result;
+
+use oracle_client::mock_oracle_server::{
+    serve, MockOracleServerState, DEFAULT_MOCK_ORACLE_SERVER_ADDR,
+};
+
+#[derive(Parser)]
+#[command(version, about = "Local stand-in for the oracle-server")]
+struct Args {
+    #[arg(long, env = "MOCK_ORACLE_SERVER_ADDR", default_value = DEFAULT_MOCK_ORACLE_SERVER_ADDR)]
+    listen_addr: SocketAddr,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    env_logger::init();
+
+    let args = Args::parse();
+    serve(args.listen_addr, MockOracleServerState::new()).await
+}
@@ -0,0 +1,756 @@
+//! # CLI
+//! Subcommands of the `oracle-client` binary. Without a subcommand it runs the attestation loop,
+//! the others are one-off tools for operators built on the same generator and signer types.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use bls::PublicKey;
+use clap::{Parser, Subcommand};
+use ethers::types::Address;
+use eyre::{Context, Result};
+
+use crate::{
+    admin_server,
+    aggregation_node::{self, CollectingMessageBroadcaster, MessageCollector},
+    attestation_scheduler::SystemClockAttestationScheduler,
+    beacon_node::{ActiveValidatorCache, BeaconNodeClient, ValidatorMonitor},
+    config::{BroadcastTargetConfig, Config, PriceProviderConfig},
+    doppelganger::DoppelgangerDetection,
+    http_client::{build_client, HttpClientConfig},
+    message_broadcaster::{
+        gossip::Libp2pMessageBroadcaster,
+        http::{HttpAuth, HttpMessageBroadcaster, HttpMessageFeed},
+        json::{ArchivingMessageBroadcaster, JsonFileMessageBroadcaster},
+        multi::{BroadcastTarget, MultiMessageBroadcaster, DEFAULT_TARGET_TIMEOUT},
+        nats::NatsMessageBroadcaster,
+        outbox::OutboxMessageBroadcaster,
+        redis_stream::RedisStreamMessageBroadcaster,
+        sqlite::{HistoryFilter, HistoryRecord, MessageStore, SqliteMessageBroadcaster},
+        stdout::StdoutMessageBroadcaster,
+        websocket::WebSocketMessageBroadcaster,
+        MessageBroadcaster, OracleMessage,
+    },
+    message_generator::{MessageGenerator, INTERVAL_PRECISION_FACTOR},
+    price_provider::{gofer::GoferPriceProvider, Price, PRECISION_DECIMALS, PRECISION_FACTOR},
+    signature_provider::{private_key::PrivateKeySignatureProvider, SignatureProvider},
+    slashing_protection::{Interchange, SlashingProtection},
+    slot::Slot,
+    verification::{verify_oracle_message, VerifyOptions},
+};
+
+#[derive(Debug, Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// Config file, defaults to oracle-client.toml if it exists.
+    #[arg(long, global = true, env = "CONFIG_PATH")]
+    config: Option<PathBuf>,
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Attest to the price at the start of every slot, the default.
+    Run,
+    /// Sign a message for a single slot and price and print it as JSON.
+    SignOnce {
+        #[arg(long)]
+        slot: u64,
+        /// Price in USD, e.g. 1953.25.
+        #[arg(long, value_parser = parse_price)]
+        price: Price,
+    },
+    /// Check the signatures, slots and interval of an oracle message JSON file.
+    Verify { file: PathBuf },
+    /// Print a summary of an oracle message JSON file.
+    Inspect { file: PathBuf },
+    /// Create a new private key in an encrypted keystore.
+    Keygen {
+        #[arg(long, default_value = "keystores")]
+        output_dir: PathBuf,
+        /// File holding the password to encrypt the keystore with.
+        #[arg(long, env = "KEYSTORE_PASSWORD_FILE")]
+        password_file: PathBuf,
+    },
+    /// Register the signing key with every broadcast target, with a proof of possession.
+    Register {
+        /// Address rewards should go to.
+        #[arg(long)]
+        fee_recipient: Address,
+        /// Name of who runs this client.
+        #[arg(long)]
+        operator: String,
+    },
+    /// List broadcast messages from the message history, or export them to CSV.
+    History {
+        /// First slot to include.
+        #[arg(long)]
+        from_slot: Option<u64>,
+        /// Last slot to include.
+        #[arg(long)]
+        to_slot: Option<u64>,
+        /// Only messages signed by this public key.
+        #[arg(long)]
+        validator: Option<PublicKey>,
+        #[arg(long)]
+        limit: Option<usize>,
+        /// Write the messages to this CSV file instead of printing them.
+        #[arg(long)]
+        csv: Option<PathBuf>,
+    },
+    /// Move the slashing protection history between machines.
+    #[command(subcommand)]
+    SlashingProtection(SlashingProtectionCommand),
+}
+
+#[derive(Debug, Subcommand)]
+enum SlashingProtectionCommand {
+    /// Merge an interchange JSON file into the history.
+    Import { file: PathBuf },
+    /// Write the history to an interchange JSON file.
+    Export { file: PathBuf },
+}
+
+impl Cli {
+    pub async fn execute(self) -> Result<()> {
+        match self.command.unwrap_or(Command::Run) {
+            Command::Run => run(load_config(self.config.as_deref())?).await,
+            Command::SignOnce { slot, price } => {
+                sign_once(&load_config(self.config.as_deref())?, Slot(slot), price)
+            }
+            Command::Verify { file } => verify(&file),
+            Command::Inspect { file } => inspect(&file),
+            Command::Keygen {
+                output_dir,
+                password_file,
+            } => keygen(&output_dir, &password_file),
+            Command::Register {
+                fee_recipient,
+                operator,
+            } => {
+                register(
+                    &load_config(self.config.as_deref())?,
+                    fee_recipient,
+                    operator,
+                )
+                .await
+            }
+            Command::History {
+                from_slot,
+                to_slot,
+                validator,
+                limit,
+                csv,
+            } => history(
+                &load_config(self.config.as_deref())?,
+                &HistoryFilter {
+                    from_slot,
+                    to_slot,
+                    validator_public_key: validator,
+                    limit,
+                },
+                csv.as_deref(),
+            ),
+            Command::SlashingProtection(command) => {
+                slashing_protection(&load_config(self.config.as_deref())?, command)
+            }
+        }
+    }
+}
+
+fn load_config(path: Option<&Path>) -> Result<Config> {
+    let config = Config::load(path)?;
+    log::debug!("Config: {:?}", config);
+    Ok(config)
+}
+
+/// Parses a decimal price, e.g. `1953.25`, into our fixed precision representation.
+fn parse_price(s: &str) -> Result<Price> {
+    let (whole, fraction) = s.split_once('.').unwrap_or((s, ""));
+    if fraction.len() > PRECISION_DECIMALS as usize {
+        eyre::bail!("price {} has more than {} decimals", s, PRECISION_DECIMALS);
+    }
+    let whole = whole
+        .parse::<u64>()
+        .wrap_err_with(|| format!("invalid price {}", s))?;
+    let fraction = format!("{:0<width$}", fraction, width = PRECISION_DECIMALS as usize)
+        .parse::<u64>()
+        .wrap_err_with(|| format!("invalid price {}", s))?;
+    let value = whole
+        .checked_mul(PRECISION_FACTOR)
+        .and_then(|value| value.checked_add(fraction))
+        .ok_or_else(|| eyre::eyre!("price {} too large", s))?;
+    Ok(Price { value })
+}
+
+fn read_oracle_message(file: &Path) -> Result<OracleMessage> {
+    let contents = std::fs::read_to_string(file)
+        .wrap_err_with(|| format!("Failed to read {}", file.display()))?;
+    serde_json::from_str(&contents)
+        .wrap_err_with(|| format!("Failed to parse oracle message in {}", file.display()))
+}
+
+fn message_generator(config: &Config) -> Result<MessageGenerator> {
+    let signature_provider = Box::new(config.signature_provider()?);
+    let message_generator_config = config.message_generator_config()?;
+    if !config.slashing_protection.enabled {
+        log::warn!("Slashing protection is disabled");
+        return Ok(MessageGenerator::new_with_config(
+            signature_provider,
+            message_generator_config,
+        ));
+    }
+    let slashing_protection = SlashingProtection::open(&config.slashing_protection.path)?;
+    Ok(MessageGenerator::new_with_slashing_protection(
+        signature_provider,
+        message_generator_config,
+        Arc::new(slashing_protection),
+    ))
+}
+
+/// The configured `broadcaster.targets`, without the p2p network. Needs to be called within a
+/// tokio runtime, WebSocket targets connect in the background right away.
+fn broadcast_targets(
+    config: &Config,
+    http_client: &reqwest::Client,
+) -> Result<Vec<BroadcastTarget>> {
+    build_broadcast_targets(config, config.broadcaster.targets.iter(), http_client)
+}
+
+fn build_broadcast_targets<'a>(
+    config: &Config,
+    targets: impl Iterator<Item = &'a BroadcastTargetConfig>,
+    http_client: &reqwest::Client,
+) -> Result<Vec<BroadcastTarget>> {
+    let message_encoding = config.message_encoding()?;
+    targets
+        .map(|target| {
+            let broadcaster: Box<dyn MessageBroadcaster + Send + Sync> =
+                if let Some(format) = target.stdout_format() {
+                    Box::new(StdoutMessageBroadcaster::new(format?))
+                } else if target.url.starts_with("ws://") || target.url.starts_with("wss://") {
+                    Box::new(WebSocketMessageBroadcaster::new(&target.url))
+                } else if target.url.starts_with("nats://") {
+                    Box::new(NatsMessageBroadcaster::new(
+                        &target.url,
+                        &target.topic(&config.network),
+                    ))
+                } else if target.is_message_bus() {
+                    Box::new(RedisStreamMessageBroadcaster::new(
+                        &target.url,
+                        &target.topic(&config.network),
+                    ))
+                } else {
+                    // A client certificate needs a client of its own.
+                    let http_client = match target.auth.client_identity() {
+                        Some(client_identity) => build_client(&HttpClientConfig {
+                            client_identity: Some(client_identity),
+                            ..config.http_client_config()
+                        })?,
+                        None => http_client.clone(),
+                    };
+                    let auth = HttpAuth::from_files(
+                        target.auth.bearer_token_file.as_deref(),
+                        target.auth.hmac_key_file.as_deref(),
+                    )?;
+                    Box::new(
+                        HttpMessageBroadcaster::new_with_client(
+                            &target.url,
+                            message_encoding,
+                            http_client,
+                        )
+                        .with_auth(auth),
+                    )
+                };
+            Ok(BroadcastTarget::new(
+                &target.name,
+                broadcaster,
+                std::time::Duration::from_secs(target.timeout_secs),
+            ))
+        })
+        .collect()
+}
+
+async fn run(config: Config) -> Result<()> {
+    config.validate()?;
+
+    // One client for everything, so all components share its connection pool.
+    let http_client = build_client(&config.http_client_config())?;
+    let price_provider = match &config.price_provider {
+        PriceProviderConfig::Gofer { url } => GoferPriceProvider::new_with_client(
+            url,
+            config.network.pair.clone(),
+            http_client.clone(),
+        ),
+    };
+    log::info!("Initialized price_provider");
+    let mut message_generator = message_generator(&config)?;
+    if config.beacon_node.enabled {
+        let validator_monitor = ValidatorMonitor::new(
+            BeaconNodeClient::new_with_client(&config.beacon_node.url, http_client.clone()),
+            message_generator.get_public_key()?,
+        );
+        let validator_info = validator_monitor
+            .refresh()
+            .await
+            .wrap_err("Refusing to run without an active validator")?;
+        log::info!(
+            "Signing as validator {}, status {}",
+            validator_info.index,
+            validator_info.status
+        );
+        let refreshing_validator_monitor = validator_monitor.clone();
+        tokio::spawn(async move { refreshing_validator_monitor.run().await });
+        message_generator = message_generator.with_validator_monitor(validator_monitor);
+    }
+    log::info!("Initialized message_generator");
+    let mut broadcast_targets = broadcast_targets(&config, &http_client)?;
+    let mut message_collector = None;
+    if config.p2p.enabled {
+        let p2p_broadcaster = Libp2pMessageBroadcaster::start(config.p2p_config()?).await?;
+        if config.aggregation_node.enabled {
+            let active_validators = ActiveValidatorCache::new(BeaconNodeClient::new_with_client(
+                &config.beacon_node.url,
+                http_client.clone(),
+            ));
+            let collector =
+                MessageCollector::new(config.aggregation_node_retained_slots()?, active_validators);
+            let running_collector = collector.clone();
+            let received = p2p_broadcaster.subscribe();
+            tokio::spawn(async move { running_collector.run(received).await });
+            let aggregation_node_addr = config.aggregation_node_addr()?;
+            let serving_collector = collector.clone();
+            tokio::spawn(async move {
+                if let Err(e) =
+                    aggregation_node::serve(aggregation_node_addr, serving_collector).await
+                {
+                    log::error!("Aggregation node API failed - {:?}", e);
+                }
+            });
+            message_collector = Some(collector);
+        }
+        broadcast_targets.push(BroadcastTarget::new(
+            "p2p",
+            Box::new(p2p_broadcaster),
+            DEFAULT_TARGET_TIMEOUT,
+        ));
+    }
+    let multi_broadcaster: Box<dyn MessageBroadcaster + Send + Sync> = {
+        let multi_broadcaster =
+            MultiMessageBroadcaster::new(broadcast_targets, config.broadcast_policy()?);
+        match message_collector {
+            Some(collector) => Box::new(CollectingMessageBroadcaster::new(
+                multi_broadcaster,
+                collector,
+            )),
+            None => Box::new(multi_broadcaster),
+        }
+    };
+    // Below the outbox, so redeliveries are recorded too.
+    let recording_broadcaster: Box<dyn MessageBroadcaster + Send + Sync> =
+        if config.broadcaster.history.enabled {
+            Box::new(SqliteMessageBroadcaster::new(
+                multi_broadcaster,
+                &config.broadcaster.history.path,
+            )?)
+        } else {
+            Box::new(multi_broadcaster)
+        };
+    let mut redelivering_broadcaster = None;
+    let message_broadcaster: Box<dyn MessageBroadcaster + Send + Sync> =
+        if config.broadcaster.outbox.enabled {
+            let outbox_broadcaster =
+                OutboxMessageBroadcaster::new(recording_broadcaster, config.outbox_config())?;
+            redelivering_broadcaster = Some(outbox_broadcaster.clone());
+            Box::new(outbox_broadcaster)
+        } else {
+            recording_broadcaster
+        };
+    let message_broadcaster: Box<dyn MessageBroadcaster + Send + Sync> =
+        if config.broadcaster.archive.enabled {
+            Box::new(ArchivingMessageBroadcaster::new(
+                message_broadcaster,
+                JsonFileMessageBroadcaster::new_with_config(
+                    &config.broadcaster.archive.path.to_string_lossy(),
+                    config.json_archive_config()?,
+                )?,
+            ))
+        } else {
+            message_broadcaster
+        };
+    log::info!("Initialized message_roadcaster");
+
+    let slashing_protection = message_generator.slashing_protection();
+    let attestation_scheduler = SystemClockAttestationScheduler::new_with_config(
+        message_broadcaster,
+        message_generator,
+        price_provider,
+        None,
+        config.scheduler_config(),
+    );
+
+    if config.admin_server.enabled {
+        let admin_server_addr = config.admin_server_addr()?;
+        let health = attestation_scheduler.health();
+        tokio::spawn(async move {
+            if let Err(e) = admin_server::serve(admin_server_addr, health).await {
+                log::error!("Admin server failed - {:?}", e);
+            }
+        });
+    }
+
+    if config.doppelganger_detection.enabled {
+        let mut doppelganger_detection = DoppelgangerDetection::new_with_config(
+            Box::new(HttpMessageFeed::new_with_client(
+                &config.doppelganger_detection.feed_url,
+                http_client,
+            )),
+            config.doppelganger_detection_config(),
+        );
+        if let Some(slashing_protection) = slashing_protection {
+            doppelganger_detection =
+                doppelganger_detection.with_slashing_protection(slashing_protection);
+        }
+        attestation_scheduler
+            .detect_doppelganger(&doppelganger_detection)
+            .await?;
+    }
+    // Only once the key is ours, our own redelivered messages would look like a doppelganger.
+    if let Some(redelivering_broadcaster) = redelivering_broadcaster {
+        tokio::spawn(async move { redelivering_broadcaster.run_redelivery().await });
+    }
+    attestation_scheduler.run().await;
+    Ok(())
+}
+
+fn sign_once(config: &Config, slot: Slot, price: Price) -> Result<()> {
+    let oracle_message = message_generator(config)?.generate_oracle_message(price, slot)?;
+    println!("{}", serde_json::to_string_pretty(&oracle_message)?);
+    Ok(())
+}
+
+fn verify(file: &Path) -> Result<()> {
+    let oracle_message = read_oracle_message(file)?;
+    let report = verify_oracle_message(&oracle_message, &VerifyOptions::default());
+    if !report.is_valid() {
+        eyre::bail!(
+            "found {} problem(s) in message from public key {}:\n{}",
+            report.violations.len(),
+            oracle_message.validator_public_key,
+            report
+        );
+    }
+    println!(
+        "Message valid for public key {}",
+        oracle_message.validator_public_key
+    );
+    Ok(())
+}
+
+fn format_fixed(value: u64, factor: u64, decimals: usize) -> String {
+    format!(
+        "{}.{:0width$}",
+        value / factor,
+        value % factor,
+        width = decimals
+    )
+}
+
+/// Human readable summary of the message, prices are in USD.
+fn summary(oracle_message: &OracleMessage) -> String {
+    let value_message = &oracle_message.value_message.message;
+    let format_interval_value = |value| {
+        format_fixed(
+            value,
+            INTERVAL_PRECISION_FACTOR,
+            crate::message_generator::INTERVAL_STEP_DECIMALS as usize,
+        )
+    };
+    let interval = match (
+        &oracle_message.interval_bounds_message,
+        oracle_message.interval_inclusion_messages.first(),
+        oracle_message.interval_inclusion_messages.last(),
+    ) {
+        (Some(interval_bounds_message), _, _) => format!(
+            "{} - {}, {} basis points, signed as bounds",
+            format_interval_value(interval_bounds_message.message.lower_bound),
+            format_interval_value(interval_bounds_message.message.upper_bound),
+            interval_bounds_message.message.interval_size
+        ),
+        (None, Some(first), Some(last)) => format!(
+            "{} - {}, {} basis points, {} signed steps",
+            format_interval_value(first.message.value),
+            format_interval_value(last.message.value + 1),
+            first.message.interval_size,
+            oracle_message.interval_inclusion_messages.len()
+        ),
+        _ => "none".to_string(),
+    };
+    let validator = match oracle_message.validator_info {
+        Some(validator_info) => format!("{} ({})", validator_info.index, validator_info.status),
+        None => "unknown".to_string(),
+    };
+    [
+        format!("Public key: {}", oracle_message.validator_public_key),
+        format!("Validator:  {}", validator),
+        format!("Version:    {}", u8::from(oracle_message.version)),
+        format!(
+            "Slot:       {} ({})",
+            value_message.slot_number,
+            Slot(value_message.slot_number).to_date_time()
+        ),
+        format!(
+            "Price:      {}",
+            format_fixed(
+                value_message.price.value,
+                PRECISION_FACTOR,
+                PRECISION_DECIMALS as usize
+            )
+        ),
+        format!("Interval:   {}", interval),
+    ]
+    .join("\n")
+}
+
+/// One line per message, prices in USD.
+fn history_line(record: &HistoryRecord) -> String {
+    let format_interval_value = |value| {
+        format_fixed(
+            value,
+            INTERVAL_PRECISION_FACTOR,
+            crate::message_generator::INTERVAL_STEP_DECIMALS as usize,
+        )
+    };
+    let interval = match (record.interval_lower_bound, record.interval_upper_bound) {
+        (Some(lower_bound), Some(upper_bound)) => format!(
+            "{} - {}",
+            format_interval_value(lower_bound),
+            format_interval_value(upper_bound)
+        ),
+        _ => "none".to_string(),
+    };
+    let outcome = match &record.error {
+        None => "ok".to_string(),
+        Some(error) => format!("failed: {}", error),
+    };
+    format!(
+        "{} {} price {} interval {} attempts {} {}ms at {} {}",
+        record.slot,
+        record.validator_public_key,
+        format_fixed(record.price, PRECISION_FACTOR, PRECISION_DECIMALS as usize),
+        interval,
+        record.attempts,
+        record.duration_ms,
+        record.broadcast_at.to_rfc3339(),
+        outcome
+    )
+}
+
+fn history(config: &Config, filter: &HistoryFilter, csv_path: Option<&Path>) -> Result<()> {
+    let path = &config.broadcaster.history.path;
+    if !path.exists() {
+        eyre::bail!("no message history at {}", path.display());
+    }
+    let records = MessageStore::open(path)?.query(filter)?;
+    match csv_path {
+        Some(csv_path) => {
+            let mut writer = csv::Writer::from_path(csv_path)
+                .wrap_err_with(|| format!("Failed to create {}", csv_path.display()))?;
+            for record in &records {
+                writer.serialize(record)?;
+            }
+            writer
+                .flush()
+                .wrap_err_with(|| format!("Failed to write {}", csv_path.display()))?;
+            println!(
+                "Exported {} messages to {}",
+                records.len(),
+                csv_path.display()
+            );
+        }
+        None => {
+            for record in &records {
+                println!("{}", history_line(record));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn inspect(file: &Path) -> Result<()> {
+    println!("{}", summary(&read_oracle_message(file)?));
+    Ok(())
+}
+
+fn keygen(output_dir: &Path, password_file: &Path) -> Result<()> {
+    let password = std::fs::read_to_string(password_file)
+        .wrap_err_with(|| format!("Failed to read password file {}", password_file.display()))?;
+    let password = password.trim_end();
+    if password.is_empty() {
+        eyre::bail!("password file {} is empty", password_file.display());
+    }
+    let signature_provider = PrivateKeySignatureProvider::random();
+    let keystore_path = signature_provider.write_keystore(output_dir, password)?;
+    println!("Public key: {}", signature_provider.get_public_key()?);
+    println!("Keystore:   {}", keystore_path.display());
+    Ok(())
+}
+
+async fn register(config: &Config, fee_recipient: Address, operator: String) -> Result<()> {
+    config.validate()?;
+    // Registering doesn't sign prices, so no slashing protection needed.
+    let message_generator = MessageGenerator::new_with_config(
+        Box::new(config.signature_provider()?),
+        config.message_generator_config()?,
+    );
+    let registration = message_generator.generate_registration(fee_recipient, operator)?;
+    let http_client = build_client(&config.http_client_config())?;
+    // Stdout, message bus and WebSocket targets, like the p2p network, have nowhere to send
+    // registrations to.
+    let http_targets = config
+        .broadcaster
+        .targets
+        .iter()
+        .filter(|target| target.is_http());
+    let broadcast_targets = build_broadcast_targets(config, http_targets, &http_client)?;
+    if broadcast_targets.is_empty() {
+        eyre::bail!("Registering needs at least one http or https target in broadcaster.targets");
+    }
+    MultiMessageBroadcaster::new(broadcast_targets, config.broadcast_policy()?)
+        .broadcast_registration(&registration)
+        .await
+        .wrap_err("Failed to register")?;
+    println!("{}", serde_json::to_string_pretty(&registration)?);
+    Ok(())
+}
+
+fn slashing_protection(config: &Config, command: SlashingProtectionCommand) -> Result<()> {
+    let slashing_protection = SlashingProtection::open(&config.slashing_protection.path)?;
+    match command {
+        SlashingProtectionCommand::Import { file } => {
+            let contents = std::fs::read_to_string(&file)
+                .wrap_err_with(|| format!("Failed to read {}", file.display()))?;
+            let interchange: Interchange = serde_json::from_str(&contents)
+                .wrap_err_with(|| format!("Failed to parse interchange in {}", file.display()))?;
+            slashing_protection.import(&interchange)?;
+            println!(
+                "Imported slashing protection history of {} validators",
+                interchange.data.len()
+            );
+        }
+        SlashingProtectionCommand::Export { file } => {
+            let interchange = slashing_protection.export();
+            std::fs::write(&file, serde_json::to_string_pretty(&interchange)?)
+                .wrap_err_with(|| format!("Failed to write {}", file.display()))?;
+            println!(
+                "Exported slashing protection history of {} validators",
+                interchange.data.len()
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_generator::MessageGenerator;
+
+    fn generate_message() -> OracleMessage {
+        MessageGenerator::new(Box::new(PrivateKeySignatureProvider::random()))
+            .generate_oracle_message(parse_price("1000").unwrap(), Slot(1))
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_prices() {
+        assert_eq!(parse_price("1953").unwrap().value, 1953 * PRECISION_FACTOR);
+        assert_eq!(parse_price("1953.25").unwrap().value, 1_953_250_000);
+        assert_eq!(parse_price("0.000001").unwrap().value, 1);
+        assert!(parse_price("0.0000001").is_err());
+        assert!(parse_price("-1").is_err());
+        assert!(parse_price("abc").is_err());
+        assert!(parse_price(&u64::MAX.to_string()).is_err());
+        assert!(parse_price(&format!("{}.999999", u64::MAX / PRECISION_FACTOR)).is_err());
+    }
+
+    #[test]
+    fn parses_subcommands() {
+        let cli = Cli::try_parse_from(["oracle-client"]).unwrap();
+        assert!(cli.command.is_none());
+
+        let cli = Cli::try_parse_from([
+            "oracle-client",
+            "sign-once",
+            "--slot",
+            "7",
+            "--price",
+            "1953.25",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::SignOnce { slot: 7, price }) if price.value == 1_953_250_000
+        ));
+
+        let cli = Cli::try_parse_from([
+            "oracle-client",
+            "register",
+            "--fee-recipient",
+            "0x1111111111111111111111111111111111111111",
+            "--operator",
+            "ultrasound",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Register { fee_recipient, .. }) if fee_recipient == Address::repeat_byte(0x11)
+        ));
+
+        let cli = Cli::try_parse_from([
+            "oracle-client",
+            "history",
+            "--from-slot",
+            "10",
+            "--to-slot",
+            "20",
+            "--csv",
+            "history.csv",
+        ])
+        .unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::History {
+                from_slot: Some(10),
+                to_slot: Some(20),
+                validator: None,
+                csv: Some(_),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn summarizes_message() {
+        let summary = summary(&generate_message());
+
+        assert!(summary.contains("Price:      1000.000000"));
+        assert!(summary.contains("Interval:   998.00 - 1002.00, 20 basis points, 400 signed steps"));
+    }
+
+    #[test]
+    fn verifies_message_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("message.json");
+        let mut oracle_message = generate_message();
+        std::fs::write(&path, serde_json::to_string(&oracle_message).unwrap()).unwrap();
+        assert!(verify(&path).is_ok());
+
+        oracle_message.value_message.message.price.value += 1;
+        std::fs::write(&path, serde_json::to_string(&oracle_message).unwrap()).unwrap();
+        assert!(verify(&path).is_err());
+    }
+}
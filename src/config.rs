@@ -0,0 +1,1279 @@
+//! # Config
+//! All settings of the client in one TOML file, see `oracle-client.toml` for an annotated example.
+//! Every section has defaults, so a missing file or section falls back to what the client did
+//! before it was configurable. The environment variables we used to read directly still override
+//! the matching settings. Call `validate` before starting anything, it reports every problem at
+//! once rather than making operators fix them one restart at a time.
+
+use std::{
+    collections::HashSet,
+    net::SocketAddr,
+    num::NonZeroU64,
+    path::{Path, PathBuf},
+};
+
+use eyre::{Context, Result};
+use serde::Deserialize;
+
+use crate::{
+    admin_server::DEFAULT_ADMIN_SERVER_ADDR,
+    aggregation_node::{DEFAULT_AGGREGATION_NODE_ADDR, DEFAULT_RETAINED_SLOTS},
+    attestation_scheduler::{
+        SchedulerConfig, ATTESTATION_TIMEOUT, DELAYED_START_LIMIT_MILLIS, MAX_CONCURRENT_SLOTS,
+    },
+    doppelganger::{DoppelgangerDetectionConfig, DOPPELGANGER_DETECTION_SLOTS},
+    http_client::{ClientIdentity, HttpClientConfig},
+    message_broadcaster::{
+        gossip::{
+            split_peer_id, topic_name, Libp2pConfig, DEFAULT_LISTEN_ADDR, DEFAULT_MAX_SLOT_DISTANCE,
+        },
+        http::MessageEncoding,
+        json::{ArchiveCompression, ArchiveFormat, JsonArchiveConfig, DIRECTORY_PATH},
+        multi::{BroadcastPolicy, DEFAULT_TARGET_TIMEOUT},
+        outbox::{OutboxConfig, DEFAULT_OUTBOX_PATH},
+        sqlite::DEFAULT_HISTORY_PATH,
+        stdout::StdoutFormat,
+        MessageVersion,
+    },
+    message_generator::{MessageGeneratorConfig, INTERVAL_SIZE_BASIS_POINTS, ONE_IN_BASIS_POINTS},
+    signature_provider::private_key::PrivateKeySignatureProvider,
+    slashing_protection::DEFAULT_SLASHING_PROTECTION_PATH,
+    slot::Slot,
+};
+
+pub const DEFAULT_CONFIG_PATH: &str = "oracle-client.toml";
+const DEFAULT_GOFER_URL: &str = "http://localhost:9200/price";
+const DEFAULT_BEACON_NODE_URL: &str = "http://localhost:5052";
+const DEFAULT_TOPIC: &str = "oracle.{network}.{pair}";
+
+/// The whole `oracle-client.toml`, every section falls back to its defaults when left out.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub network: NetworkConfig,
+    pub signer: SignerConfig,
+    pub price_provider: PriceProviderConfig,
+    pub interval: IntervalConfig,
+    pub broadcaster: BroadcasterConfig,
+    pub p2p: P2pConfig,
+    pub aggregation_node: AggregationNodeConfig,
+    pub scheduler: SchedulerSection,
+    pub admin_server: AdminServerConfig,
+    pub slashing_protection: SlashingProtectionConfig,
+    pub doppelganger_detection: DoppelgangerDetectionSection,
+    pub beacon_node: BeaconNodeConfig,
+    pub http_client: HttpClientSection,
+}
+
+/// The chain and price pair the messages are for.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NetworkConfig {
+    pub name: String,
+    pub pair: String,
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        NetworkConfig {
+            name: "mainnet".to_string(),
+            pair: "ETH/USD".to_string(),
+        }
+    }
+}
+
+/// Where the BLS key signing the messages comes from.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum SignerConfig {
+    /// A fresh key on every start, only useful for testing.
+    #[default]
+    Random,
+    /// An encrypted keystore as created by `oracle-client keygen`.
+    Keystore {
+        path: PathBuf,
+        password_file: PathBuf,
+    },
+}
+
+/// Where the price comes from, a gofer instance for now.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum PriceProviderConfig {
+    Gofer { url: String },
+}
+
+impl Default for PriceProviderConfig {
+    fn default() -> Self {
+        PriceProviderConfig::Gofer {
+            url: DEFAULT_GOFER_URL.to_string(),
+        }
+    }
+}
+
+/// The interval around the price the signed messages cover.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IntervalConfig {
+    pub size_basis_points: u64,
+    pub message_version: u8,
+}
+
+impl Default for IntervalConfig {
+    fn default() -> Self {
+        IntervalConfig {
+            size_basis_points: INTERVAL_SIZE_BASIS_POINTS,
+            message_version: MessageVersion::V1.into(),
+        }
+    }
+}
+
+/// Where messages are sent and what is kept of them locally.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BroadcasterConfig {
+    /// `all`, `any` or `quorum:<n>`.
+    pub policy: String,
+    /// `json`, `ssz` or `ssz_snappy`.
+    pub encoding: String,
+    pub targets: Vec<BroadcastTargetConfig>,
+    pub outbox: OutboxSection,
+    pub history: HistorySection,
+    pub archive: ArchiveSection,
+}
+
+impl Default for BroadcasterConfig {
+    fn default() -> Self {
+        BroadcasterConfig {
+            policy: BroadcastPolicy::All.to_string(),
+            encoding: "json".to_string(),
+            targets: Vec::new(),
+            outbox: OutboxSection::default(),
+            history: HistorySection::default(),
+            archive: ArchiveSection::default(),
+        }
+    }
+}
+
+/// One entry of `broadcaster.targets`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BroadcastTargetConfig {
+    pub name: String,
+    /// `http(s)://` or `ws(s)://` url of a server, `nats://` or `redis(s)://` url of a message
+    /// bus, or `stdout://summary` or `stdout://full` to write messages to stdout.
+    pub url: String,
+    #[serde(default = "default_target_timeout_secs")]
+    pub timeout_secs: u64,
+    #[serde(default)]
+    pub auth: TargetAuthConfig,
+    /// NATS subject or Redis stream to publish to, `{network}` and `{pair}` are filled in.
+    #[serde(default)]
+    pub topic: Option<String>,
+}
+
+impl BroadcastTargetConfig {
+    /// HTTP targets are the only ones taking registrations and auth.
+    pub fn is_http(&self) -> bool {
+        self.url.starts_with("http://") || self.url.starts_with("https://")
+    }
+
+    pub fn is_message_bus(&self) -> bool {
+        ["nats://", "redis://", "rediss://"]
+            .iter()
+            .any(|scheme| self.url.starts_with(scheme))
+    }
+
+    /// The subject or stream name of a message bus target, with a `/` in the pair turned into
+    /// `-`, e.g. `oracle.mainnet.ETH-USD`.
+    pub fn topic(&self, network: &NetworkConfig) -> String {
+        self.topic
+            .as_deref()
+            .unwrap_or(DEFAULT_TOPIC)
+            .replace("{network}", &network.name)
+            .replace("{pair}", &network.pair.replace('/', "-"))
+    }
+
+    /// The format of a `stdout://<format>` target, `None` for targets that aren't stdout.
+    pub fn stdout_format(&self) -> Option<Result<StdoutFormat>> {
+        self.url
+            .strip_prefix("stdout://")
+            .map(|format| format.parse())
+    }
+}
+
+/// How to authenticate to an HTTP target, any combination works. Secrets are only ever read from
+/// files.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TargetAuthConfig {
+    pub bearer_token_file: Option<PathBuf>,
+    pub hmac_key_file: Option<PathBuf>,
+    /// PEM client certificate for mutual TLS, needs `client_key` too.
+    pub client_certificate: Option<PathBuf>,
+    /// PKCS#8 PEM key of `client_certificate`.
+    pub client_key: Option<PathBuf>,
+}
+
+impl TargetAuthConfig {
+    pub fn is_empty(&self) -> bool {
+        self.bearer_token_file.is_none()
+            && self.hmac_key_file.is_none()
+            && self.client_certificate.is_none()
+            && self.client_key.is_none()
+    }
+
+    /// Expects a validated config.
+    pub fn client_identity(&self) -> Option<ClientIdentity> {
+        match (&self.client_certificate, &self.client_key) {
+            (Some(certificate), Some(key)) => Some(ClientIdentity {
+                certificate: certificate.clone(),
+                key: key.clone(),
+            }),
+            _ => None,
+        }
+    }
+}
+
+fn default_target_timeout_secs() -> u64 {
+    DEFAULT_TARGET_TIMEOUT.as_secs()
+}
+
+/// The file recording every message until the targets accepted it, see `OutboxConfig`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OutboxSection {
+    pub enabled: bool,
+    pub path: PathBuf,
+    pub max_age_secs: i64,
+}
+
+impl Default for OutboxSection {
+    fn default() -> Self {
+        OutboxSection {
+            enabled: true,
+            path: PathBuf::from(DEFAULT_OUTBOX_PATH),
+            max_age_secs: OutboxConfig::default().max_age.num_seconds(),
+        }
+    }
+}
+
+/// SQLite database keeping every broadcast message and its outcome for audits.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HistorySection {
+    pub enabled: bool,
+    pub path: PathBuf,
+}
+
+impl Default for HistorySection {
+    fn default() -> Self {
+        HistorySection {
+            enabled: false,
+            path: PathBuf::from(DEFAULT_HISTORY_PATH),
+        }
+    }
+}
+
+/// JSON files keeping a copy of every generated message, with rotation and retention limits.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ArchiveSection {
+    pub enabled: bool,
+    pub path: PathBuf,
+    /// `files` for a file per slot or `ndjson` for a daily log.
+    pub format: String,
+    /// `none`, `gzip` or `zstd`.
+    pub compression: String,
+    pub partition_by_date: bool,
+    pub max_age_secs: Option<i64>,
+    pub max_total_bytes: Option<u64>,
+    pub prune_interval_secs: i64,
+}
+
+impl Default for ArchiveSection {
+    fn default() -> Self {
+        ArchiveSection {
+            enabled: false,
+            path: PathBuf::from(DIRECTORY_PATH),
+            format: "files".to_string(),
+            compression: "none".to_string(),
+            partition_by_date: false,
+            max_age_secs: None,
+            max_total_bytes: None,
+            prune_interval_secs: JsonArchiveConfig::default().prune_interval.num_seconds(),
+        }
+    }
+}
+
+/// Gossiping messages to other oracle nodes over libp2p, counts as a broadcast target when enabled.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct P2pConfig {
+    pub enabled: bool,
+    pub listen_addrs: Vec<String>,
+    /// Multiaddrs ending in `/p2p/<peer id>`.
+    pub bootnodes: Vec<String>,
+    /// Messages for slots further from the current one aren't relayed.
+    pub max_slot_distance: u64,
+}
+
+impl Default for P2pConfig {
+    fn default() -> Self {
+        P2pConfig {
+            enabled: false,
+            listen_addrs: vec![DEFAULT_LISTEN_ADDR.to_string()],
+            bootnodes: Vec::new(),
+            max_slot_distance: DEFAULT_MAX_SLOT_DISTANCE,
+        }
+    }
+}
+
+/// Collecting the messages of other validators from the p2p network and serving their consensus.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AggregationNodeConfig {
+    pub enabled: bool,
+    pub listen_addr: String,
+    /// Slots to keep messages for.
+    pub retained_slots: u64,
+}
+
+impl Default for AggregationNodeConfig {
+    fn default() -> Self {
+        AggregationNodeConfig {
+            enabled: false,
+            listen_addr: DEFAULT_AGGREGATION_NODE_ADDR.to_string(),
+            retained_slots: DEFAULT_RETAINED_SLOTS,
+        }
+    }
+}
+
+/// Timing of the work done every slot, see `SchedulerConfig`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SchedulerSection {
+    pub max_concurrent_slots: usize,
+    pub attestation_timeout_secs: u64,
+    pub delayed_start_limit_ms: i64,
+}
+
+impl Default for SchedulerSection {
+    fn default() -> Self {
+        SchedulerSection {
+            max_concurrent_slots: MAX_CONCURRENT_SLOTS,
+            attestation_timeout_secs: ATTESTATION_TIMEOUT,
+            delayed_start_limit_ms: DELAYED_START_LIMIT_MILLIS,
+        }
+    }
+}
+
+/// The server answering liveness and readiness probes.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminServerConfig {
+    pub enabled: bool,
+    pub listen_addr: String,
+}
+
+impl Default for AdminServerConfig {
+    fn default() -> Self {
+        AdminServerConfig {
+            enabled: true,
+            listen_addr: DEFAULT_ADMIN_SERVER_ADDR.to_string(),
+        }
+    }
+}
+
+/// The history of signed slots that keeps us from signing a slot twice.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SlashingProtectionConfig {
+    pub enabled: bool,
+    pub path: PathBuf,
+}
+
+impl Default for SlashingProtectionConfig {
+    fn default() -> Self {
+        SlashingProtectionConfig {
+            enabled: true,
+            path: PathBuf::from(DEFAULT_SLASHING_PROTECTION_PATH),
+        }
+    }
+}
+
+/// Watching for messages signed by our key before attesting, in case another client uses it.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DoppelgangerDetectionSection {
+    pub enabled: bool,
+    /// Base url of the server to query for messages signed by our key.
+    pub feed_url: String,
+    pub slots: u64,
+}
+
+impl Default for DoppelgangerDetectionSection {
+    fn default() -> Self {
+        DoppelgangerDetectionSection {
+            enabled: false,
+            feed_url: String::new(),
+            slots: DOPPELGANGER_DETECTION_SLOTS,
+        }
+    }
+}
+
+/// The beacon node telling which keys belong to active validators.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BeaconNodeConfig {
+    /// Require the signing key to be an active validator and attach its index to messages.
+    pub enabled: bool,
+    pub url: String,
+}
+
+impl Default for BeaconNodeConfig {
+    fn default() -> Self {
+        BeaconNodeConfig {
+            enabled: false,
+            url: DEFAULT_BEACON_NODE_URL.to_string(),
+        }
+    }
+}
+
+/// Settings of the HTTP client shared by the price provider, broadcasters, message feed and beacon
+/// node client.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HttpClientSection {
+    pub connect_timeout_secs: u64,
+    pub request_timeout_secs: u64,
+    pub pool_idle_timeout_secs: u64,
+    pub tcp_keepalive_secs: u64,
+    pub http2_prior_knowledge: bool,
+    pub proxy: Option<String>,
+    /// PEM file with extra CA certificates to trust.
+    pub ca_bundle: Option<PathBuf>,
+}
+
+impl Default for HttpClientSection {
+    fn default() -> Self {
+        let defaults = HttpClientConfig::default();
+        HttpClientSection {
+            connect_timeout_secs: defaults.connect_timeout.as_secs(),
+            request_timeout_secs: defaults.request_timeout.as_secs(),
+            pool_idle_timeout_secs: defaults.pool_idle_timeout.as_secs(),
+            tcp_keepalive_secs: defaults.tcp_keepalive.as_secs(),
+            http2_prior_knowledge: defaults.http2_prior_knowledge,
+            proxy: None,
+            ca_bundle: None,
+        }
+    }
+}
+
+/// Every problem `Config::validate` found, one per line.
+#[derive(Debug)]
+pub struct ConfigErrors(pub Vec<String>);
+
+impl std::fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "found {} problem(s) in config:", self.0.len())?;
+        for problem in self.0.iter() {
+            writeln!(f, "  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigErrors {}
+
+fn check_url(problems: &mut Vec<String>, field: &str, url: &str) {
+    if let Err(e) = reqwest::Url::parse(url) {
+        problems.push(format!("{}: invalid url {:?}: {}", field, url, e));
+    }
+}
+
+fn check_target_auth(problems: &mut Vec<String>, i: usize, target: &BroadcastTargetConfig) {
+    let auth = &target.auth;
+    if auth.is_empty() {
+        return;
+    }
+    if !target.is_http() {
+        problems.push(format!(
+            "broadcaster.targets[{}].auth: only supported for http and https targets",
+            i
+        ));
+    }
+    for (field, path) in [
+        ("bearer_token_file", &auth.bearer_token_file),
+        ("hmac_key_file", &auth.hmac_key_file),
+        ("client_certificate", &auth.client_certificate),
+        ("client_key", &auth.client_key),
+    ] {
+        if let Some(path) = path {
+            if !path.is_file() {
+                problems.push(format!(
+                    "broadcaster.targets[{}].auth.{}: no file at {}",
+                    i,
+                    field,
+                    path.display()
+                ));
+            }
+        }
+    }
+    if auth.client_certificate.is_some() != auth.client_key.is_some() {
+        problems.push(format!(
+            "broadcaster.targets[{}].auth: client_certificate and client_key go together",
+            i
+        ));
+    }
+}
+
+impl Config {
+    /// Reads the config file at `path`, then applies environment overrides. Without an explicit
+    /// path a missing default config file is fine and we run on defaults.
+    pub fn load(path: Option<&Path>) -> Result<Config> {
+        let config = match path {
+            Some(path) => Self::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(Path::new(DEFAULT_CONFIG_PATH))?
+            }
+            None => {
+                log::info!("No config file found, using defaults");
+                Config::default()
+            }
+        };
+        config.with_env_overrides(|key| std::env::var(key).ok())
+    }
+
+    /// Applies `apply_env_overrides`, reporting its problems along with everything `validate`
+    /// finds.
+    fn with_env_overrides(mut self, env: impl Fn(&str) -> Option<String>) -> Result<Config> {
+        if let Err(ConfigErrors(mut problems)) = self.apply_env_overrides(env) {
+            if let Err(ConfigErrors(config_problems)) = self.validate() {
+                problems.extend(config_problems);
+            }
+            return Err(ConfigErrors(problems).into());
+        }
+        Ok(self)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config> {
+        let contents = std::fs::read_to_string(path)
+            .wrap_err_with(|| format!("Failed to read config file {}", path.display()))?;
+        toml::from_str(&contents)
+            .wrap_err_with(|| format!("Failed to parse config file {}", path.display()))
+    }
+
+    /// Overrides settings from the environment variables the client used before it had a config
+    /// file. `SERVER_URL` may hold several comma separated urls and replaces all targets. Values
+    /// that can't be parsed are reported, every one of them at once.
+    pub fn apply_env_overrides(
+        &mut self,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> std::result::Result<(), ConfigErrors> {
+        let mut problems = Vec::new();
+        if let Some(gofer_url) = env("GOFER_URL") {
+            self.price_provider = PriceProviderConfig::Gofer { url: gofer_url };
+        }
+        if let Some(server_urls) = env("SERVER_URL") {
+            self.broadcaster.targets = server_urls
+                .split(',')
+                .map(|url| BroadcastTargetConfig {
+                    name: url.to_string(),
+                    url: url.to_string(),
+                    timeout_secs: default_target_timeout_secs(),
+                    auth: TargetAuthConfig::default(),
+                    topic: None,
+                })
+                .collect();
+        }
+        if let Some(policy) = env("BROADCAST_POLICY") {
+            self.broadcaster.policy = policy;
+        }
+        if let Some(encoding) = env("MESSAGE_ENCODING") {
+            self.broadcaster.encoding = encoding;
+        }
+        if let Some(message_version) = env("MESSAGE_VERSION") {
+            match message_version.parse() {
+                Ok(message_version) => self.interval.message_version = message_version,
+                Err(e) => problems.push(format!(
+                    "MESSAGE_VERSION: invalid version {:?}: {}",
+                    message_version, e
+                )),
+            }
+        }
+        if let Some(outbox_path) = env("OUTBOX_PATH") {
+            self.broadcaster.outbox.path = PathBuf::from(outbox_path);
+        }
+        if let Some(history_path) = env("HISTORY_PATH") {
+            self.broadcaster.history.path = PathBuf::from(history_path);
+        }
+        if let Some(bootnodes) = env("P2P_BOOTNODES") {
+            self.p2p.bootnodes = bootnodes.split(',').map(str::to_string).collect();
+        }
+        if let Some(admin_server_addr) = env("ADMIN_SERVER_ADDR") {
+            self.admin_server.listen_addr = admin_server_addr;
+        }
+        if let Some(beacon_node_url) = env("BEACON_NODE_URL") {
+            self.beacon_node.url = beacon_node_url;
+        }
+        if let Some(slashing_protection_path) = env("SLASHING_PROTECTION_PATH") {
+            self.slashing_protection.path = PathBuf::from(slashing_protection_path);
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigErrors(problems))
+        }
+    }
+
+    /// Checks the whole config, returning every problem found rather than just the first.
+    pub fn validate(&self) -> std::result::Result<(), ConfigErrors> {
+        let mut problems = Vec::new();
+
+        if self.network.name.is_empty() {
+            problems.push("network.name: must not be empty".to_string());
+        }
+        if self.network.pair.split_once('/').is_none() {
+            problems.push(format!(
+                "network.pair: expected BASE/QUOTE, got {:?}",
+                self.network.pair
+            ));
+        }
+
+        if let SignerConfig::Keystore {
+            path,
+            password_file,
+        } = &self.signer
+        {
+            if !path.is_file() {
+                problems.push(format!("signer.path: no keystore at {}", path.display()));
+            }
+            if !password_file.is_file() {
+                problems.push(format!(
+                    "signer.password_file: no password file at {}",
+                    password_file.display()
+                ));
+            }
+        }
+
+        match &self.price_provider {
+            PriceProviderConfig::Gofer { url } => {
+                check_url(&mut problems, "price_provider.url", url)
+            }
+        }
+
+        if self.interval.size_basis_points == 0
+            || self.interval.size_basis_points >= ONE_IN_BASIS_POINTS
+        {
+            problems.push(format!(
+                "interval.size_basis_points: must be between 1 and {}, got {}",
+                ONE_IN_BASIS_POINTS - 1,
+                self.interval.size_basis_points
+            ));
+        }
+        if let Err(e) = MessageVersion::try_from(self.interval.message_version) {
+            problems.push(format!("interval.message_version: {}", e));
+        }
+
+        // The p2p network counts as one more target.
+        let target_count = self.broadcaster.targets.len() + usize::from(self.p2p.enabled);
+        match self.broadcaster.policy.parse::<BroadcastPolicy>() {
+            Ok(BroadcastPolicy::Quorum(quorum)) if quorum == 0 || quorum > target_count => {
+                problems.push(format!(
+                    "broadcaster.policy: quorum must be between 1 and the number of targets ({}), got {}",
+                    target_count,
+                    quorum
+                ))
+            }
+            Ok(_) => {}
+            Err(e) => problems.push(format!("broadcaster.policy: {}", e)),
+        }
+        if let Err(e) = self.broadcaster.encoding.parse::<MessageEncoding>() {
+            problems.push(format!("broadcaster.encoding: {}", e));
+        }
+        if target_count == 0 {
+            problems.push(
+                "broadcaster.targets: at least one target is required, add a [[broadcaster.targets]] section, set SERVER_URL or enable p2p"
+                    .to_string(),
+            );
+        }
+        let mut target_names = HashSet::new();
+        for (i, target) in self.broadcaster.targets.iter().enumerate() {
+            if !target_names.insert(target.name.as_str()) {
+                problems.push(format!(
+                    "broadcaster.targets[{}].name: duplicate target name {:?}",
+                    i, target.name
+                ));
+            }
+            match target.stdout_format() {
+                Some(Err(e)) => problems.push(format!("broadcaster.targets[{}].url: {}", i, e)),
+                Some(Ok(_)) => {}
+                None => check_url(
+                    &mut problems,
+                    &format!("broadcaster.targets[{}].url", i),
+                    &target.url,
+                ),
+            }
+            if target.timeout_secs == 0 {
+                problems.push(format!(
+                    "broadcaster.targets[{}].timeout_secs: must be more than 0",
+                    i
+                ));
+            }
+            check_target_auth(&mut problems, i, target);
+            if target.topic.is_some() && !target.is_message_bus() {
+                problems.push(format!(
+                    "broadcaster.targets[{}].topic: only supported for nats and redis targets",
+                    i
+                ));
+            }
+        }
+        if self.broadcaster.outbox.enabled && self.broadcaster.outbox.max_age_secs <= 0 {
+            problems.push("broadcaster.outbox.max_age_secs: must be more than 0".to_string());
+        }
+        let archive = &self.broadcaster.archive;
+        if archive.enabled {
+            if let Err(e) = archive.format.parse::<ArchiveFormat>() {
+                problems.push(format!("broadcaster.archive.format: {}", e));
+            }
+            if let Err(e) = archive.compression.parse::<ArchiveCompression>() {
+                problems.push(format!("broadcaster.archive.compression: {}", e));
+            }
+            if archive
+                .max_age_secs
+                .is_some_and(|max_age_secs| max_age_secs <= 0)
+            {
+                problems.push("broadcaster.archive.max_age_secs: must be more than 0".to_string());
+            }
+            if archive.max_total_bytes == Some(0) {
+                problems
+                    .push("broadcaster.archive.max_total_bytes: must be more than 0".to_string());
+            }
+            if archive.prune_interval_secs < 0 {
+                problems.push(
+                    "broadcaster.archive.prune_interval_secs: must not be negative".to_string(),
+                );
+            }
+        }
+
+        if self.p2p.enabled {
+            if self.p2p.listen_addrs.is_empty() {
+                problems.push("p2p.listen_addrs: at least one address is required".to_string());
+            }
+            for (i, addr) in self.p2p.listen_addrs.iter().enumerate() {
+                if let Err(e) = addr.parse::<libp2p::Multiaddr>() {
+                    problems.push(format!(
+                        "p2p.listen_addrs[{}]: invalid address {:?}: {}",
+                        i, addr, e
+                    ));
+                }
+            }
+            for (i, bootnode) in self.p2p.bootnodes.iter().enumerate() {
+                let result = bootnode
+                    .parse::<libp2p::Multiaddr>()
+                    .map_err(eyre::Report::from)
+                    .and_then(|addr| split_peer_id(&addr));
+                if let Err(e) = result {
+                    problems.push(format!(
+                        "p2p.bootnodes[{}]: invalid address {:?}: {}",
+                        i, bootnode, e
+                    ));
+                }
+            }
+        }
+
+        if self.aggregation_node.enabled {
+            if !self.p2p.enabled {
+                problems.push(
+                    "aggregation_node.enabled: needs p2p enabled to receive messages".to_string(),
+                );
+            }
+            if !self.beacon_node.enabled {
+                problems.push(
+                    "aggregation_node.enabled: needs beacon_node enabled to only count active validators"
+                        .to_string(),
+                );
+            }
+            if let Err(e) = self.aggregation_node.listen_addr.parse::<SocketAddr>() {
+                problems.push(format!(
+                    "aggregation_node.listen_addr: invalid address {:?}: {}",
+                    self.aggregation_node.listen_addr, e
+                ));
+            }
+            if self.aggregation_node.retained_slots == 0 {
+                problems.push("aggregation_node.retained_slots: must be at least 1".to_string());
+            }
+        }
+
+        if self.scheduler.max_concurrent_slots == 0 {
+            problems.push("scheduler.max_concurrent_slots: must be at least 1".to_string());
+        }
+        if self.scheduler.attestation_timeout_secs == 0 {
+            problems.push("scheduler.attestation_timeout_secs: must be more than 0".to_string());
+        }
+        // Otherwise slots that run into their timeout pile up and later slots get skipped.
+        let max_attestation_timeout_secs =
+            self.scheduler.max_concurrent_slots as u64 * Slot::SLOT_PERIOD_SECONDS;
+        if self.scheduler.attestation_timeout_secs > max_attestation_timeout_secs {
+            problems.push(format!(
+                "scheduler.attestation_timeout_secs: must be at most max_concurrent_slots * {}s = {}s, got {}s",
+                Slot::SLOT_PERIOD_SECONDS,
+                max_attestation_timeout_secs,
+                self.scheduler.attestation_timeout_secs
+            ));
+        }
+        if self.scheduler.delayed_start_limit_ms < 0
+            || self.scheduler.delayed_start_limit_ms >= Slot::SLOT_PERIOD_SECONDS as i64 * 1000
+        {
+            problems.push(format!(
+                "scheduler.delayed_start_limit_ms: must be between 0 and {}, got {}",
+                Slot::SLOT_PERIOD_SECONDS * 1000 - 1,
+                self.scheduler.delayed_start_limit_ms
+            ));
+        }
+
+        if self.admin_server.enabled {
+            if let Err(e) = self.admin_server.listen_addr.parse::<SocketAddr>() {
+                problems.push(format!(
+                    "admin_server.listen_addr: invalid address {:?}: {}",
+                    self.admin_server.listen_addr, e
+                ));
+            }
+        }
+
+        if self.doppelganger_detection.enabled {
+            check_url(
+                &mut problems,
+                "doppelganger_detection.feed_url",
+                &self.doppelganger_detection.feed_url,
+            );
+        }
+
+        if self.beacon_node.enabled {
+            check_url(&mut problems, "beacon_node.url", &self.beacon_node.url);
+        }
+
+        if self.http_client.connect_timeout_secs == 0 {
+            problems.push("http_client.connect_timeout_secs: must be more than 0".to_string());
+        }
+        if self.http_client.request_timeout_secs == 0 {
+            problems.push("http_client.request_timeout_secs: must be more than 0".to_string());
+        }
+        if let Some(proxy) = &self.http_client.proxy {
+            check_url(&mut problems, "http_client.proxy", proxy);
+        }
+        if let Some(ca_bundle) = &self.http_client.ca_bundle {
+            if !ca_bundle.is_file() {
+                problems.push(format!(
+                    "http_client.ca_bundle: no CA bundle at {}",
+                    ca_bundle.display()
+                ));
+            }
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(ConfigErrors(problems))
+        }
+    }
+
+    // None of the accessors need a validated config, so one-off signing works without broadcast
+    // targets. They stop at the first problem though, `validate` reports all of them.
+
+    pub fn signature_provider(&self) -> Result<PrivateKeySignatureProvider> {
+        match &self.signer {
+            SignerConfig::Random => Ok(PrivateKeySignatureProvider::random()),
+            SignerConfig::Keystore {
+                path,
+                password_file,
+            } => {
+                let password = std::fs::read_to_string(password_file).wrap_err_with(|| {
+                    format!("Failed to read password file {}", password_file.display())
+                })?;
+                PrivateKeySignatureProvider::from_keystore(path, password.trim_end())
+            }
+        }
+    }
+
+    pub fn message_generator_config(&self) -> Result<MessageGeneratorConfig> {
+        Ok(MessageGeneratorConfig {
+            message_version: MessageVersion::try_from(self.interval.message_version)
+                .map_err(|e| eyre::eyre!("interval.message_version: {}", e))?,
+            interval_size_basis_points: self.interval.size_basis_points,
+        })
+    }
+
+    pub fn broadcast_policy(&self) -> Result<BroadcastPolicy> {
+        self.broadcaster
+            .policy
+            .parse()
+            .wrap_err("broadcaster.policy")
+    }
+
+    pub fn message_encoding(&self) -> Result<MessageEncoding> {
+        self.broadcaster
+            .encoding
+            .parse()
+            .wrap_err("broadcaster.encoding")
+    }
+
+    pub fn admin_server_addr(&self) -> Result<SocketAddr> {
+        self.admin_server
+            .listen_addr
+            .parse()
+            .wrap_err("admin_server.listen_addr")
+    }
+
+    pub fn aggregation_node_addr(&self) -> Result<SocketAddr> {
+        self.aggregation_node
+            .listen_addr
+            .parse()
+            .wrap_err("aggregation_node.listen_addr")
+    }
+
+    pub fn aggregation_node_retained_slots(&self) -> Result<NonZeroU64> {
+        NonZeroU64::new(self.aggregation_node.retained_slots)
+            .ok_or_else(|| eyre::eyre!("aggregation_node.retained_slots: must be at least 1"))
+    }
+
+    pub fn outbox_config(&self) -> OutboxConfig {
+        OutboxConfig {
+            path: self.broadcaster.outbox.path.clone(),
+            max_age: chrono::Duration::seconds(self.broadcaster.outbox.max_age_secs),
+            ..OutboxConfig::default()
+        }
+    }
+
+    pub fn json_archive_config(&self) -> Result<JsonArchiveConfig> {
+        let archive = &self.broadcaster.archive;
+        Ok(JsonArchiveConfig {
+            format: archive
+                .format
+                .parse()
+                .wrap_err("broadcaster.archive.format")?,
+            compression: archive
+                .compression
+                .parse()
+                .wrap_err("broadcaster.archive.compression")?,
+            partition_by_date: archive.partition_by_date,
+            max_age: archive.max_age_secs.map(chrono::Duration::seconds),
+            max_total_bytes: archive.max_total_bytes,
+            prune_interval: chrono::Duration::seconds(archive.prune_interval_secs),
+        })
+    }
+
+    pub fn p2p_config(&self) -> Result<Libp2pConfig> {
+        let parse = |field: &str, addrs: &[String]| {
+            addrs
+                .iter()
+                .enumerate()
+                .map(|(i, addr)| {
+                    addr.parse().wrap_err_with(|| {
+                        format!("p2p.{}[{}]: invalid address {:?}", field, i, addr)
+                    })
+                })
+                .collect::<Result<Vec<_>>>()
+        };
+        Ok(Libp2pConfig {
+            listen_addrs: parse("listen_addrs", &self.p2p.listen_addrs)?,
+            bootnodes: parse("bootnodes", &self.p2p.bootnodes)?,
+            topic: topic_name(&self.network.name, &self.network.pair),
+            max_slot_distance: self.p2p.max_slot_distance,
+            ..Libp2pConfig::default()
+        })
+    }
+
+    pub fn doppelganger_detection_config(&self) -> DoppelgangerDetectionConfig {
+        DoppelgangerDetectionConfig {
+            slots: self.doppelganger_detection.slots,
+            ..DoppelgangerDetectionConfig::default()
+        }
+    }
+
+    pub fn http_client_config(&self) -> HttpClientConfig {
+        HttpClientConfig {
+            connect_timeout: std::time::Duration::from_secs(self.http_client.connect_timeout_secs),
+            request_timeout: std::time::Duration::from_secs(self.http_client.request_timeout_secs),
+            pool_idle_timeout: std::time::Duration::from_secs(
+                self.http_client.pool_idle_timeout_secs,
+            ),
+            tcp_keepalive: std::time::Duration::from_secs(self.http_client.tcp_keepalive_secs),
+            http2_prior_knowledge: self.http_client.http2_prior_knowledge,
+            proxy: self.http_client.proxy.clone(),
+            ca_bundle: self.http_client.ca_bundle.clone(),
+            client_identity: None,
+        }
+    }
+
+    pub fn scheduler_config(&self) -> SchedulerConfig {
+        SchedulerConfig {
+            max_concurrent_slots: self.scheduler.max_concurrent_slots,
+            attestation_timeout: std::time::Duration::from_secs(
+                self.scheduler.attestation_timeout_secs,
+            ),
+            delayed_start_limit: chrono::Duration::milliseconds(
+                self.scheduler.delayed_start_limit_ms,
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn parses_example_config() {
+        let config = Config::from_file(Path::new(DEFAULT_CONFIG_PATH)).unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.broadcast_policy().unwrap(), BroadcastPolicy::All);
+        assert_eq!(config.broadcaster.targets.len(), 1);
+    }
+
+    #[test]
+    fn fills_in_defaults_for_missing_sections() {
+        let config: Config = toml::from_str(
+            r#"
+            [[broadcaster.targets]]
+            name = "local"
+            url = "http://localhost:3000/post_oracle_message"
+            "#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.network.pair, "ETH/USD");
+        assert_eq!(
+            config.broadcaster.targets[0].timeout_secs,
+            DEFAULT_TARGET_TIMEOUT.as_secs()
+        );
+        assert_eq!(
+            config.scheduler_config().max_concurrent_slots,
+            MAX_CONCURRENT_SLOTS
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        let result = toml::from_str::<Config>(
+            r#"
+            [scheduler]
+            attestation_timeout = 24
+            "#,
+        );
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn env_overrides_config_file() {
+        let mut config = Config::default();
+        let env = HashMap::from([
+            ("GOFER_URL", "http://gofer:9200/price"),
+            ("SERVER_URL", "http://a/post,http://b/post"),
+            ("BROADCAST_POLICY", "quorum:2"),
+        ]);
+
+        config
+            .apply_env_overrides(|key| env.get(key).map(|value| value.to_string()))
+            .unwrap();
+
+        config.validate().unwrap();
+        assert!(matches!(
+            config.price_provider,
+            PriceProviderConfig::Gofer { ref url } if url == "http://gofer:9200/price"
+        ));
+        assert_eq!(
+            config
+                .broadcaster
+                .targets
+                .iter()
+                .map(|target| target.url.as_str())
+                .collect::<Vec<_>>(),
+            vec!["http://a/post", "http://b/post"]
+        );
+        assert_eq!(
+            config.broadcast_policy().unwrap(),
+            BroadcastPolicy::Quorum(2)
+        );
+    }
+
+    #[test]
+    fn reports_unparsable_env_overrides() {
+        let mut config = Config::default();
+
+        let problems = config
+            .apply_env_overrides(|key| (key == "MESSAGE_VERSION").then(|| "two".to_string()))
+            .unwrap_err()
+            .0;
+
+        assert_eq!(problems.len(), 1);
+        assert!(problems[0].starts_with("MESSAGE_VERSION: invalid version \"two\""));
+        assert_eq!(
+            config.interval.message_version,
+            u8::from(MessageVersion::V1)
+        );
+    }
+
+    #[test]
+    fn reports_env_override_problems_with_config_problems() {
+        let problems = Config::default()
+            .with_env_overrides(|key| (key == "MESSAGE_VERSION").then(|| "two".to_string()))
+            .unwrap_err()
+            .downcast::<ConfigErrors>()
+            .unwrap()
+            .0;
+
+        let fields = problems
+            .iter()
+            .map(|problem| problem.split(':').next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(fields, vec!["MESSAGE_VERSION", "broadcaster.targets"]);
+    }
+
+    #[test]
+    fn renders_message_bus_topics() {
+        let config: Config = toml::from_str(
+            r#"
+            [[broadcaster.targets]]
+            name = "nats"
+            url = "nats://localhost:4222"
+
+            [[broadcaster.targets]]
+            name = "redis"
+            url = "redis://localhost:6379"
+            topic = "prices:{pair}"
+            "#,
+        )
+        .unwrap();
+
+        config.validate().unwrap();
+        let topics = config
+            .broadcaster
+            .targets
+            .iter()
+            .map(|target| target.topic(&config.network))
+            .collect::<Vec<_>>();
+        assert_eq!(topics, vec!["oracle.mainnet.ETH-USD", "prices:ETH-USD"]);
+    }
+
+    #[test]
+    fn counts_p2p_as_a_target() {
+        let config: Config = toml::from_str(
+            r#"
+            [broadcaster]
+            policy = "quorum:1"
+
+            [p2p]
+            enabled = true
+            listen_addrs = ["/ip4/127.0.0.1/tcp/9000"]
+            bootnodes = ["/ip4/10.0.0.1/tcp/9000/p2p/12D3KooWLFMRWp4Qa3LGbVCVNDaLvPNnTj4K1MpkpG5nRBxfbPZB"]
+            "#,
+        )
+        .unwrap();
+
+        config.validate().unwrap();
+        let p2p_config = config.p2p_config().unwrap();
+        assert_eq!(p2p_config.bootnodes.len(), 1);
+        assert_eq!(p2p_config.topic, "/oracle/mainnet/ETH-USD/ssz");
+    }
+
+    #[test]
+    fn builds_archive_config() {
+        let config: Config = toml::from_str(
+            r#"
+            [[broadcaster.targets]]
+            name = "local"
+            url = "http://localhost:3000/post_oracle_message"
+
+            [broadcaster.archive]
+            enabled = true
+            format = "ndjson"
+            compression = "zstd"
+            partition_by_date = true
+            max_age_secs = 86400
+            "#,
+        )
+        .unwrap();
+
+        config.validate().unwrap();
+        let archive_config = config.json_archive_config().unwrap();
+        assert_eq!(archive_config.format, ArchiveFormat::Ndjson);
+        assert_eq!(archive_config.compression, ArchiveCompression::Zstd);
+        assert!(archive_config.partition_by_date);
+        assert_eq!(archive_config.max_age, Some(chrono::Duration::days(1)));
+        assert_eq!(archive_config.max_total_bytes, None);
+        assert_eq!(archive_config.prune_interval, chrono::Duration::hours(1));
+    }
+
+    #[test]
+    fn reports_every_problem_at_once() {
+        let config: Config = toml::from_str(
+            r#"
+            [interval]
+            size_basis_points = 0
+            message_version = 3
+
+            [broadcaster]
+            policy = "quorum:4"
+            encoding = "xml"
+
+            [[broadcaster.targets]]
+            name = "local"
+            url = "not a url"
+
+            [[broadcaster.targets]]
+            name = "stdout"
+            url = "stdout://xml"
+
+            [broadcaster.archive]
+            enabled = true
+            compression = "brotli"
+            max_total_bytes = 0
+
+            [p2p]
+            enabled = true
+            bootnodes = ["/ip4/10.0.0.1/tcp/9000"]
+
+            [aggregation_node]
+            enabled = true
+            retained_slots = 0
+
+            [scheduler]
+            attestation_timeout_secs = 60
+
+            [admin_server]
+            listen_addr = "localhost"
+            "#,
+        )
+        .unwrap();
+
+        let problems = config.validate().unwrap_err().0;
+
+        let fields = problems
+            .iter()
+            .map(|problem| problem.split(':').next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            fields,
+            vec![
+                "interval.size_basis_points",
+                "interval.message_version",
+                "broadcaster.policy",
+                "broadcaster.encoding",
+                "broadcaster.targets[0].url",
+                "broadcaster.targets[1].url",
+                "broadcaster.archive.compression",
+                "broadcaster.archive.max_total_bytes",
+                "p2p.bootnodes[0]",
+                "aggregation_node.enabled",
+                "aggregation_node.retained_slots",
+                "scheduler.attestation_timeout_secs",
+                "admin_server.listen_addr",
+            ]
+        );
+    }
+}
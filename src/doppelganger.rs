@@ -0,0 +1,198 @@
+//! # Doppelganger Detection
+//! Before attesting with a key we watch a message feed for a few slots, to see whether another
+//! client is already signing with it. Two machines running the same key sign different prices for
+//! the same slot, which is what slashing protection can't catch across machines. Like the
+//! validator clients do, we'd rather lose a few slots at startup than risk that. Slots the local
+//! slashing protection history shows we signed ourselves, e.g. right before a restart, are skipped.
+
+use std::sync::Arc;
+
+use bls::PublicKey;
+use chrono::{Duration, Utc};
+use eyre::{Context, Result};
+
+use crate::{
+    attestation_scheduler::ATTESTATION_TIMEOUT, health::HealthState,
+    message_broadcaster::MessageFeed, slashing_protection::SlashingProtection, slot::Slot,
+};
+
+pub const DOPPELGANGER_DETECTION_SLOTS: u64 = 2;
+
+#[derive(Clone, Debug)]
+pub struct DoppelgangerDetectionConfig {
+    /// Slots to watch after the current one, the previous slot is always checked.
+    pub slots: u64,
+    /// How long after the start of a slot its messages are expected in the feed.
+    pub observation_delay: Duration,
+}
+
+impl Default for DoppelgangerDetectionConfig {
+    fn default() -> Self {
+        DoppelgangerDetectionConfig {
+            slots: DOPPELGANGER_DETECTION_SLOTS,
+            // Other clients may take until their attestation timeout to broadcast.
+            observation_delay: Duration::seconds(ATTESTATION_TIMEOUT as i64),
+        }
+    }
+}
+
+pub struct DoppelgangerDetection {
+    feed: Box<dyn MessageFeed + Send + Sync>,
+    config: DoppelgangerDetectionConfig,
+    slashing_protection: Option<Arc<SlashingProtection>>,
+}
+
+impl DoppelgangerDetection {
+    pub fn new(feed: Box<dyn MessageFeed + Send + Sync>) -> DoppelgangerDetection {
+        Self::new_with_config(feed, DoppelgangerDetectionConfig::default())
+    }
+
+    pub fn new_with_config(
+        feed: Box<dyn MessageFeed + Send + Sync>,
+        config: DoppelgangerDetectionConfig,
+    ) -> DoppelgangerDetection {
+        DoppelgangerDetection {
+            feed,
+            config,
+            slashing_protection: None,
+        }
+    }
+
+    /// Skips the slots `slashing_protection` shows this client signed, their messages are ours.
+    pub fn with_slashing_protection(
+        self,
+        slashing_protection: Arc<SlashingProtection>,
+    ) -> DoppelgangerDetection {
+        DoppelgangerDetection {
+            slashing_protection: Some(slashing_protection),
+            ..self
+        }
+    }
+
+    /// Watches the feed from the previous slot on, errors as soon as a message signed by
+    /// `public_key` shows up or the feed can't be queried. Records ticks into `health` meanwhile,
+    /// so waiting doesn't look like a wedged client.
+    pub async fn run(&self, public_key: &PublicKey, health: &HealthState) -> Result<()> {
+        let current_slot = Slot::now();
+        log::info!(
+            "Watching for messages signed by {} until slot {} before attesting",
+            public_key,
+            current_slot.0 + self.config.slots
+        );
+        for slot in current_slot.0.saturating_sub(1)..=current_slot.0 + self.config.slots {
+            if self
+                .slashing_protection
+                .as_ref()
+                .is_some_and(|slashing_protection| slashing_protection.has_signed(public_key, slot))
+            {
+                log::debug!("Skipping slot {}, signed by this client", slot);
+                continue;
+            }
+            let observable_at = Slot(slot).to_date_time() + self.config.observation_delay;
+            if let Ok(wait) = (observable_at - Utc::now()).to_std() {
+                tokio::time::sleep(wait).await;
+            }
+            health.record_tick(Slot::now());
+            let has_message = self
+                .feed
+                .has_message(slot, public_key)
+                .await
+                .wrap_err("Failed to query message feed for doppelganger detection")?;
+            if has_message {
+                eyre::bail!(
+                    "Found a message signed by {} for slot {}, another client is already attesting with this key",
+                    public_key,
+                    slot
+                );
+            }
+            log::debug!("No doppelganger found for slot {}", slot);
+        }
+        log::info!("No doppelganger found, starting to attest");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use async_trait::async_trait;
+
+    use super::*;
+    use crate::{
+        message_broadcaster::PriceValueMessage,
+        price_provider::Price,
+        signature_provider::{private_key::PrivateKeySignatureProvider, SignatureProvider},
+    };
+
+    struct StaticFeed {
+        slots: HashSet<u64>,
+    }
+
+    #[async_trait]
+    impl MessageFeed for StaticFeed {
+        async fn has_message(&self, slot: u64, _: &PublicKey) -> Result<bool> {
+            Ok(self.slots.contains(&slot))
+        }
+    }
+
+    fn detection(slots: HashSet<u64>) -> DoppelgangerDetection {
+        DoppelgangerDetection::new_with_config(
+            Box::new(StaticFeed { slots }),
+            DoppelgangerDetectionConfig {
+                slots: 0,
+                observation_delay: Duration::zero(),
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn refuses_key_seen_in_feed() {
+        let public_key = PrivateKeySignatureProvider::random()
+            .get_public_key()
+            .unwrap();
+        let previous_slot = Slot::now().0 - 1;
+
+        assert!(detection(HashSet::new())
+            .run(&public_key, &HealthState::new())
+            .await
+            .is_ok());
+        assert!(detection(HashSet::from([previous_slot]))
+            .run(&public_key, &HealthState::new())
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn ignores_own_message_from_before_restart() {
+        let public_key = PrivateKeySignatureProvider::random()
+            .get_public_key()
+            .unwrap();
+        let previous_slot = Slot::now().0 - 1;
+        let dir = tempfile::tempdir().unwrap();
+        let slashing_protection =
+            SlashingProtection::open(&dir.path().join("slashing_protection.ndjson")).unwrap();
+        // Signed and broadcast right before the restart, so it's in the feed already.
+        slashing_protection
+            .check_and_record(
+                &public_key,
+                &PriceValueMessage {
+                    price: Price { value: 1000 },
+                    slot_number: previous_slot,
+                },
+            )
+            .unwrap();
+        let slashing_protection = Arc::new(slashing_protection);
+
+        assert!(detection(HashSet::from([previous_slot]))
+            .with_slashing_protection(slashing_protection.clone())
+            .run(&public_key, &HealthState::new())
+            .await
+            .is_ok());
+        assert!(detection(HashSet::from([previous_slot, previous_slot + 1]))
+            .with_slashing_protection(slashing_protection)
+            .run(&public_key, &HealthState::new())
+            .await
+            .is_err());
+    }
+}
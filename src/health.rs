@@ -0,0 +1,270 @@
+//! # Health
+//! Tracks what the client's subsystems have been up to so orchestration can tell a wedged client
+//! from a healthy one. The attestation scheduler records into a shared `HealthState`, the admin
+//! server reads from it to answer liveness and readiness probes.
+
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Duration, Utc};
+use lazy_static::lazy_static;
+use serde::Serialize;
+
+use crate::slot::Slot;
+
+// The scheduler ticks once per slot, we allow for a missed tick before declaring it dead.
+const LIVENESS_TICK_LIMIT_SECS: i64 = 36;
+// Gofer is asked for a price every slot, if we haven't heard back in a few slots something is off.
+const PRICE_FRESHNESS_LIMIT_SECS: i64 = 60;
+// Number of most recent slots that need to have been broadcast successfully to be ready.
+pub const READINESS_SLOT_COUNT: usize = 2;
+
+lazy_static! {
+    static ref LIVENESS_TICK_LIMIT: Duration = Duration::seconds(LIVENESS_TICK_LIMIT_SECS);
+    static ref PRICE_FRESHNESS_LIMIT: Duration = Duration::seconds(PRICE_FRESHNESS_LIMIT_SECS);
+}
+
+/// The parts of the client whose successes and errors are tracked.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Subsystem {
+    Scheduler,
+    Signer,
+    PriceProvider,
+    Broadcaster,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SubsystemError {
+    pub at: DateTime<Utc>,
+    pub message: String,
+}
+
+/// When a subsystem last succeeded and failed.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct SubsystemStatus {
+    pub last_success: Option<DateTime<Utc>>,
+    pub last_error: Option<SubsystemError>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SubsystemsReport {
+    pub scheduler: SubsystemStatus,
+    pub signer: SubsystemStatus,
+    pub price_provider: SubsystemStatus,
+    pub broadcaster: SubsystemStatus,
+}
+
+/// What gets returned as JSON body from both the liveness and readiness endpoints.
+#[derive(Clone, Debug, Serialize)]
+pub struct HealthReport {
+    pub ok: bool,
+    pub current_slot: Option<u64>,
+    pub last_tick: DateTime<Utc>,
+    pub recent_slots: Vec<SlotOutcome>,
+    pub subsystems: SubsystemsReport,
+}
+
+/// Whether the message for a slot made it out.
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct SlotOutcome {
+    pub slot: u64,
+    pub broadcast: bool,
+}
+
+struct HealthStateInner {
+    last_tick: DateTime<Utc>,
+    current_slot: Option<Slot>,
+    recent_slots: VecDeque<SlotOutcome>,
+    scheduler: SubsystemStatus,
+    signer: SubsystemStatus,
+    price_provider: SubsystemStatus,
+    broadcaster: SubsystemStatus,
+}
+
+impl HealthStateInner {
+    fn subsystem_mut(&mut self, subsystem: Subsystem) -> &mut SubsystemStatus {
+        match subsystem {
+            Subsystem::Scheduler => &mut self.scheduler,
+            Subsystem::Signer => &mut self.signer,
+            Subsystem::PriceProvider => &mut self.price_provider,
+            Subsystem::Broadcaster => &mut self.broadcaster,
+        }
+    }
+}
+
+/// Shared, cheaply clonable handle on the health of the client.
+#[derive(Clone)]
+pub struct HealthState {
+    inner: Arc<Mutex<HealthStateInner>>,
+}
+
+impl HealthState {
+    /// Nothing recorded yet, not ready until the signer, price provider and broadcasts succeeded.
+    pub fn new() -> HealthState {
+        HealthState {
+            inner: Arc::new(Mutex::new(HealthStateInner {
+                // We count starting up as a tick, otherwise we'd be dead until the first slot.
+                last_tick: Utc::now(),
+                current_slot: None,
+                recent_slots: VecDeque::with_capacity(READINESS_SLOT_COUNT),
+                scheduler: SubsystemStatus::default(),
+                signer: SubsystemStatus::default(),
+                price_provider: SubsystemStatus::default(),
+                broadcaster: SubsystemStatus::default(),
+            })),
+        }
+    }
+
+    /// The scheduler started working on `slot`, keeps the client live.
+    pub fn record_tick(&self, slot: Slot) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.last_tick = Utc::now();
+        inner.current_slot = Some(slot);
+    }
+
+    /// `subsystem` just did its work.
+    pub fn record_success(&self, subsystem: Subsystem) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.subsystem_mut(subsystem).last_success = Some(Utc::now());
+    }
+
+    /// `subsystem` just failed with `error`, the latest error is reported until replaced.
+    pub fn record_error(&self, subsystem: Subsystem, error: &eyre::Report) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.subsystem_mut(subsystem).last_error = Some(SubsystemError {
+            at: Utc::now(),
+            message: format!("{:#}", error),
+        });
+    }
+
+    /// Records the outcome of a subsystem's work and hands the result back unchanged.
+    pub fn track<T>(&self, subsystem: Subsystem, result: eyre::Result<T>) -> eyre::Result<T> {
+        match &result {
+            Ok(_) => self.record_success(subsystem),
+            Err(e) => self.record_error(subsystem, e),
+        }
+        result
+    }
+
+    /// Records whether the message for a slot made it out. Only the most recent slots are kept.
+    pub fn record_slot_outcome(&self, slot: Slot, broadcast: bool) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.recent_slots.len() == READINESS_SLOT_COUNT {
+            inner.recent_slots.pop_front();
+        }
+        inner.recent_slots.push_back(SlotOutcome {
+            slot: slot.0,
+            broadcast,
+        });
+    }
+
+    /// The client is live as long as the scheduler loop keeps ticking.
+    pub fn liveness(&self) -> HealthReport {
+        self.report_at(Utc::now(), |inner, now| {
+            now - inner.last_tick <= *LIVENESS_TICK_LIMIT
+        })
+    }
+
+    /// The client is ready when the signer is loaded, the price provider answered recently and
+    /// the last `READINESS_SLOT_COUNT` slots were broadcast.
+    pub fn readiness(&self) -> HealthReport {
+        self.report_at(Utc::now(), |inner, now| {
+            let signer_loaded = inner.signer.last_success.is_some();
+            let price_fresh = inner
+                .price_provider
+                .last_success
+                .is_some_and(|last_success| now - last_success <= *PRICE_FRESHNESS_LIMIT);
+            let recent_slots_broadcast = inner.recent_slots.len() == READINESS_SLOT_COUNT
+                && inner.recent_slots.iter().all(|outcome| outcome.broadcast);
+            signer_loaded && price_fresh && recent_slots_broadcast
+        })
+    }
+
+    fn report_at(
+        &self,
+        now: DateTime<Utc>,
+        check: impl Fn(&HealthStateInner, DateTime<Utc>) -> bool,
+    ) -> HealthReport {
+        let inner = self.inner.lock().unwrap();
+        HealthReport {
+            ok: check(&inner, now),
+            current_slot: inner.current_slot.map(|slot| slot.0),
+            last_tick: inner.last_tick,
+            recent_slots: inner.recent_slots.iter().copied().collect(),
+            subsystems: SubsystemsReport {
+                scheduler: inner.scheduler.clone(),
+                signer: inner.signer.clone(),
+                price_provider: inner.price_provider.clone(),
+                broadcaster: inner.broadcaster.clone(),
+            },
+        }
+    }
+}
+
+impl Default for HealthState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_live_after_start() {
+        let health = HealthState::new();
+        assert!(health.liveness().ok);
+    }
+
+    #[test]
+    fn is_not_live_when_scheduler_stopped_ticking() {
+        let health = HealthState::new();
+        health.inner.lock().unwrap().last_tick =
+            Utc::now() - Duration::seconds(LIVENESS_TICK_LIMIT_SECS + 1);
+        assert!(!health.liveness().ok);
+    }
+
+    #[test]
+    fn is_not_ready_after_start() {
+        let health = HealthState::new();
+        assert!(!health.readiness().ok);
+    }
+
+    #[test]
+    fn is_ready_after_successful_slots() {
+        let health = HealthState::new();
+        health.record_success(Subsystem::Signer);
+        for slot in 0..READINESS_SLOT_COUNT as u64 {
+            health.record_tick(Slot(slot));
+            health.record_success(Subsystem::PriceProvider);
+            health.record_success(Subsystem::Broadcaster);
+            health.record_slot_outcome(Slot(slot), true);
+        }
+        let report = health.readiness();
+        assert!(report.ok);
+        assert_eq!(report.current_slot, Some(READINESS_SLOT_COUNT as u64 - 1));
+    }
+
+    #[test]
+    fn is_not_ready_when_last_slot_failed() {
+        let health = HealthState::new();
+        health.record_success(Subsystem::Signer);
+        health.record_success(Subsystem::PriceProvider);
+        for slot in 0..READINESS_SLOT_COUNT as u64 {
+            health.record_slot_outcome(Slot(slot), true);
+        }
+        health.record_error(Subsystem::Broadcaster, &eyre::eyre!("server unavailable"));
+        health.record_slot_outcome(Slot(READINESS_SLOT_COUNT as u64), false);
+
+        let report = health.readiness();
+        assert!(!report.ok);
+        assert_eq!(
+            report.subsystems.broadcaster.last_error.unwrap().message,
+            "server unavailable"
+        );
+    }
+}
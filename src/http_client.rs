@@ -0,0 +1,145 @@
+//! # HTTP Client
+//! One tuned `reqwest::Client` for every HTTP-based provider and broadcaster, so connections are
+//! pooled and kept alive across slots instead of being set up for every request, and no request
+//! can hang forever. Clients are cheap to clone and clones share their pool.
+
+use std::{path::PathBuf, time::Duration};
+
+use eyre::{Context, Result};
+use lazy_static::lazy_static;
+
+pub const USER_AGENT: &str = concat!("oracle-client/", env!("CARGO_PKG_VERSION"));
+
+lazy_static! {
+    static ref DEFAULT_CLIENT: reqwest::Client = build_client(&HttpClientConfig::default())
+        .expect("expect default http client config to be valid");
+}
+
+#[derive(Clone, Debug)]
+pub struct HttpClientConfig {
+    pub connect_timeout: Duration,
+    /// Limit on the whole request, including reading the response.
+    pub request_timeout: Duration,
+    /// How long unused pooled connections are kept open.
+    pub pool_idle_timeout: Duration,
+    pub tcp_keepalive: Duration,
+    /// Speak HTTP/2 right away, for servers only offering cleartext HTTP/2. Over TLS HTTP/2 is
+    /// negotiated either way.
+    pub http2_prior_knowledge: bool,
+    /// Proxy for every request. Without one the `HTTP_PROXY` and `HTTPS_PROXY` environment
+    /// variables are used.
+    pub proxy: Option<String>,
+    /// PEM file with CA certificates to trust besides the system ones.
+    pub ca_bundle: Option<PathBuf>,
+    /// Certificate to present to servers requiring mutual TLS.
+    pub client_identity: Option<ClientIdentity>,
+}
+
+/// PEM files of a client certificate and its PKCS#8 private key.
+#[derive(Clone, Debug)]
+pub struct ClientIdentity {
+    pub certificate: PathBuf,
+    pub key: PathBuf,
+}
+
+impl Default for HttpClientConfig {
+    fn default() -> Self {
+        HttpClientConfig {
+            connect_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(10),
+            pool_idle_timeout: Duration::from_secs(90),
+            tcp_keepalive: Duration::from_secs(60),
+            http2_prior_knowledge: false,
+            proxy: None,
+            ca_bundle: None,
+            client_identity: None,
+        }
+    }
+}
+
+pub fn build_client(config: &HttpClientConfig) -> Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder()
+        .user_agent(USER_AGENT)
+        .connect_timeout(config.connect_timeout)
+        .timeout(config.request_timeout)
+        .pool_idle_timeout(config.pool_idle_timeout)
+        .tcp_keepalive(config.tcp_keepalive);
+    if config.http2_prior_knowledge {
+        builder = builder.http2_prior_knowledge();
+    }
+    if let Some(proxy) = &config.proxy {
+        builder = builder.proxy(
+            reqwest::Proxy::all(proxy).wrap_err_with(|| format!("Invalid proxy {}", proxy))?,
+        );
+    }
+    if let Some(ca_bundle) = &config.ca_bundle {
+        let pem = std::fs::read(ca_bundle)
+            .wrap_err_with(|| format!("Failed to read CA bundle {}", ca_bundle.display()))?;
+        let certificates = reqwest::Certificate::from_pem_bundle(&pem)
+            .wrap_err_with(|| format!("Invalid CA bundle {}", ca_bundle.display()))?;
+        for certificate in certificates {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+    if let Some(client_identity) = &config.client_identity {
+        let certificate = std::fs::read(&client_identity.certificate).wrap_err_with(|| {
+            format!(
+                "Failed to read client certificate {}",
+                client_identity.certificate.display()
+            )
+        })?;
+        let key = std::fs::read(&client_identity.key).wrap_err_with(|| {
+            format!(
+                "Failed to read client key {}",
+                client_identity.key.display()
+            )
+        })?;
+        builder = builder.identity(
+            reqwest::Identity::from_pkcs8_pem(&certificate, &key)
+                .wrap_err("Invalid client certificate or key")?,
+        );
+    }
+    builder.build().wrap_err("Failed to build http client")
+}
+
+/// The client with default settings, for components not given one explicitly.
+pub fn default_client() -> reqwest::Client {
+    DEFAULT_CLIENT.clone()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn sends_user_agent() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let mock = server
+            .mock("GET", "/")
+            .match_header("user-agent", USER_AGENT)
+            .with_status(200)
+            .create_async()
+            .await;
+
+        default_client().get(server.url()).send().await?;
+
+        mock.assert_async().await;
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_settings() {
+        let dir = tempfile::tempdir().unwrap();
+
+        assert!(build_client(&HttpClientConfig {
+            ca_bundle: Some(dir.path().join("missing.pem")),
+            ..HttpClientConfig::default()
+        })
+        .is_err());
+        assert!(build_client(&HttpClientConfig {
+            proxy: Some("not a url".to_string()),
+            ..HttpClientConfig::default()
+        })
+        .is_err());
+    }
+}
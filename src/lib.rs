@@ -0,0 +1,51 @@
+//! # Oracle Client
+//! Library behind the `oracle-client` binary, for services that want to generate, verify or
+//! broadcast oracle messages themselves.
+//!
+//! The most used items are re-exported here: the message types, the `PriceProvider`,
+//! `SignatureProvider` and `MessageBroadcaster` traits to plug in your own implementations, the
+//! `MessageGenerator` to sign messages, `verify_oracle_message` to check them and the
+//! `SystemClockAttestationScheduler` that ties it all together every slot.
+
+pub mod admin_server;
+pub mod aggregation;
+pub mod aggregation_node;
+pub mod attestation_scheduler;
+pub mod beacon_node;
+mod cli;
+pub mod config;
+pub mod doppelganger;
+pub mod health;
+pub mod http_client;
+pub mod message_broadcaster;
+pub mod message_generator;
+#[cfg(feature = "mock-server")]
+pub mod mock_oracle_server;
+pub mod price_provider;
+pub mod signature_provider;
+pub mod slashing_protection;
+pub mod slot;
+pub mod verification;
+
+pub use attestation_scheduler::{SchedulerConfig, SystemClockAttestationScheduler};
+pub use message_broadcaster::{
+    IntervalBoundsMessage, IntervalInclusionMessage, MessageBroadcaster, MessageVersion,
+    OracleMessage, OracleRegistration, PriceValueMessage, SignedIntervalBoundsMessage,
+    SignedIntervalInclusionMessage, SignedOracleRegistration, SignedPriceValueMessage,
+    ValidatorInfo, ValidatorStatus,
+};
+pub use message_generator::{MessageGenerator, MessageGeneratorConfig};
+pub use price_provider::{Price, PriceProvider};
+pub use signature_provider::SignatureProvider;
+pub use slot::Slot;
+pub use verification::{
+    verify_oracle_message, verify_oracle_registration, VerificationReport, VerifyOptions, Violation,
+};
+
+/// Entry point of the `oracle-client` binary, parses the command line and runs the command.
+#[doc(hidden)]
+pub async fn run_cli() -> eyre::Result<()> {
+    use clap::Parser;
+
+    cli::Cli::parse().execute().await
+}
@@ -0,0 +1,618 @@
+//! # Libp2p Message Broadcaster
+//! Gossips SSZ encoded messages over libp2p gossipsub, on a topic per network and pair, so there
+//! is no central server to fail or censor messages. Peers are found by dialing the bootnodes and
+//! walking a Kademlia DHT seeded with them. Every node checks received messages with
+//! `verify_oracle_message` before relaying them, peers gossiping invalid ones get penalised.
+//! Messages for slots too far from the current one are dropped without penalty, they may just
+//! come from a node with a skewed clock. Only a few messages are checked at a time and a limited
+//! number wait for their turn, the ones arriving beyond that are ignored, so peers can't flood us
+//! with expensive signature checks. A background task owns the node, broadcasts succeed once the
+//! message has been handed to at least one peer.
+
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use eyre::{Context, Result};
+use futures::StreamExt;
+use libp2p::{
+    gossipsub::{self, IdentTopic, MessageAcceptance, MessageId, PublishError},
+    identify, identity, kad,
+    multiaddr::Protocol,
+    noise,
+    swarm::{NetworkBehaviour, SwarmEvent},
+    tcp, yamux, Multiaddr, PeerId, StreamProtocol, Swarm,
+};
+use sha2::{Digest, Sha256};
+use ssz::{Decode, Encode};
+use tokio::sync::{broadcast, mpsc, oneshot, OwnedSemaphorePermit, Semaphore};
+
+use crate::{
+    http_client::USER_AGENT,
+    message_broadcaster::{MessageBroadcaster, OracleMessage},
+    slot::Slot,
+    verification::{verify_oracle_message, VerifyOptions},
+};
+
+pub const DEFAULT_LISTEN_ADDR: &str = "/ip4/0.0.0.0/tcp/9000";
+/// An epoch either way.
+pub const DEFAULT_MAX_SLOT_DISTANCE: u64 = 32;
+const PROTOCOL_VERSION: &str = "/oracle/1.0.0";
+const KADEMLIA_PROTOCOL: StreamProtocol = StreamProtocol::new("/oracle/kad/1.0.0");
+// V1 messages of wide intervals hold thousands of signatures.
+const MAX_TRANSMIT_SIZE: usize = 1 << 20;
+// Stop dialing peers found in the DHT beyond this many connections.
+const TARGET_PEERS: usize = 16;
+const BOOTSTRAP_INTERVAL: Duration = Duration::from_secs(300);
+const IDLE_CONNECTION_TIMEOUT: Duration = Duration::from_secs(60);
+const LISTEN_TIMEOUT: Duration = Duration::from_secs(5);
+const COMMAND_QUEUE_SIZE: usize = 16;
+const RECEIVED_QUEUE_SIZE: usize = 256;
+// V1 messages carry thousands of signatures, each check keeps a blocking thread busy.
+const MAX_CONCURRENT_VALIDATIONS: usize = 4;
+// Checked and waiting messages, enough for every validator of a large operator in one slot.
+const MAX_PENDING_VALIDATIONS: usize = 256;
+
+/// Gossip topic of the messages for `pair` on `network`, e.g. `/oracle/mainnet/ETH-USD/ssz`.
+pub fn topic_name(network: &str, pair: &str) -> String {
+    format!("/oracle/{}/{}/ssz", network, pair.replace('/', "-"))
+}
+
+#[derive(Clone, Debug)]
+pub struct Libp2pConfig {
+    pub listen_addrs: Vec<Multiaddr>,
+    /// Addresses ending in `/p2p/<peer id>` to join the network through.
+    pub bootnodes: Vec<Multiaddr>,
+    pub topic: String,
+    /// Messages for slots further from the current one are dropped.
+    pub max_slot_distance: u64,
+    pub heartbeat_interval: Duration,
+}
+
+impl Default for Libp2pConfig {
+    fn default() -> Self {
+        Libp2pConfig {
+            listen_addrs: vec![DEFAULT_LISTEN_ADDR
+                .parse()
+                .expect("expect default listen address to be valid")],
+            bootnodes: Vec::new(),
+            topic: topic_name("mainnet", "ETH/USD"),
+            max_slot_distance: DEFAULT_MAX_SLOT_DISTANCE,
+            heartbeat_interval: Duration::from_secs(1),
+        }
+    }
+}
+
+/// The peer id at the end of a bootnode address, and the address without it.
+pub fn split_peer_id(addr: &Multiaddr) -> Result<(PeerId, Multiaddr)> {
+    let mut addr = addr.clone();
+    match addr.pop() {
+        Some(Protocol::P2p(peer_id)) => Ok((peer_id, addr)),
+        _ => eyre::bail!("{} doesn't end in /p2p/<peer id>", addr),
+    }
+}
+
+#[derive(NetworkBehaviour)]
+struct Behaviour {
+    gossipsub: gossipsub::Behaviour,
+    identify: identify::Behaviour,
+    kademlia: kad::Behaviour<kad::store::MemoryStore>,
+}
+
+impl Behaviour {
+    fn new(
+        keypair: &identity::Keypair,
+        config: &Libp2pConfig,
+    ) -> std::result::Result<Behaviour, Box<dyn std::error::Error + Send + Sync>> {
+        let gossipsub_config = gossipsub::ConfigBuilder::default()
+            .heartbeat_interval(config.heartbeat_interval)
+            .max_transmit_size(MAX_TRANSMIT_SIZE)
+            // Hold messages back until we checked them.
+            .validate_messages()
+            // The same message reaching us from several peers is only relayed once.
+            .message_id_fn(|message| MessageId::from(Sha256::digest(&message.data).to_vec()))
+            .build()?;
+        let gossipsub = gossipsub::Behaviour::new(
+            gossipsub::MessageAuthenticity::Signed(keypair.clone()),
+            gossipsub_config,
+        )?;
+        let identify = identify::Behaviour::new(
+            identify::Config::new(PROTOCOL_VERSION.to_string(), keypair.public())
+                .with_agent_version(USER_AGENT.to_string()),
+        );
+        let peer_id = keypair.public().to_peer_id();
+        let mut kademlia_config = kad::Config::default();
+        // Our own DHT, not the IPFS one.
+        kademlia_config.set_protocol_names(vec![KADEMLIA_PROTOCOL]);
+        let mut kademlia = kad::Behaviour::with_config(
+            peer_id,
+            kad::store::MemoryStore::new(peer_id),
+            kademlia_config,
+        );
+        // Answer DHT queries even without a confirmed public address, e.g. on localhost.
+        kademlia.set_mode(Some(kad::Mode::Server));
+        Ok(Behaviour {
+            gossipsub,
+            identify,
+            kademlia,
+        })
+    }
+}
+
+/// What to do with a message received from a peer.
+#[derive(Debug)]
+enum Validation {
+    Accept(Box<OracleMessage>),
+    /// Not worth relaying, but no reason to penalise the sender.
+    Ignore(String),
+    /// Invalid, penalise the sender.
+    Reject(String),
+}
+
+fn validate(data: &[u8], current_slot: Slot, max_slot_distance: u64) -> Validation {
+    let msg = match OracleMessage::from_ssz_bytes(data) {
+        Ok(msg) => msg,
+        Err(e) => return Validation::Reject(format!("undecodable message: {:?}", e)),
+    };
+    let slot = msg.value_message.message.slot_number;
+    if slot.abs_diff(current_slot.0) > max_slot_distance {
+        return Validation::Ignore(format!(
+            "message for slot {} while at slot {}",
+            slot, current_slot.0
+        ));
+    }
+    let report = verify_oracle_message(&msg, &VerifyOptions::default());
+    if !report.is_valid() {
+        return Validation::Reject(format!("invalid message for slot {}: {}", slot, report));
+    }
+    Validation::Accept(Box::new(msg))
+}
+
+struct ValidationResult {
+    message_id: MessageId,
+    propagation_source: PeerId,
+    validation: Validation,
+    // Held until the result is reported.
+    _permit: OwnedSemaphorePermit,
+}
+
+struct PublishRequest {
+    data: Vec<u8>,
+    result: oneshot::Sender<Result<()>>,
+}
+
+#[derive(Clone)]
+pub struct Libp2pMessageBroadcaster {
+    local_peer_id: PeerId,
+    listen_addrs: Vec<Multiaddr>,
+    requests: mpsc::Sender<PublishRequest>,
+    received: broadcast::Sender<OracleMessage>,
+}
+
+impl Libp2pMessageBroadcaster {
+    /// Starts a node listening on the configured addresses and joins the network through the
+    /// bootnodes in the background.
+    pub async fn start(config: Libp2pConfig) -> Result<Libp2pMessageBroadcaster> {
+        let mut swarm = libp2p::SwarmBuilder::with_new_identity()
+            .with_tokio()
+            .with_tcp(
+                tcp::Config::default().nodelay(true),
+                noise::Config::new,
+                yamux::Config::default,
+            )?
+            .with_behaviour(|keypair| Behaviour::new(keypair, &config))
+            .map_err(|e| eyre::eyre!("Failed to set up libp2p node: {}", e))?
+            .with_swarm_config(|swarm_config| {
+                swarm_config.with_idle_connection_timeout(IDLE_CONNECTION_TIMEOUT)
+            })
+            .build();
+        let local_peer_id = *swarm.local_peer_id();
+        let topic = IdentTopic::new(config.topic.clone());
+        swarm.behaviour_mut().gossipsub.subscribe(&topic)?;
+
+        for addr in &config.listen_addrs {
+            swarm
+                .listen_on(addr.clone())
+                .wrap_err_with(|| format!("Failed to listen on {}", addr))?;
+        }
+        let listen_addrs = tokio::time::timeout(
+            LISTEN_TIMEOUT,
+            wait_for_listeners(&mut swarm, config.listen_addrs.len()),
+        )
+        .await
+        .wrap_err("Timed out starting to listen")??;
+        log::info!(
+            "Started libp2p node {} on {}",
+            local_peer_id,
+            listen_addrs
+                .iter()
+                .map(|addr| addr.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+
+        for bootnode in &config.bootnodes {
+            let (peer_id, addr) = split_peer_id(bootnode)?;
+            swarm
+                .behaviour_mut()
+                .kademlia
+                .add_address(&peer_id, addr.clone());
+            if let Err(e) = swarm.dial(bootnode.clone()) {
+                log::warn!("Failed to dial bootnode {} - {}", bootnode, e);
+            }
+        }
+
+        let (requests, request_receiver) = mpsc::channel(COMMAND_QUEUE_SIZE);
+        let (received, _) = broadcast::channel(RECEIVED_QUEUE_SIZE);
+        let node = Node {
+            swarm,
+            topic,
+            max_slot_distance: config.max_slot_distance,
+            received: received.clone(),
+            pending_validations: Arc::new(Semaphore::new(MAX_PENDING_VALIDATIONS)),
+            running_validations: Arc::new(Semaphore::new(MAX_CONCURRENT_VALIDATIONS)),
+        };
+        tokio::spawn(node.run(request_receiver));
+
+        Ok(Libp2pMessageBroadcaster {
+            local_peer_id,
+            listen_addrs: listen_addrs
+                .into_iter()
+                .map(|addr| addr.with(Protocol::P2p(local_peer_id)))
+                .collect(),
+            requests,
+            received,
+        })
+    }
+
+    pub fn local_peer_id(&self) -> PeerId {
+        self.local_peer_id
+    }
+
+    /// Where other nodes can reach this one, usable as their bootnodes.
+    pub fn listen_addrs(&self) -> &[Multiaddr] {
+        &self.listen_addrs
+    }
+
+    /// Valid messages received from peers, not the ones we broadcast ourselves.
+    pub fn subscribe(&self) -> broadcast::Receiver<OracleMessage> {
+        self.received.subscribe()
+    }
+}
+
+/// Drives the swarm until it reports an address for every listener.
+async fn wait_for_listeners(
+    swarm: &mut Swarm<Behaviour>,
+    listeners: usize,
+) -> Result<Vec<Multiaddr>> {
+    let mut listen_addrs = Vec::new();
+    let mut listener_ids = HashSet::new();
+    while listener_ids.len() < listeners {
+        match swarm.select_next_some().await {
+            SwarmEvent::NewListenAddr {
+                listener_id,
+                address,
+            } => {
+                listener_ids.insert(listener_id);
+                listen_addrs.push(address);
+            }
+            SwarmEvent::ListenerError { error, .. } => return Err(error.into()),
+            SwarmEvent::ListenerClosed { reason, .. } => {
+                reason.wrap_err("Listener closed")?;
+                eyre::bail!("Listener closed");
+            }
+            _ => {}
+        }
+    }
+    Ok(listen_addrs)
+}
+
+#[async_trait]
+impl MessageBroadcaster for Libp2pMessageBroadcaster {
+    async fn broadcast(&self, msg: &OracleMessage) -> Result<()> {
+        let (result, result_receiver) = oneshot::channel();
+        self.requests
+            .send(PublishRequest {
+                data: msg.as_ssz_bytes(),
+                result,
+            })
+            .await
+            .map_err(|_| eyre::eyre!("libp2p node stopped"))?;
+        result_receiver
+            .await
+            .map_err(|_| eyre::eyre!("libp2p node stopped"))?
+            .wrap_err_with(|| {
+                format!(
+                    "Failed to gossip message for slot {}",
+                    msg.value_message.message.slot_number
+                )
+            })
+    }
+}
+
+/// Owns the swarm, meant to run as a background task.
+struct Node {
+    swarm: Swarm<Behaviour>,
+    topic: IdentTopic,
+    max_slot_distance: u64,
+    received: broadcast::Sender<OracleMessage>,
+    pending_validations: Arc<Semaphore>,
+    running_validations: Arc<Semaphore>,
+}
+
+impl Node {
+    async fn run(mut self, mut requests: mpsc::Receiver<PublishRequest>) {
+        // Checking signatures takes long enough to stall the swarm, so it happens elsewhere.
+        let (validated, mut validation_results) = mpsc::channel(MAX_CONCURRENT_VALIDATIONS);
+        let mut bootstrap = tokio::time::interval(BOOTSTRAP_INTERVAL);
+        loop {
+            tokio::select! {
+                request = requests.recv() => match request {
+                    Some(request) => self.publish(request),
+                    None => return,
+                },
+                Some(result) = validation_results.recv() => self.report_validation(result),
+                event = self.swarm.select_next_some() => self.handle_event(event, &validated),
+                _ = bootstrap.tick() => {
+                    // Fails without known peers, the next tick tries again.
+                    let _ = self.swarm.behaviour_mut().kademlia.bootstrap();
+                }
+            }
+        }
+    }
+
+    fn publish(&mut self, request: PublishRequest) {
+        let result = match self
+            .swarm
+            .behaviour_mut()
+            .gossipsub
+            .publish(self.topic.clone(), request.data)
+        {
+            // Already gossiped, e.g. when an outbox redelivers it.
+            Ok(_) | Err(PublishError::Duplicate) => Ok(()),
+            Err(PublishError::InsufficientPeers) => Err(eyre::eyre!("no peers to gossip to")),
+            Err(e) => Err(eyre::eyre!("{}", e)),
+        };
+        let _ = request.result.send(result);
+    }
+
+    fn report_validation(&mut self, result: ValidationResult) {
+        let acceptance = match result.validation {
+            Validation::Accept(msg) => {
+                // Nobody listening is fine.
+                let _ = self.received.send(*msg);
+                MessageAcceptance::Accept
+            }
+            Validation::Ignore(reason) => {
+                log::debug!("Ignoring gossiped message - {}", reason);
+                MessageAcceptance::Ignore
+            }
+            Validation::Reject(reason) => {
+                log::warn!(
+                    "Rejecting message gossiped by {} - {}",
+                    result.propagation_source,
+                    reason
+                );
+                MessageAcceptance::Reject
+            }
+        };
+        let _ = self
+            .swarm
+            .behaviour_mut()
+            .gossipsub
+            .report_message_validation_result(
+                &result.message_id,
+                &result.propagation_source,
+                acceptance,
+            );
+    }
+
+    fn handle_event(
+        &mut self,
+        event: SwarmEvent<BehaviourEvent>,
+        validated: &mpsc::Sender<ValidationResult>,
+    ) {
+        match event {
+            SwarmEvent::Behaviour(BehaviourEvent::Gossipsub(gossipsub::Event::Message {
+                propagation_source,
+                message_id,
+                message,
+            })) => {
+                let Ok(permit) = self.pending_validations.clone().try_acquire_owned() else {
+                    log::debug!(
+                        "Ignoring message gossiped by {}, {} messages are waiting to be checked",
+                        propagation_source,
+                        MAX_PENDING_VALIDATIONS
+                    );
+                    let _ = self
+                        .swarm
+                        .behaviour_mut()
+                        .gossipsub
+                        .report_message_validation_result(
+                            &message_id,
+                            &propagation_source,
+                            MessageAcceptance::Ignore,
+                        );
+                    return;
+                };
+                let validated = validated.clone();
+                let running_validations = self.running_validations.clone();
+                let max_slot_distance = self.max_slot_distance;
+                tokio::spawn(async move {
+                    let Ok(_running) = running_validations.acquire_owned().await else {
+                        return;
+                    };
+                    let Ok(validation) = tokio::task::spawn_blocking(move || {
+                        validate(&message.data, Slot::now(), max_slot_distance)
+                    })
+                    .await
+                    else {
+                        return;
+                    };
+                    let _ = validated
+                        .send(ValidationResult {
+                            message_id,
+                            propagation_source,
+                            validation,
+                            _permit: permit,
+                        })
+                        .await;
+                });
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify::Event::Received {
+                peer_id,
+                info,
+            })) => {
+                for addr in info.listen_addrs {
+                    self.swarm
+                        .behaviour_mut()
+                        .kademlia
+                        .add_address(&peer_id, addr);
+                }
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Kademlia(kad::Event::RoutingUpdated {
+                peer,
+                is_new_peer: true,
+                ..
+            })) if !self.swarm.is_connected(&peer)
+                && self.swarm.connected_peers().count() < TARGET_PEERS =>
+            {
+                if let Err(e) = self.swarm.dial(peer) {
+                    log::debug!("Failed to dial peer {} - {}", peer, e);
+                }
+            }
+            SwarmEvent::ConnectionEstablished { peer_id, .. } => {
+                log::debug!("Connected to peer {}", peer_id);
+            }
+            SwarmEvent::ConnectionClosed { peer_id, cause, .. } => {
+                log::debug!("Disconnected from peer {} - {:?}", peer_id, cause);
+            }
+            SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
+                log::debug!("Failed to connect to {:?} - {}", peer_id, error);
+            }
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        message_generator::MessageGenerator, price_provider::Price,
+        signature_provider::private_key::PrivateKeySignatureProvider,
+    };
+
+    fn generate_message(slot: Slot) -> OracleMessage {
+        MessageGenerator::new(Box::new(PrivateKeySignatureProvider::random()))
+            .generate_oracle_message(
+                Price {
+                    value: 1_000_000_000,
+                },
+                slot,
+            )
+            .unwrap()
+    }
+
+    async fn start_node(bootnodes: Vec<Multiaddr>) -> Libp2pMessageBroadcaster {
+        Libp2pMessageBroadcaster::start(Libp2pConfig {
+            listen_addrs: vec!["/ip4/127.0.0.1/tcp/0".parse().unwrap()],
+            bootnodes,
+            heartbeat_interval: Duration::from_millis(100),
+            ..Libp2pConfig::default()
+        })
+        .await
+        .unwrap()
+    }
+
+    /// Keeps broadcasting fresh messages from `sender` until one reaches `receiver`, the mesh
+    /// takes a few heartbeats to form.
+    async fn wait_for_mesh(
+        sender: &Libp2pMessageBroadcaster,
+        receiver: &mut broadcast::Receiver<OracleMessage>,
+    ) {
+        for _ in 0..50 {
+            let _ = sender.broadcast(&generate_message(Slot::now())).await;
+            if tokio::time::timeout(Duration::from_millis(200), receiver.recv())
+                .await
+                .is_ok()
+            {
+                return;
+            }
+        }
+        panic!("message never arrived");
+    }
+
+    #[test]
+    fn validates_messages() {
+        let slot = Slot(100);
+        let msg = generate_message(slot);
+        assert!(matches!(
+            validate(&msg.as_ssz_bytes(), slot, 2),
+            Validation::Accept(_)
+        ));
+        assert!(matches!(
+            validate(&msg.as_ssz_bytes(), Slot(103), 2),
+            Validation::Ignore(_)
+        ));
+
+        let mut tampered_msg = msg.clone();
+        tampered_msg.value_message.message.price.value += 1;
+        assert!(matches!(
+            validate(&tampered_msg.as_ssz_bytes(), slot, 2),
+            Validation::Reject(_)
+        ));
+        assert!(matches!(
+            validate(b"not ssz", slot, 2),
+            Validation::Reject(_)
+        ));
+    }
+
+    #[test]
+    fn reads_bootnode_addresses() {
+        let peer_id = PeerId::random();
+        let addr: Multiaddr = "/ip4/10.0.0.1/tcp/9000".parse().unwrap();
+
+        let (split_peer, split_addr) =
+            split_peer_id(&addr.clone().with(Protocol::P2p(peer_id))).unwrap();
+        assert_eq!(split_peer, peer_id);
+        assert_eq!(split_addr, addr);
+        assert!(split_peer_id(&addr).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn relays_only_valid_messages() {
+        let bootnode = start_node(Vec::new()).await;
+        let sender = start_node(bootnode.listen_addrs().to_vec()).await;
+        let receiver_node = start_node(bootnode.listen_addrs().to_vec()).await;
+        let mut received = receiver_node.subscribe();
+        wait_for_mesh(&sender, &mut received).await;
+
+        let mut tampered_msg = generate_message(Slot::now());
+        tampered_msg.value_message.message.price.value += 1;
+        let msg = generate_message(Slot::now());
+        sender.broadcast(&tampered_msg).await.unwrap();
+        sender.broadcast(&msg).await.unwrap();
+
+        // Messages from forming the mesh may still be arriving.
+        loop {
+            let relayed = tokio::time::timeout(Duration::from_secs(5), received.recv())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_ne!(relayed.id(), tampered_msg.id());
+            if relayed.id() == msg.id() {
+                break;
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn fails_without_peers() {
+        let node = start_node(Vec::new()).await;
+
+        assert!(node
+            .broadcast(&generate_message(Slot::now()))
+            .await
+            .is_err());
+    }
+}
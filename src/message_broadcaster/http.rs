@@ -1,10 +1,107 @@
+use std::{path::Path, str::FromStr};
+
 use async_trait::async_trait;
+use bls::PublicKey;
 use eyre::{Context, Result};
+use hmac::{Hmac, Mac};
+use reqwest::{
+    header::{CONTENT_ENCODING, CONTENT_TYPE, RETRY_AFTER},
+    IntoUrl, RequestBuilder, Response, StatusCode,
+};
+use sha2::Sha256;
+use ssz::Encode;
+
+use crate::{
+    http_client::default_client,
+    message_broadcaster::{
+        response::{
+            parse_retry_after, BroadcastError, RejectionReason, SubmissionResponse,
+            SubmissionStatus,
+        },
+        MessageBroadcaster, MessageFeed, OracleMessage, SignedOracleRegistration,
+    },
+    slot::Slot,
+};
+
+/// Where registrations go, next to the endpoint messages are posted to.
+pub const REGISTRATION_PATH: &str = "register_oracle";
+
+/// Header carrying the hex encoded HMAC-SHA256 of the request body.
+pub const HMAC_SIGNATURE_HEADER: &str = "x-oracle-signature";
+
+/// How an `OracleMessage` is put on the wire. The JSON form hex encodes every signature, SSZ
+/// roughly halves that, snappy compression on top shaves off a bit more.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageEncoding {
+    Json,
+    Ssz,
+    SszSnappy,
+}
+
+impl FromStr for MessageEncoding {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(MessageEncoding::Json),
+            "ssz" => Ok(MessageEncoding::Ssz),
+            "ssz_snappy" => Ok(MessageEncoding::SszSnappy),
+            _ => Err(eyre::eyre!(
+                "unknown message encoding {}, expected json, ssz or ssz_snappy",
+                s
+            )),
+        }
+    }
+}
+
+/// Credentials telling the server which operator a request comes from. Client certificates for
+/// mutual TLS belong to the `reqwest::Client` instead, see `HttpClientConfig::client_identity`.
+#[derive(Clone, Default)]
+pub struct HttpAuth {
+    pub bearer_token: Option<String>,
+    /// Key to sign request bodies with, see `HMAC_SIGNATURE_HEADER`.
+    pub hmac_key: Option<Vec<u8>>,
+}
+
+impl HttpAuth {
+    /// Reads the secrets from files, so they don't end up in the environment or the config.
+    /// Trailing whitespace like a final newline is ignored.
+    pub fn from_files(
+        bearer_token_file: Option<&Path>,
+        hmac_key_file: Option<&Path>,
+    ) -> Result<HttpAuth> {
+        Ok(HttpAuth {
+            bearer_token: bearer_token_file.map(read_secret).transpose()?,
+            hmac_key: hmac_key_file
+                .map(|path| read_secret(path).map(String::into_bytes))
+                .transpose()?,
+        })
+    }
+}
+
+fn read_secret(path: &Path) -> Result<String> {
+    let secret = std::fs::read_to_string(path)
+        .wrap_err_with(|| format!("Failed to read secret from {}", path.display()))?;
+    let secret = secret.trim_end();
+    if secret.is_empty() {
+        eyre::bail!("Secret file {} is empty", path.display());
+    }
+    Ok(secret.to_string())
+}
 
-use crate::message_broadcaster::{MessageBroadcaster, OracleMessage};
+/// Hex encoded HMAC-SHA256 of `body`, as sent in `HMAC_SIGNATURE_HEADER`.
+pub fn hmac_signature(key: &[u8], body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key).expect("expect HMAC to accept keys of any length");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
 
 pub struct HttpMessageBroadcaster {
     server_url: String,
+    encoding: MessageEncoding,
+    client: reqwest::Client,
+    auth: HttpAuth,
 }
 
 impl HttpMessageBroadcaster {
@@ -12,35 +109,177 @@ impl HttpMessageBroadcaster {
         let server_url = std::env::var("SERVER_URL").context(
             "expect SERVER_URL in env when no server_url is given to HttpMessageBroadcaster",
         )?;
-        Ok(HttpMessageBroadcaster { server_url })
+        Ok(Self::new_with_url(&server_url))
     }
 
-    #[cfg(test)]
     pub fn new_with_url(server_url: &str) -> HttpMessageBroadcaster {
+        Self::new_with_encoding(server_url, MessageEncoding::Json)
+    }
+
+    pub fn new_with_encoding(
+        server_url: &str,
+        encoding: MessageEncoding,
+    ) -> HttpMessageBroadcaster {
+        Self::new_with_client(server_url, encoding, default_client())
+    }
+
+    pub fn new_with_client(
+        server_url: &str,
+        encoding: MessageEncoding,
+        client: reqwest::Client,
+    ) -> HttpMessageBroadcaster {
         HttpMessageBroadcaster {
             server_url: server_url.to_string(),
+            encoding,
+            client,
+            auth: HttpAuth::default(),
+        }
+    }
+
+    pub fn with_auth(mut self, auth: HttpAuth) -> Self {
+        self.auth = auth;
+        self
+    }
+
+    /// POSTs `body` with our credentials, the HMAC covers the body exactly as sent.
+    fn post(&self, url: impl IntoUrl, body: Vec<u8>) -> RequestBuilder {
+        let mut request = self.client.post(url);
+        if let Some(bearer_token) = &self.auth.bearer_token {
+            request = request.bearer_auth(bearer_token);
+        }
+        if let Some(hmac_key) = &self.auth.hmac_key {
+            request = request.header(HMAC_SIGNATURE_HEADER, hmac_signature(hmac_key, &body));
         }
+        request.body(body)
     }
 
     async fn send_request(&self, msg: &OracleMessage) -> Result<()> {
-        let client = reqwest::Client::new();
         log::debug!("Sending message to server at: {:}", self.server_url);
-        let response = client
-            .post(&self.server_url)
-            .json(&msg)
+        let request = match self.encoding {
+            MessageEncoding::Json => self
+                .post(&self.server_url, serde_json::to_vec(msg)?)
+                .header(CONTENT_TYPE, "application/json"),
+            MessageEncoding::Ssz => self
+                .post(&self.server_url, msg.as_ssz_bytes())
+                .header(CONTENT_TYPE, "application/octet-stream"),
+            MessageEncoding::SszSnappy => self
+                .post(
+                    &self.server_url,
+                    snap::raw::Encoder::new()
+                        .compress_vec(&msg.as_ssz_bytes())
+                        .wrap_err("Failed to snappy compress message")?,
+                )
+                .header(CONTENT_TYPE, "application/octet-stream")
+                .header(CONTENT_ENCODING, "snappy"),
+        };
+        let response = request
             .send()
             .await
             .map_err(|e| eyre::eyre!("Error sending message: {}", e))?;
         log::debug!("Response: {:?}", response);
-        if response.status().is_success() {
-            Ok(())
-        } else {
-            Err(eyre::eyre!(
-                "Non-Success response when submitting oracle message: {:?}",
-                response
-            ))
+        check_response(
+            response,
+            &format!("message for slot {}", msg.value_message.message.slot_number),
+        )
+        .await
+    }
+}
+
+/// Statuses worth retrying the same request on when the body gives no reason.
+fn is_retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::REQUEST_TIMEOUT
+        || status.as_u16() == 425
+        || status == StatusCode::TOO_MANY_REQUESTS
+        || status.is_server_error()
+}
+
+/// Turns the server's answer to `submission` into a result, classifying rejections. Servers that
+/// don't answer with a `SubmissionResponse` are judged by status code alone.
+async fn check_response(response: Response, submission: &str) -> Result<()> {
+    let status = response.status();
+    let retry_after = response
+        .headers()
+        .get(RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| parse_retry_after(value, chrono::Utc::now()));
+    let body = response.json::<SubmissionResponse>().await.ok();
+
+    let server_slot = body.as_ref().and_then(|body| body.current_slot);
+    if let Some(server_slot) = server_slot {
+        let our_slot = Slot::now().0;
+        if server_slot.abs_diff(our_slot) > 1 {
+            log::warn!(
+                "Server is at slot {} while we are at slot {}, check the system clock",
+                server_slot,
+                our_slot
+            );
         }
     }
+
+    let rejected = body
+        .as_ref()
+        .is_some_and(|body| body.status == SubmissionStatus::Rejected);
+    if status.is_success() && !rejected {
+        return Ok(());
+    }
+
+    let reason = body.as_ref().and_then(|body| body.reason);
+    let details = body
+        .as_ref()
+        .map(|body| {
+            body.message
+                .iter()
+                .chain(body.violations.iter())
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join(", ")
+        })
+        .unwrap_or_default();
+    match reason {
+        Some(RejectionReason::BadSignature) => log::error!(
+            "Server rejected the signature of {}, check the signing key - {}",
+            submission,
+            details
+        ),
+        Some(RejectionReason::LateSlot) => log::warn!(
+            "Server rejected {} as late, it is at slot {} - {}",
+            submission,
+            server_slot.map_or("unknown".to_string(), |slot| slot.to_string()),
+            details
+        ),
+        Some(RejectionReason::UnknownValidator) => log::error!(
+            "Server doesn't know our validator, rejected {}. Run `oracle-client register` - {}",
+            submission,
+            details
+        ),
+        Some(RejectionReason::Unauthorized) => log::error!(
+            "Server refused our credentials for {} - {}",
+            submission,
+            details
+        ),
+        Some(RejectionReason::RateLimited) => log::warn!(
+            "Server is rate limiting us, rejected {} - {}",
+            submission,
+            details
+        ),
+        Some(reason) => log::warn!("Server rejected {}: {} - {}", submission, reason, details),
+        None => log::warn!(
+            "Server rejected {} with status {} - {}",
+            submission,
+            status,
+            details
+        ),
+    }
+
+    Err(BroadcastError {
+        retryable: reason
+            .and_then(|reason| reason.is_retryable())
+            .unwrap_or_else(|| is_retryable_status(status)),
+        retry_after,
+        reason,
+        message: format!("Server rejected {} with status {}", submission, status),
+    }
+    .into())
 }
 
 #[async_trait]
@@ -49,23 +288,100 @@ impl MessageBroadcaster for HttpMessageBroadcaster {
         self.send_request(msg).await?;
         Ok(())
     }
+
+    async fn broadcast_registration(&self, registration: &SignedOracleRegistration) -> Result<()> {
+        let registration_url = reqwest::Url::parse(&self.server_url)
+            .and_then(|server_url| server_url.join(REGISTRATION_PATH))
+            .wrap_err_with(|| format!("Invalid server url {}", self.server_url))?;
+        log::debug!("Sending registration to server at: {}", registration_url);
+        let response = self
+            .post(registration_url, serde_json::to_vec(registration)?)
+            .header(CONTENT_TYPE, "application/json")
+            .send()
+            .await
+            .map_err(|e| eyre::eyre!("Error sending registration: {}", e))?;
+        check_response(response, "registration").await
+    }
 }
 
 impl Clone for HttpMessageBroadcaster {
     fn clone(&self) -> Self {
         HttpMessageBroadcaster {
             server_url: self.server_url.clone(),
+            encoding: self.encoding,
+            client: self.client.clone(),
+            auth: self.auth.clone(),
+        }
+    }
+}
+
+/// Queries the messages an oracle server received per slot, as served by `GET /slots/<slot>/messages`
+/// of the mock oracle server.
+#[derive(Clone)]
+pub struct HttpMessageFeed {
+    server_url: String,
+    client: reqwest::Client,
+}
+
+impl HttpMessageFeed {
+    /// `server_url` is the base url of the server, without a path.
+    pub fn new(server_url: &str) -> HttpMessageFeed {
+        Self::new_with_client(server_url, default_client())
+    }
+
+    pub fn new_with_client(server_url: &str, client: reqwest::Client) -> HttpMessageFeed {
+        HttpMessageFeed {
+            server_url: server_url.trim_end_matches('/').to_string(),
+            client,
+        }
+    }
+}
+
+#[async_trait]
+impl MessageFeed for HttpMessageFeed {
+    async fn has_message(&self, slot: u64, validator_public_key: &PublicKey) -> Result<bool> {
+        let url = format!("{}/slots/{}/messages", self.server_url, slot);
+        let response = self
+            .client
+            .get(&url)
+            .query(&[("validator_public_key", validator_public_key.to_string())])
+            .send()
+            .await
+            .wrap_err_with(|| format!("Error querying messages at {}", url))?;
+        // No messages for the slot at all.
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
         }
+        let messages: Vec<OracleMessage> = response
+            .error_for_status()
+            .wrap_err_with(|| format!("Non-Success response querying messages at {}", url))?
+            .json()
+            .await
+            .wrap_err_with(|| format!("Invalid messages response from {}", url))?;
+        // Servers may ignore the filter, so check the keys ourselves.
+        Ok(messages
+            .iter()
+            .any(|message| message.validator_public_key == *validator_public_key))
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{
+        io::{BufRead, BufReader, Read, Write},
+        net::{SocketAddr, TcpListener},
+        path::PathBuf,
+    };
+
     use mockito::Matcher;
+    use openssl::ssl::{SslAcceptor, SslFiletype, SslMethod, SslVerifyMode};
 
     use crate::{
-        message_generator::MessageGenerator, price_provider::Price,
-        signature_provider::private_key::PrivateKeySignatureProvider, slot::Slot,
+        http_client::{build_client, ClientIdentity, HttpClientConfig},
+        message_generator::MessageGenerator,
+        price_provider::Price,
+        signature_provider::{private_key::PrivateKeySignatureProvider, SignatureProvider},
+        slot::Slot,
     };
 
     use super::*;
@@ -92,4 +408,252 @@ mod tests {
 
         Ok(())
     }
+
+    #[tokio::test]
+    async fn sends_ssz_snappy_body() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+
+        let broadcaster =
+            HttpMessageBroadcaster::new_with_encoding(&server.url(), MessageEncoding::SszSnappy);
+
+        let signature_provider = PrivateKeySignatureProvider::random();
+        let message = MessageGenerator::new(Box::new(signature_provider))
+            .generate_oracle_message(Price { value: 10 }, Slot(1))?;
+        let expected_body = snap::raw::Encoder::new().compress_vec(&message.as_ssz_bytes())?;
+
+        let mock = server
+            .mock("POST", "/")
+            .match_header("content-type", "application/octet-stream")
+            .match_header("content-encoding", "snappy")
+            .match_body(expected_body)
+            .with_status(200)
+            .create_async()
+            .await;
+
+        broadcaster.broadcast(&message).await?;
+
+        mock.assert_async().await;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn classifies_rejections() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let broadcaster = HttpMessageBroadcaster::new_with_url(&server.url());
+        let message = generate_message()?;
+
+        let mock = server
+            .mock("POST", "/")
+            .with_status(429)
+            .with_header("retry-after", "30")
+            .create_async()
+            .await;
+        let error = broadcaster.broadcast(&message).await.unwrap_err();
+        let classification = BroadcastError::find(&error).unwrap();
+        assert!(classification.retryable);
+        assert_eq!(
+            classification.retry_after,
+            Some(std::time::Duration::from_secs(30))
+        );
+        mock.remove_async().await;
+
+        let mock = server
+            .mock("POST", "/")
+            .with_status(400)
+            .with_body(r#"{"status": "rejected", "reason": "bad_signature", "current_slot": 1}"#)
+            .create_async()
+            .await;
+        let error = broadcaster.broadcast(&message).await.unwrap_err();
+        let classification = BroadcastError::find(&error).unwrap();
+        assert!(!classification.retryable);
+        assert_eq!(classification.reason, Some(RejectionReason::BadSignature));
+        mock.remove_async().await;
+
+        // Accepted by status code, rejected by body.
+        server
+            .mock("POST", "/")
+            .with_status(200)
+            .with_body(r#"{"status": "rejected", "reason": "late_slot"}"#)
+            .create_async()
+            .await;
+        let error = broadcaster.broadcast(&message).await.unwrap_err();
+        assert!(BroadcastError::is_permanent(&error));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn finds_messages_of_key_in_feed() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let signature_provider = PrivateKeySignatureProvider::random();
+        let public_key = signature_provider.get_public_key()?;
+        let message = MessageGenerator::new(Box::new(signature_provider))
+            .generate_oracle_message(Price { value: 10 }, Slot(1))?;
+        server
+            .mock("GET", "/slots/1/messages")
+            .match_query(Matcher::UrlEncoded(
+                "validator_public_key".to_string(),
+                public_key.to_string(),
+            ))
+            .with_status(200)
+            .with_body(serde_json::to_string(&vec![message])?)
+            .create_async()
+            .await;
+        server
+            .mock("GET", "/slots/2/messages")
+            .match_query(Matcher::Any)
+            .with_status(404)
+            .create_async()
+            .await;
+
+        let feed = HttpMessageFeed::new(&server.url());
+
+        assert!(feed.has_message(1, &public_key).await?);
+        assert!(!feed.has_message(2, &public_key).await?);
+
+        Ok(())
+    }
+
+    fn generate_message() -> Result<OracleMessage> {
+        MessageGenerator::new(Box::new(PrivateKeySignatureProvider::random()))
+            .generate_oracle_message(Price { value: 10 }, Slot(1))
+    }
+
+    fn write_secret(dir: &tempfile::TempDir, name: &str, secret: &str) -> PathBuf {
+        let path = dir.path().join(name);
+        std::fs::write(&path, format!("{}\n", secret)).unwrap();
+        path
+    }
+
+    #[test]
+    fn computes_hmac_signature() {
+        // Test case 2 of RFC 4231.
+        assert_eq!(
+            hmac_signature(b"Jefe", b"what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_bearer_token() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let dir = tempfile::tempdir()?;
+        let auth = HttpAuth::from_files(Some(&write_secret(&dir, "token", "s3cret")), None)?;
+        let broadcaster = HttpMessageBroadcaster::new_with_url(&server.url()).with_auth(auth);
+        let mock = server
+            .mock("POST", "/")
+            .match_header("authorization", "Bearer s3cret")
+            .with_status(200)
+            .create_async()
+            .await;
+
+        broadcaster.broadcast(&generate_message()?).await?;
+
+        mock.assert_async().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn signs_body_with_hmac() -> Result<()> {
+        let mut server = mockito::Server::new_async().await;
+        let dir = tempfile::tempdir()?;
+        let auth = HttpAuth::from_files(None, Some(&write_secret(&dir, "hmac_key", "key")))?;
+        let broadcaster =
+            HttpMessageBroadcaster::new_with_encoding(&server.url(), MessageEncoding::Ssz)
+                .with_auth(auth);
+        let message = generate_message()?;
+        let mock = server
+            .mock("POST", "/")
+            .match_header(
+                HMAC_SIGNATURE_HEADER,
+                hmac_signature(b"key", &message.as_ssz_bytes()).as_str(),
+            )
+            .match_body(message.as_ssz_bytes())
+            .with_status(200)
+            .create_async()
+            .await;
+
+        broadcaster.broadcast(&message).await?;
+
+        mock.assert_async().await;
+        Ok(())
+    }
+
+    /// Answers every request with 200, over TLS only accepting client certificates of the test CA.
+    fn spawn_mtls_server() -> SocketAddr {
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor
+            .set_private_key_file("test_data/tls/server.key", SslFiletype::PEM)
+            .unwrap();
+        acceptor
+            .set_certificate_chain_file("test_data/tls/server.pem")
+            .unwrap();
+        acceptor.set_ca_file("test_data/tls/ca.pem").unwrap();
+        acceptor.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        let acceptor = acceptor.build();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        std::thread::spawn(move || {
+            for tcp_stream in listener.incoming() {
+                // Fails without a valid client certificate.
+                let Ok(stream) = acceptor.accept(tcp_stream.unwrap()) else {
+                    continue;
+                };
+                let mut stream = BufReader::new(stream);
+                let mut content_length = 0;
+                loop {
+                    let mut line = String::new();
+                    stream.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                    if let Some((name, value)) = line.split_once(':') {
+                        if name.eq_ignore_ascii_case("content-length") {
+                            content_length = value.trim().parse().unwrap();
+                        }
+                    }
+                }
+                let mut body = vec![0; content_length];
+                stream.read_exact(&mut body).unwrap();
+                stream
+                    .get_mut()
+                    .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                    .unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn presents_client_certificate() -> Result<()> {
+        let addr = spawn_mtls_server();
+        let url = format!("https://{}/", addr);
+        let http_client_config = HttpClientConfig {
+            ca_bundle: Some(PathBuf::from("test_data/tls/ca.pem")),
+            ..HttpClientConfig::default()
+        };
+        let message = generate_message()?;
+
+        let anonymous = HttpMessageBroadcaster::new_with_client(
+            &url,
+            MessageEncoding::Json,
+            build_client(&http_client_config)?,
+        );
+        assert!(anonymous.broadcast(&message).await.is_err());
+
+        let authenticated = HttpMessageBroadcaster::new_with_client(
+            &url,
+            MessageEncoding::Json,
+            build_client(&HttpClientConfig {
+                client_identity: Some(ClientIdentity {
+                    certificate: PathBuf::from("test_data/tls/client.pem"),
+                    key: PathBuf::from("test_data/tls/client.key"),
+                }),
+                ..http_client_config
+            })?,
+        );
+        authenticated.broadcast(&message).await?;
+        Ok(())
+    }
 }
@@ -1,36 +1,308 @@
+//! # JSON File Archive
+//! Keeps every message on disk, by default as one pretty printed file per slot in a flat
+//! directory. At 7200 slots a day that fills disks quickly, so `JsonArchiveConfig` can put files
+//! in a directory per day, append them to a daily NDJSON log instead, compress them and prune the
+//! oldest files by age or total size. Compressed NDJSON logs are a series of independently
+//! compressed lines, which `gzip -d` and `zstd -d` read like a single stream.
+
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Instant, SystemTime},
+};
+
 use async_trait::async_trait;
+use chrono::Duration;
 use eyre::Result;
 
-use crate::message_broadcaster::{MessageBroadcaster, OracleMessage};
+use crate::{
+    message_broadcaster::{MessageBroadcaster, OracleMessage, SignedOracleRegistration},
+    slot::Slot,
+};
 
 pub const DIRECTORY_PATH: &str = "test_messages";
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    /// One pretty printed file per slot.
+    FilePerSlot,
+    /// One message per line, appended to a log that rotates daily.
+    Ndjson,
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "files" => Ok(ArchiveFormat::FilePerSlot),
+            "ndjson" => Ok(ArchiveFormat::Ndjson),
+            _ => Err(eyre::eyre!(
+                "unknown archive format {}, expected files or ndjson",
+                s
+            )),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveCompression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl FromStr for ArchiveCompression {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(ArchiveCompression::None),
+            "gzip" => Ok(ArchiveCompression::Gzip),
+            "zstd" => Ok(ArchiveCompression::Zstd),
+            _ => Err(eyre::eyre!(
+                "unknown archive compression {}, expected none, gzip or zstd",
+                s
+            )),
+        }
+    }
+}
+
+impl ArchiveCompression {
+    fn extension(&self) -> &'static str {
+        match self {
+            ArchiveCompression::None => "",
+            ArchiveCompression::Gzip => ".gz",
+            ArchiveCompression::Zstd => ".zst",
+        }
+    }
+
+    fn compress(&self, bytes: Vec<u8>) -> Result<Vec<u8>> {
+        match self {
+            ArchiveCompression::None => Ok(bytes),
+            ArchiveCompression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&bytes)?;
+                Ok(encoder.finish()?)
+            }
+            ArchiveCompression::Zstd => Ok(zstd::encode_all(bytes.as_slice(), 0)?),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct JsonArchiveConfig {
+    pub format: ArchiveFormat,
+    pub compression: ArchiveCompression,
+    /// Put files into a subdirectory per day, e.g. `2023-07-04/`.
+    pub partition_by_date: bool,
+    /// Files last written longer ago are deleted.
+    pub max_age: Option<Duration>,
+    /// The oldest files are deleted while the archive is larger.
+    pub max_total_bytes: Option<u64>,
+    /// How often the limits are applied, besides whenever the date rolls over. Applying them
+    /// walks the whole archive, so not on every write.
+    pub prune_interval: Duration,
+}
+
+impl Default for JsonArchiveConfig {
+    fn default() -> Self {
+        JsonArchiveConfig {
+            format: ArchiveFormat::FilePerSlot,
+            compression: ArchiveCompression::None,
+            partition_by_date: false,
+            max_age: None,
+            max_total_bytes: None,
+            prune_interval: Duration::hours(1),
+        }
+    }
+}
+
 pub struct JsonFileMessageBroadcaster {
     directory_path: String,
+    config: JsonArchiveConfig,
+    // Concurrent slots must not interleave appends or prune each other's files. Holds when and
+    // for which date the archive was last pruned.
+    lock: Arc<Mutex<Option<(Instant, String)>>>,
 }
 
 impl JsonFileMessageBroadcaster {
-    #[allow(dead_code)]
     pub fn new(directory_path: Option<String>) -> Result<JsonFileMessageBroadcaster> {
-        // Create directory if it doesn't exist yet
         let directory_path = match directory_path {
             Some(path) => path,
             None => String::from(DIRECTORY_PATH),
         };
-        std::fs::create_dir_all(&directory_path)?;
-        Ok(JsonFileMessageBroadcaster { directory_path })
+        Self::new_with_config(&directory_path, JsonArchiveConfig::default())
+    }
+
+    pub fn new_with_config(
+        directory_path: &str,
+        config: JsonArchiveConfig,
+    ) -> Result<JsonFileMessageBroadcaster> {
+        // Create directory if it doesn't exist yet
+        std::fs::create_dir_all(directory_path)?;
+        Ok(JsonFileMessageBroadcaster {
+            directory_path: directory_path.to_string(),
+            config,
+            lock: Arc::new(Mutex::new(None)),
+        })
+    }
+
+    /// Where the message for `slot` goes.
+    fn file_path(&self, slot: u64) -> PathBuf {
+        let date = date(slot);
+        let mut path = PathBuf::from(&self.directory_path);
+        if self.config.partition_by_date {
+            path.push(&date);
+        }
+        let file_name = match (self.config.format, self.config.partition_by_date) {
+            (ArchiveFormat::FilePerSlot, _) => format!("{}.json", slot),
+            (ArchiveFormat::Ndjson, true) => "messages.ndjson".to_string(),
+            (ArchiveFormat::Ndjson, false) => format!("messages-{}.ndjson", date),
+        };
+        path.push(file_name + self.config.compression.extension());
+        path
     }
 
     fn write_file(&self, msg: &OracleMessage) -> Result<()> {
-        let file_name = format!(
-            "{}/{}.json",
-            self.directory_path, msg.value_message.message.slot_number
-        );
-        log::debug!("Writing message to file: {}", file_name);
-        let file = std::fs::File::create(file_name)?;
-        serde_json::to_writer_pretty(file, &msg)?;
+        let mut last_prune = self.lock.lock().unwrap();
+        let slot = msg.value_message.message.slot_number;
+        let file_path = self.file_path(slot);
+        log::debug!("Writing message to file: {}", file_path.display());
+        if let Some(parent) = file_path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        match self.config.format {
+            ArchiveFormat::FilePerSlot => {
+                let bytes = self
+                    .config
+                    .compression
+                    .compress(serde_json::to_vec_pretty(msg)?)?;
+                std::fs::write(&file_path, bytes)?;
+            }
+            ArchiveFormat::Ndjson => {
+                let mut line = serde_json::to_vec(msg)?;
+                line.push(b'\n');
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&file_path)?
+                    .write_all(&self.config.compression.compress(line)?)?;
+            }
+        }
+
+        let date = date(slot);
+        let prune_due = match last_prune.as_ref() {
+            Some((pruned_at, pruned_date)) => {
+                *pruned_date != date
+                    || pruned_at.elapsed()
+                        >= self.config.prune_interval.to_std().unwrap_or_default()
+            }
+            None => true,
+        };
+        if prune_due {
+            // The message is archived, a full disk shows up in the next write anyway.
+            if let Err(e) = self.prune(&file_path) {
+                log::warn!("Failed to prune message archive - {:?}", e);
+            }
+            *last_prune = Some((Instant::now(), date));
+        }
         Ok(())
     }
+
+    /// Applies the retention limits, never deleting the file just written.
+    fn prune(&self, current_file: &Path) -> Result<()> {
+        if self.config.max_age.is_none() && self.config.max_total_bytes.is_none() {
+            return Ok(());
+        }
+        let mut files = Vec::new();
+        collect_archive_files(Path::new(&self.directory_path), &mut files)?;
+        // Oldest first.
+        files.sort_by(|a, b| (a.modified, &a.path).cmp(&(b.modified, &b.path)));
+
+        let cut_off = self
+            .config
+            .max_age
+            .and_then(|max_age| max_age.to_std().ok())
+            .and_then(|max_age| SystemTime::now().checked_sub(max_age));
+        let mut total_bytes: u64 = files.iter().map(|file| file.len).sum();
+        for file in files.iter().filter(|file| file.path != current_file) {
+            let too_old = cut_off.is_some_and(|cut_off| file.modified < cut_off);
+            let too_large = self
+                .config
+                .max_total_bytes
+                .is_some_and(|max_total_bytes| total_bytes > max_total_bytes);
+            if !too_old && !too_large {
+                continue;
+            }
+            log::debug!("Pruning archived messages in {}", file.path.display());
+            std::fs::remove_file(&file.path)?;
+            total_bytes -= file.len;
+            if let Some(parent) = file.path.parent() {
+                // Only succeeds once a date directory is empty.
+                if parent != Path::new(&self.directory_path) {
+                    let _ = std::fs::remove_dir(parent);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+fn date(slot: u64) -> String {
+    Slot(slot).to_date_time().format("%Y-%m-%d").to_string()
+}
+
+struct ArchiveFile {
+    path: PathBuf,
+    modified: SystemTime,
+    len: u64,
+}
+
+/// Whether `file_path` could have produced `name`, i.e. `<slot>.json` or
+/// `messages[-<date>].ndjson`, with any compression extension.
+fn is_archive_file_name(name: &str) -> bool {
+    let name = [ArchiveCompression::Gzip, ArchiveCompression::Zstd]
+        .iter()
+        .find_map(|compression| name.strip_suffix(compression.extension()))
+        .unwrap_or(name);
+    if let Some(slot) = name.strip_suffix(".json") {
+        return !slot.is_empty() && slot.bytes().all(|b| b.is_ascii_digit());
+    }
+    match name.strip_suffix(".ndjson") {
+        Some("messages") => true,
+        Some(log) => log
+            .strip_prefix("messages-")
+            .is_some_and(|date| chrono::NaiveDate::parse_from_str(date, "%Y-%m-%d").is_ok()),
+        None => false,
+    }
+}
+
+/// Every file under `directory` this broadcaster could have written.
+fn collect_archive_files(directory: &Path, files: &mut Vec<ArchiveFile>) -> Result<()> {
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        let path = entry.path();
+        if metadata.is_dir() {
+            collect_archive_files(&path, files)?;
+        } else if path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(is_archive_file_name)
+        {
+            files.push(ArchiveFile {
+                path,
+                modified: metadata.modified()?,
+                len: metadata.len(),
+            });
+        }
+    }
+    Ok(())
 }
 
 #[async_trait]
@@ -44,6 +316,238 @@ impl Clone for JsonFileMessageBroadcaster {
     fn clone(&self) -> Self {
         JsonFileMessageBroadcaster {
             directory_path: self.directory_path.clone(),
+            config: self.config.clone(),
+            lock: self.lock.clone(),
+        }
+    }
+}
+
+/// Archives every message before handing it on, whether broadcasting it succeeds or not.
+pub struct ArchivingMessageBroadcaster<B: MessageBroadcaster> {
+    inner: B,
+    archive: JsonFileMessageBroadcaster,
+}
+
+impl<B: MessageBroadcaster> ArchivingMessageBroadcaster<B> {
+    pub fn new(inner: B, archive: JsonFileMessageBroadcaster) -> ArchivingMessageBroadcaster<B> {
+        ArchivingMessageBroadcaster { inner, archive }
+    }
+}
+
+#[async_trait]
+impl<B: MessageBroadcaster + Send + Sync> MessageBroadcaster for ArchivingMessageBroadcaster<B> {
+    async fn broadcast(&self, msg: &OracleMessage) -> Result<()> {
+        // Losing the archived copy of a message must not fail its broadcast.
+        if let Err(e) = self.archive.write_file(msg) {
+            log::error!("Failed to archive message - {:?}", e);
+        }
+        self.inner.broadcast(msg).await
+    }
+
+    async fn broadcast_registration(&self, registration: &SignedOracleRegistration) -> Result<()> {
+        self.inner.broadcast_registration(registration).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read};
+
+    use super::*;
+    use crate::{
+        message_generator::MessageGenerator, price_provider::Price,
+        signature_provider::private_key::PrivateKeySignatureProvider,
+    };
+
+    const SLOTS_PER_DAY: u64 = 24 * 60 * 60 / Slot::SLOT_PERIOD_SECONDS;
+
+    fn generate_message(slot: u64) -> OracleMessage {
+        MessageGenerator::new(Box::new(PrivateKeySignatureProvider::random()))
+            .generate_oracle_message(Price { value: 10 }, Slot(slot))
+            .unwrap()
+    }
+
+    struct FailingMessageBroadcaster;
+
+    #[async_trait]
+    impl MessageBroadcaster for FailingMessageBroadcaster {
+        async fn broadcast(&self, _msg: &OracleMessage) -> Result<()> {
+            eyre::bail!("server unreachable")
+        }
+    }
+
+    fn archive(dir: &tempfile::TempDir, config: JsonArchiveConfig) -> JsonFileMessageBroadcaster {
+        JsonFileMessageBroadcaster::new_with_config(dir.path().to_str().unwrap(), config).unwrap()
+    }
+
+    #[tokio::test]
+    async fn rotates_ndjson_log_daily() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let broadcaster = archive(
+            &dir,
+            JsonArchiveConfig {
+                format: ArchiveFormat::Ndjson,
+                compression: ArchiveCompression::Gzip,
+                ..JsonArchiveConfig::default()
+            },
+        );
+        // Slot 0 starts at noon, so the next day starts half a day later.
+        let tomorrow = SLOTS_PER_DAY / 2;
+
+        for slot in [1, 2, tomorrow] {
+            broadcaster.broadcast(&generate_message(slot)).await?;
+        }
+
+        let read_slots = |date: String| -> Vec<u64> {
+            let path = dir.path().join(format!("messages-{}.ndjson.gz", date));
+            let decoder = flate2::read::MultiGzDecoder::new(std::fs::File::open(path).unwrap());
+            BufReader::new(decoder)
+                .lines()
+                .map(|line| {
+                    serde_json::from_str::<OracleMessage>(&line.unwrap())
+                        .unwrap()
+                        .value_message
+                        .message
+                        .slot_number
+                })
+                .collect()
+        };
+        assert_eq!(read_slots(date(1)), vec![1, 2]);
+        assert_eq!(read_slots(date(tomorrow)), vec![tomorrow]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn partitions_files_by_date() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let broadcaster = archive(
+            &dir,
+            JsonArchiveConfig {
+                compression: ArchiveCompression::Zstd,
+                partition_by_date: true,
+                ..JsonArchiveConfig::default()
+            },
+        );
+
+        broadcaster.broadcast(&generate_message(5)).await?;
+
+        let path = dir.path().join(date(5)).join("5.json.zst");
+        let mut json = String::new();
+        zstd::Decoder::new(std::fs::File::open(path)?)?.read_to_string(&mut json)?;
+        let message: OracleMessage = serde_json::from_str(&json)?;
+        assert_eq!(message.value_message.message.slot_number, 5);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prunes_when_date_rolls_over() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_size = serde_json::to_vec_pretty(&generate_message(1))?.len() as u64;
+        let broadcaster = archive(
+            &dir,
+            JsonArchiveConfig {
+                // Room for one and a half messages.
+                max_total_bytes: Some(file_size * 3 / 2),
+                ..JsonArchiveConfig::default()
+            },
+        );
+        let tomorrow = SLOTS_PER_DAY / 2;
+
+        for slot in [1, 2] {
+            broadcaster.broadcast(&generate_message(slot)).await?;
         }
+        // Within the prune interval.
+        assert!(dir.path().join("1.json").exists());
+
+        broadcaster.broadcast(&generate_message(tomorrow)).await?;
+        let remaining = std::fs::read_dir(dir.path())?
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(remaining, vec![format!("{}.json", tomorrow)]);
+        Ok(())
+    }
+
+    #[test]
+    fn matches_only_archive_file_names() {
+        for name in [
+            "7.json",
+            "7.json.gz",
+            "messages.ndjson.zst",
+            "messages-2024-01-31.ndjson",
+        ] {
+            assert!(is_archive_file_name(name), "{}", name);
+        }
+        for name in [
+            "config.json",
+            "7.json.bak",
+            "7.json.gz.tmp",
+            ".json",
+            "messages-latest.ndjson",
+            "other.ndjson",
+        ] {
+            assert!(!is_archive_file_name(name), "{}", name);
+        }
+    }
+
+    #[tokio::test]
+    async fn prunes_by_age_and_size() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        // Two days after the old slot.
+        let later_slot = 2 * SLOTS_PER_DAY;
+        let file_size = serde_json::to_vec_pretty(&generate_message(later_slot))?.len() as u64;
+        let broadcaster = archive(
+            &dir,
+            JsonArchiveConfig {
+                partition_by_date: true,
+                max_age: Some(Duration::days(1)),
+                // Room for two and a half messages.
+                max_total_bytes: Some(file_size * 5 / 2),
+                prune_interval: Duration::zero(),
+                ..JsonArchiveConfig::default()
+            },
+        );
+        let old_slot = 1;
+        broadcaster.broadcast(&generate_message(old_slot)).await?;
+        std::fs::File::options()
+            .write(true)
+            .open(dir.path().join(date(old_slot)).join("1.json"))?
+            .set_modified(SystemTime::now() - std::time::Duration::from_secs(2 * 24 * 60 * 60))?;
+
+        broadcaster.broadcast(&generate_message(later_slot)).await?;
+        // Past the age limit, and its date directory went with it.
+        assert!(!dir.path().join(date(old_slot)).exists());
+
+        broadcaster
+            .broadcast(&generate_message(later_slot + 1))
+            .await?;
+        broadcaster
+            .broadcast(&generate_message(later_slot + 2))
+            .await?;
+        let mut remaining = std::fs::read_dir(dir.path().join(date(later_slot)))?
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        remaining.sort();
+        assert_eq!(
+            remaining,
+            vec![
+                format!("{}.json", later_slot + 1),
+                format!("{}.json", later_slot + 2)
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn archives_messages_that_failed_to_broadcast() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let broadcaster = ArchivingMessageBroadcaster::new(
+            FailingMessageBroadcaster,
+            archive(&dir, JsonArchiveConfig::default()),
+        );
+
+        assert!(broadcaster.broadcast(&generate_message(3)).await.is_err());
+
+        assert!(dir.path().join("3.json").exists());
+        Ok(())
     }
 }
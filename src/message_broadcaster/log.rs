@@ -1,7 +1,7 @@
 use async_trait::async_trait;
 use eyre::Result;
 
-use crate::message_broadcaster::{MessageBroadcaster, OracleMessage};
+use crate::message_broadcaster::{MessageBroadcaster, OracleMessage, SignedOracleRegistration};
 
 pub struct LogMessageBroadcaster {}
 
@@ -11,6 +11,11 @@ impl MessageBroadcaster for LogMessageBroadcaster {
         log::debug!("Broadcasting message: {:?}", msg);
         Ok(())
     }
+
+    async fn broadcast_registration(&self, registration: &SignedOracleRegistration) -> Result<()> {
+        log::debug!("Broadcasting registration: {:?}", registration);
+        Ok(())
+    }
 }
 
 impl Clone for LogMessageBroadcaster {
@@ -1,3 +1,5 @@
+use std::str::FromStr;
+
 use async_trait::async_trait;
 use eyre::Result;
 use serde::{Deserialize, Serialize};
@@ -5,18 +7,186 @@ use ssz_derive::{Decode, Encode};
 
 use crate::price_provider::Price;
 use bls::{PublicKey, Signature};
+use ethers::types::Address;
 
+pub mod gossip;
 pub mod http;
 pub mod json;
 pub mod log;
+pub mod multi;
+pub mod nats;
+pub mod outbox;
+pub mod redis_stream;
+pub mod response;
+pub mod sqlite;
+mod ssz_encoding;
+pub mod stdout;
+pub mod websocket;
+
+/// Which interval attestation scheme an `OracleMessage` uses.
+/// V1 signs an `IntervalInclusionMessage` for every step in the interval, V2 signs a single
+/// `IntervalBoundsMessage`. Both coexist while servers migrate, V1 messages leave the version out
+/// of their JSON so they look exactly like they did before versioning.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(into = "u8", try_from = "u8")]
+pub enum MessageVersion {
+    #[default]
+    V1 = 1,
+    V2 = 2,
+}
+
+impl MessageVersion {
+    fn is_v1(&self) -> bool {
+        *self == MessageVersion::V1
+    }
+}
+
+impl From<MessageVersion> for u8 {
+    fn from(version: MessageVersion) -> Self {
+        version as u8
+    }
+}
 
+impl TryFrom<u8> for MessageVersion {
+    type Error = String;
+
+    fn try_from(version: u8) -> std::result::Result<Self, Self::Error> {
+        match version {
+            1 => Ok(MessageVersion::V1),
+            2 => Ok(MessageVersion::V2),
+            _ => Err(format!("unknown message version {}", version)),
+        }
+    }
+}
+
+impl FromStr for MessageVersion {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let version = s
+            .parse::<u8>()
+            .map_err(|e| eyre::eyre!("invalid message version {}: {}", s, e))?;
+        MessageVersion::try_from(version).map_err(|e| eyre::eyre!(e))
+    }
+}
+
+/// Everything a validator attests to for one slot, all signed with `validator_public_key`'s key.
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct OracleMessage {
+    #[serde(default, skip_serializing_if = "MessageVersion::is_v1")]
+    pub version: MessageVersion,
     pub value_message: SignedPriceValueMessage,
+    /// Only filled for `MessageVersion::V1`.
     pub interval_inclusion_messages: Vec<SignedIntervalInclusionMessage>,
+    /// Only filled for `MessageVersion::V2`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval_bounds_message: Option<SignedIntervalBoundsMessage>,
     pub validator_public_key: PublicKey,
+    /// Not signed, servers should check it against their own beacon node.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub validator_info: Option<ValidatorInfo>,
+}
+
+impl OracleMessage {
+    /// Tells the message apart from those of other slots and keys, to deduplicate deliveries.
+    pub fn id(&self) -> String {
+        format!(
+            "{}-{}",
+            self.value_message.message.slot_number, self.validator_public_key
+        )
+    }
+
+    /// The interval the message attests to, whichever version it is. For V1 the bounds are those
+    /// of its first and last step.
+    pub fn interval_bounds(&self) -> Option<IntervalBoundsMessage> {
+        if let Some(interval_bounds_message) = &self.interval_bounds_message {
+            return Some(interval_bounds_message.message.clone());
+        }
+        let first = self.interval_inclusion_messages.first()?;
+        let last = self.interval_inclusion_messages.last()?;
+        Some(IntervalBoundsMessage {
+            lower_bound: first.message.value,
+            upper_bound: last.message.value + 1,
+            interval_size: first.message.interval_size,
+            slot_number: first.message.slot_number,
+        })
+    }
+}
+
+/// Where `OracleMessage::validator_public_key` stands on the beacon chain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValidatorInfo {
+    pub index: u64,
+    pub status: ValidatorStatus,
+}
+
+/// Validator status as reported by the beacon API.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ValidatorStatus {
+    PendingInitialized = 0,
+    PendingQueued = 1,
+    ActiveOngoing = 2,
+    ActiveExiting = 3,
+    ActiveSlashed = 4,
+    ExitedUnslashed = 5,
+    ExitedSlashed = 6,
+    WithdrawalPossible = 7,
+    WithdrawalDone = 8,
+}
+
+impl ValidatorStatus {
+    /// Whether the validator is attesting on the beacon chain, slashed ones don't count.
+    pub fn is_active(&self) -> bool {
+        matches!(
+            self,
+            ValidatorStatus::ActiveOngoing | ValidatorStatus::ActiveExiting
+        )
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ValidatorStatus::PendingInitialized => "pending_initialized",
+            ValidatorStatus::PendingQueued => "pending_queued",
+            ValidatorStatus::ActiveOngoing => "active_ongoing",
+            ValidatorStatus::ActiveExiting => "active_exiting",
+            ValidatorStatus::ActiveSlashed => "active_slashed",
+            ValidatorStatus::ExitedUnslashed => "exited_unslashed",
+            ValidatorStatus::ExitedSlashed => "exited_slashed",
+            ValidatorStatus::WithdrawalPossible => "withdrawal_possible",
+            ValidatorStatus::WithdrawalDone => "withdrawal_done",
+        }
+    }
+}
+
+impl TryFrom<u8> for ValidatorStatus {
+    type Error = String;
+
+    fn try_from(status: u8) -> std::result::Result<Self, Self::Error> {
+        [
+            ValidatorStatus::PendingInitialized,
+            ValidatorStatus::PendingQueued,
+            ValidatorStatus::ActiveOngoing,
+            ValidatorStatus::ActiveExiting,
+            ValidatorStatus::ActiveSlashed,
+            ValidatorStatus::ExitedUnslashed,
+            ValidatorStatus::ExitedSlashed,
+            ValidatorStatus::WithdrawalPossible,
+            ValidatorStatus::WithdrawalDone,
+        ]
+        .get(status as usize)
+        .copied()
+        .ok_or_else(|| format!("unknown validator status {}", status))
+    }
+}
+
+impl std::fmt::Display for ValidatorStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
+/// Attests to the exact price seen at the slot.
 #[derive(Clone, Debug, Decode, Encode, Serialize, Deserialize)]
 pub struct PriceValueMessage {
     pub price: Price,
@@ -29,6 +199,7 @@ pub struct SignedPriceValueMessage {
     pub signature: Signature,
 }
 
+/// Attests to the price being close to `value`, one step of the interval around the price.
 #[derive(Clone, Debug, Decode, Encode, Serialize, Deserialize)]
 pub struct IntervalInclusionMessage {
     pub value: u64,
@@ -42,7 +213,86 @@ pub struct SignedIntervalInclusionMessage {
     pub signature: Signature,
 }
 
+/// Attests to every interval value in `[lower_bound, upper_bound)` at once.
+#[derive(Clone, Debug, Decode, Encode, Serialize, Deserialize)]
+pub struct IntervalBoundsMessage {
+    pub lower_bound: u64,
+    pub upper_bound: u64,
+    pub interval_size: u64,
+    pub slot_number: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SignedIntervalBoundsMessage {
+    pub message: IntervalBoundsMessage,
+    pub signature: Signature,
+}
+
+/// Registers a validator key with oracle servers. Aggregating interval signatures is only safe
+/// against rogue-key attacks for keys whose owner proved they hold the private key, which is
+/// what `proof_of_possession` does.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OracleRegistration {
+    pub validator_public_key: PublicKey,
+    /// Signature over `proof_of_possession_message` of the public key.
+    pub proof_of_possession: Signature,
+    /// Where the operator wants rewards to go.
+    pub fee_recipient: Address,
+    /// Free form name of who runs the client.
+    pub operator: String,
+    pub client_version: String,
+    /// Unix timestamp, servers keep the most recent registration per key.
+    pub timestamp: u64,
+}
+
+impl OracleRegistration {
+    /// Prefixed so a proof of possession can't be mistaken for a signature over anything else.
+    const PROOF_OF_POSSESSION_DOMAIN: &'static [u8] = b"ORACLE_PROOF_OF_POSSESSION";
+
+    /// What the proof of possession of `public_key` signs.
+    pub fn proof_of_possession_message(public_key: &PublicKey) -> Vec<u8> {
+        [
+            Self::PROOF_OF_POSSESSION_DOMAIN,
+            public_key.serialize().as_slice(),
+        ]
+        .concat()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SignedOracleRegistration {
+    pub message: OracleRegistration,
+    pub signature: Signature,
+}
+
+/// Delivers signed oracle messages somewhere, e.g. to an oracle-server.
 #[async_trait]
 pub trait MessageBroadcaster {
+    /// Errors when the message could not be delivered.
     async fn broadcast(&self, msg: &OracleMessage) -> Result<()>;
+
+    /// Submits a registration, errors when it could not be delivered. Not every broadcaster has
+    /// somewhere to send registrations to.
+    async fn broadcast_registration(&self, _registration: &SignedOracleRegistration) -> Result<()> {
+        eyre::bail!("broadcaster does not support registrations")
+    }
+}
+
+/// Lets callers pick a broadcaster at runtime, e.g. with or without an outbox in front of it.
+#[async_trait]
+impl<T: MessageBroadcaster + Send + Sync + ?Sized> MessageBroadcaster for Box<T> {
+    async fn broadcast(&self, msg: &OracleMessage) -> Result<()> {
+        (**self).broadcast(msg).await
+    }
+
+    async fn broadcast_registration(&self, registration: &SignedOracleRegistration) -> Result<()> {
+        (**self).broadcast_registration(registration).await
+    }
+}
+
+/// Read side of where messages are broadcast to, used to spot other clients signing with our key.
+#[async_trait]
+pub trait MessageFeed {
+    /// Whether a message signed by `validator_public_key` has been seen for `slot`.
+    async fn has_message(&self, slot: u64, validator_public_key: &PublicKey) -> Result<bool>;
 }
@@ -0,0 +1,464 @@
+//! # Multi Message Broadcaster
+//! Broadcasts every message to several targets concurrently, e.g. our own aggregator and the public
+//! oracle-server. Each target gets its own timeout and reports its own result, whether the
+//! broadcast as a whole succeeded is decided by a `BroadcastPolicy`. Until it is met, the targets
+//! that accepted a message are remembered, so broadcasting it again, e.g. from the outbox, only
+//! goes to the ones that didn't.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt::Display,
+    future::Future,
+    str::FromStr,
+    sync::Mutex,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use eyre::Result;
+use futures::future::join_all;
+use tokio::time::{timeout, Instant};
+
+use crate::message_broadcaster::{
+    response::BroadcastError, MessageBroadcaster, OracleMessage, SignedOracleRegistration,
+};
+
+pub const DEFAULT_TARGET_TIMEOUT: Duration = Duration::from_secs(10);
+// Messages we remember accepting targets of, more than an hour of slots for a few keys.
+const MAX_TRACKED_MESSAGES: usize = 1024;
+
+/// How many targets need to accept a message for the broadcast to count as a success.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BroadcastPolicy {
+    All,
+    Any,
+    Quorum(usize),
+}
+
+impl BroadcastPolicy {
+    fn is_satisfied(&self, succeeded: usize, total: usize) -> bool {
+        match self {
+            BroadcastPolicy::All => succeeded == total,
+            BroadcastPolicy::Any => succeeded > 0,
+            BroadcastPolicy::Quorum(quorum) => succeeded >= *quorum,
+        }
+    }
+}
+
+impl FromStr for BroadcastPolicy {
+    type Err = eyre::Report;
+
+    /// Parses `all`, `any` or `quorum:<n>`.
+    fn from_str(s: &str) -> Result<Self> {
+        match s.split_once(':') {
+            None if s == "all" => Ok(BroadcastPolicy::All),
+            None if s == "any" => Ok(BroadcastPolicy::Any),
+            Some(("quorum", quorum)) => {
+                Ok(BroadcastPolicy::Quorum(quorum.parse().map_err(|e| {
+                    eyre::eyre!("invalid quorum in broadcast policy {}: {}", s, e)
+                })?))
+            }
+            _ => Err(eyre::eyre!(
+                "unknown broadcast policy {}, expected all, any or quorum:<n>",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for BroadcastPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BroadcastPolicy::All => write!(f, "all"),
+            BroadcastPolicy::Any => write!(f, "any"),
+            BroadcastPolicy::Quorum(quorum) => write!(f, "quorum:{}", quorum),
+        }
+    }
+}
+
+/// A named broadcaster, given up on when it takes longer than its timeout.
+pub struct BroadcastTarget {
+    name: String,
+    broadcaster: Box<dyn MessageBroadcaster + Send + Sync>,
+    timeout: Duration,
+}
+
+impl BroadcastTarget {
+    pub fn new(
+        name: &str,
+        broadcaster: Box<dyn MessageBroadcaster + Send + Sync>,
+        timeout: Duration,
+    ) -> BroadcastTarget {
+        BroadcastTarget {
+            name: name.to_string(),
+            broadcaster,
+            timeout,
+        }
+    }
+}
+
+/// How broadcasting to one target went.
+#[derive(Debug)]
+pub struct TargetResult {
+    pub name: String,
+    pub result: Result<()>,
+    pub duration: Duration,
+}
+
+/// Outcome of broadcasting one message to every target.
+#[derive(Debug)]
+pub struct MultiBroadcastReport {
+    pub results: Vec<TargetResult>,
+    /// Targets that accepted the message before and weren't sent it again.
+    pub already_accepted: Vec<String>,
+}
+
+impl MultiBroadcastReport {
+    /// Including the targets that accepted the message before.
+    pub fn succeeded(&self) -> usize {
+        self.results.iter().filter(|r| r.result.is_ok()).count() + self.already_accepted.len()
+    }
+
+    pub fn total(&self) -> usize {
+        self.results.len() + self.already_accepted.len()
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = &TargetResult> {
+        self.results.iter().filter(|r| r.result.is_err())
+    }
+}
+
+/// Broadcasts to every target at once and checks the outcome against a `BroadcastPolicy`.
+pub struct MultiMessageBroadcaster {
+    targets: Vec<BroadcastTarget>,
+    policy: BroadcastPolicy,
+    /// Names of the targets that accepted a message whose policy isn't met yet, by slot and key.
+    accepted: Mutex<BTreeMap<(u64, String), HashSet<String>>>,
+}
+
+impl MultiMessageBroadcaster {
+    pub fn new(targets: Vec<BroadcastTarget>, policy: BroadcastPolicy) -> MultiMessageBroadcaster {
+        MultiMessageBroadcaster {
+            targets,
+            policy,
+            accepted: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Broadcasts to all targets concurrently and collects every target's result, never stopping
+    /// at the first error. Targets that accepted the message before are skipped.
+    pub async fn broadcast_to_targets(&self, msg: &OracleMessage) -> MultiBroadcastReport {
+        let key = (
+            msg.value_message.message.slot_number,
+            msg.validator_public_key.to_string(),
+        );
+        let already_accepted = self
+            .accepted
+            .lock()
+            .unwrap()
+            .get(&key)
+            .cloned()
+            .unwrap_or_default();
+        let report = self
+            .run_on_targets(&already_accepted, |broadcaster| broadcaster.broadcast(msg))
+            .await;
+
+        let mut accepted = self.accepted.lock().unwrap();
+        if self.policy.is_satisfied(report.succeeded(), report.total()) {
+            accepted.remove(&key);
+        } else {
+            accepted.entry(key).or_default().extend(
+                report
+                    .results
+                    .iter()
+                    .filter(|r| r.result.is_ok())
+                    .map(|r| r.name.clone()),
+            );
+            while accepted.len() > MAX_TRACKED_MESSAGES {
+                accepted.pop_first();
+            }
+        }
+        report
+    }
+
+    async fn run_on_targets<'a, F, Fut>(
+        &'a self,
+        already_accepted: &HashSet<String>,
+        send: F,
+    ) -> MultiBroadcastReport
+    where
+        F: Fn(&'a (dyn MessageBroadcaster + Send + Sync)) -> Fut,
+        Fut: Future<Output = Result<()>>,
+    {
+        let (skipped, targets): (Vec<_>, Vec<_>) = self
+            .targets
+            .iter()
+            .partition(|target| already_accepted.contains(&target.name));
+        let send = &send;
+        let results = join_all(targets.into_iter().map(|target| async move {
+            let start = Instant::now();
+            let result = timeout(target.timeout, send(target.broadcaster.as_ref()))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(eyre::eyre!(
+                        "Hit {}ms timeout broadcasting to {}",
+                        target.timeout.as_millis(),
+                        target.name
+                    ))
+                });
+            TargetResult {
+                name: target.name.clone(),
+                result,
+                duration: start.elapsed(),
+            }
+        }))
+        .await;
+        MultiBroadcastReport {
+            results,
+            already_accepted: skipped.iter().map(|target| target.name.clone()).collect(),
+        }
+    }
+
+    fn check_policy(&self, report: MultiBroadcastReport) -> Result<()> {
+        for name in report.already_accepted.iter() {
+            log::debug!("Not broadcasting to {} again, it accepted before", name);
+        }
+        for target_result in report.results.iter() {
+            match &target_result.result {
+                Ok(()) => log::debug!(
+                    "Broadcast to {} succeeded after {}ms",
+                    target_result.name,
+                    target_result.duration.as_millis()
+                ),
+                Err(e) => log::warn!(
+                    "Broadcast to {} failed after {}ms - {:?}",
+                    target_result.name,
+                    target_result.duration.as_millis(),
+                    e
+                ),
+            }
+        }
+
+        let succeeded = report.succeeded();
+        if self.policy.is_satisfied(succeeded, report.total()) {
+            Ok(())
+        } else {
+            let failures = report
+                .failed()
+                .map(|r| format!("{}: {:#}", r.name, r.result.as_ref().unwrap_err()))
+                .collect::<Vec<_>>()
+                .join(", ");
+            // Worth retrying unless every failed target rejected the message for good.
+            let retryable = report
+                .failed()
+                .any(|r| !BroadcastError::is_permanent(r.result.as_ref().unwrap_err()));
+            let retry_after = report
+                .failed()
+                .filter_map(|r| BroadcastError::retry_after_of(r.result.as_ref().unwrap_err()))
+                .max();
+            Err(BroadcastError {
+                retryable,
+                retry_after,
+                reason: None,
+                message: format!(
+                    "Broadcast policy {} not met, {} of {} targets succeeded. Failures: {}",
+                    self.policy,
+                    succeeded,
+                    report.total(),
+                    failures
+                ),
+            }
+            .into())
+        }
+    }
+}
+
+#[async_trait]
+impl MessageBroadcaster for MultiMessageBroadcaster {
+    async fn broadcast(&self, msg: &OracleMessage) -> Result<()> {
+        let report = self.broadcast_to_targets(msg).await;
+        self.check_policy(report)
+    }
+
+    async fn broadcast_registration(&self, registration: &SignedOracleRegistration) -> Result<()> {
+        let report = self
+            .run_on_targets(&HashSet::new(), |broadcaster| {
+                broadcaster.broadcast_registration(registration)
+            })
+            .await;
+        self.check_policy(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::{
+        message_generator::MessageGenerator, price_provider::Price,
+        signature_provider::private_key::PrivateKeySignatureProvider, slot::Slot,
+    };
+
+    struct StaticMessageBroadcaster {
+        succeed: bool,
+        delay: Duration,
+    }
+
+    #[async_trait]
+    impl MessageBroadcaster for StaticMessageBroadcaster {
+        async fn broadcast(&self, _msg: &OracleMessage) -> Result<()> {
+            tokio::time::sleep(self.delay).await;
+            if self.succeed {
+                Ok(())
+            } else {
+                Err(eyre::eyre!("rejected"))
+            }
+        }
+    }
+
+    /// Fails the first `failures` broadcasts, then succeeds, counting every attempt.
+    struct FlakyMessageBroadcaster {
+        failures: usize,
+        attempts: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl MessageBroadcaster for FlakyMessageBroadcaster {
+        async fn broadcast(&self, _msg: &OracleMessage) -> Result<()> {
+            if self.attempts.fetch_add(1, Ordering::SeqCst) < self.failures {
+                Err(eyre::eyre!("server unavailable"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn target(name: &str, succeed: bool) -> BroadcastTarget {
+        BroadcastTarget::new(
+            name,
+            Box::new(StaticMessageBroadcaster {
+                succeed,
+                delay: Duration::ZERO,
+            }),
+            DEFAULT_TARGET_TIMEOUT,
+        )
+    }
+
+    fn generate_message() -> OracleMessage {
+        MessageGenerator::new(Box::new(PrivateKeySignatureProvider::random()))
+            .generate_oracle_message(Price { value: 10 }, Slot(1))
+            .unwrap()
+    }
+
+    #[test]
+    fn parses_broadcast_policy() {
+        assert_eq!(
+            "all".parse::<BroadcastPolicy>().unwrap(),
+            BroadcastPolicy::All
+        );
+        assert_eq!(
+            "any".parse::<BroadcastPolicy>().unwrap(),
+            BroadcastPolicy::Any
+        );
+        assert_eq!(
+            "quorum:2".parse::<BroadcastPolicy>().unwrap(),
+            BroadcastPolicy::Quorum(2)
+        );
+        assert!("quorum:two".parse::<BroadcastPolicy>().is_err());
+        assert!("most".parse::<BroadcastPolicy>().is_err());
+    }
+
+    #[tokio::test]
+    async fn reports_every_target_result() {
+        let broadcaster = MultiMessageBroadcaster::new(
+            vec![target("a", true), target("b", false), target("c", true)],
+            BroadcastPolicy::All,
+        );
+
+        let report = broadcaster.broadcast_to_targets(&generate_message()).await;
+
+        assert_eq!(report.results.len(), 3);
+        assert_eq!(report.succeeded(), 2);
+        assert_eq!(
+            report.failed().map(|r| r.name.as_str()).collect::<Vec<_>>(),
+            vec!["b"]
+        );
+    }
+
+    #[tokio::test]
+    async fn applies_broadcast_policy() {
+        let message = generate_message();
+        let targets = || vec![target("a", true), target("b", false), target("c", true)];
+
+        let all = MultiMessageBroadcaster::new(targets(), BroadcastPolicy::All);
+        assert!(all.broadcast(&message).await.is_err());
+
+        let any = MultiMessageBroadcaster::new(targets(), BroadcastPolicy::Any);
+        assert!(any.broadcast(&message).await.is_ok());
+
+        let quorum_met = MultiMessageBroadcaster::new(targets(), BroadcastPolicy::Quorum(2));
+        assert!(quorum_met.broadcast(&message).await.is_ok());
+
+        let quorum_missed = MultiMessageBroadcaster::new(targets(), BroadcastPolicy::Quorum(3));
+        assert!(quorum_missed.broadcast(&message).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn times_out_slow_targets() {
+        let slow_target = BroadcastTarget::new(
+            "slow",
+            Box::new(StaticMessageBroadcaster {
+                succeed: true,
+                delay: Duration::from_secs(10),
+            }),
+            Duration::from_millis(50),
+        );
+        let broadcaster = MultiMessageBroadcaster::new(
+            vec![target("fast", true), slow_target],
+            BroadcastPolicy::Any,
+        );
+
+        let report = broadcaster.broadcast_to_targets(&generate_message()).await;
+
+        assert_eq!(report.succeeded(), 1);
+        let slow_result = report.failed().next().unwrap();
+        assert_eq!(slow_result.name, "slow");
+        assert!(slow_result.duration < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn rebroadcasts_only_to_failed_targets() {
+        let attempts = [Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0))];
+        let flaky_target = |name: &str, failures: usize, attempts: &Arc<AtomicUsize>| {
+            BroadcastTarget::new(
+                name,
+                Box::new(FlakyMessageBroadcaster {
+                    failures,
+                    attempts: attempts.clone(),
+                }),
+                DEFAULT_TARGET_TIMEOUT,
+            )
+        };
+        let broadcaster = MultiMessageBroadcaster::new(
+            vec![
+                flaky_target("up", 0, &attempts[0]),
+                flaky_target("down", 1, &attempts[1]),
+            ],
+            BroadcastPolicy::All,
+        );
+        let message = generate_message();
+
+        assert!(broadcaster.broadcast(&message).await.is_err());
+        assert!(broadcaster.broadcast(&message).await.is_ok());
+        assert_eq!(attempts[0].load(Ordering::SeqCst), 1);
+        assert_eq!(attempts[1].load(Ordering::SeqCst), 2);
+
+        // Once the policy is met, broadcasting again goes everywhere.
+        assert!(broadcaster.broadcast(&message).await.is_ok());
+        assert_eq!(attempts[0].load(Ordering::SeqCst), 2);
+    }
+}
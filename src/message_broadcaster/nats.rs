@@ -0,0 +1,72 @@
+//! # NATS Message Broadcaster
+//! Publishes every message as JSON to a NATS JetStream subject, e.g. `oracle.mainnet.ETH-USD`, for
+//! fan-out over an internal message bus. A broadcast only succeeds once the stream acknowledged
+//! storing the message, so with an outbox in front every message is delivered at least once. The
+//! message id header lets JetStream drop redeliveries it already stored within its duplicate
+//! window. A stream capturing the subject has to exist.
+
+use async_nats::{header::NATS_MESSAGE_ID, jetstream, HeaderMap};
+use async_trait::async_trait;
+use eyre::{Context, Result};
+use tokio::sync::OnceCell;
+
+use crate::message_broadcaster::{MessageBroadcaster, OracleMessage};
+
+pub struct NatsMessageBroadcaster {
+    server_url: String,
+    subject: String,
+    /// Connected on the first broadcast, the client reconnects by itself from then on.
+    jetstream: OnceCell<jetstream::Context>,
+}
+
+impl NatsMessageBroadcaster {
+    pub fn new(server_url: &str, subject: &str) -> NatsMessageBroadcaster {
+        NatsMessageBroadcaster {
+            server_url: server_url.to_string(),
+            subject: subject.to_string(),
+            jetstream: OnceCell::new(),
+        }
+    }
+
+    async fn jetstream(&self) -> Result<&jetstream::Context> {
+        self.jetstream
+            .get_or_try_init(|| async {
+                let client = async_nats::connect(self.server_url.as_str())
+                    .await
+                    .wrap_err_with(|| {
+                        format!("Failed to connect to NATS server at {}", self.server_url)
+                    })?;
+                log::info!("Connected to NATS server at {}", self.server_url);
+                Ok(jetstream::new(client))
+            })
+            .await
+    }
+}
+
+#[async_trait]
+impl MessageBroadcaster for NatsMessageBroadcaster {
+    async fn broadcast(&self, msg: &OracleMessage) -> Result<()> {
+        let mut headers = HeaderMap::new();
+        headers.insert(NATS_MESSAGE_ID, msg.id().as_str());
+        let ack = self
+            .jetstream()
+            .await?
+            .publish_with_headers(
+                self.subject.clone(),
+                headers,
+                serde_json::to_vec(msg)?.into(),
+            )
+            .await
+            .wrap_err_with(|| format!("Failed to publish to {}", self.subject))?
+            .await
+            .wrap_err_with(|| format!("No ack from JetStream for {}", self.subject))?;
+        log::debug!(
+            "Published message for slot {} to stream {} at sequence {}{}",
+            msg.value_message.message.slot_number,
+            ack.stream,
+            ack.sequence,
+            if ack.duplicate { ", a duplicate" } else { "" }
+        );
+        Ok(())
+    }
+}
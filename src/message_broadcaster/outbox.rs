@@ -0,0 +1,583 @@
+//! # Outbox
+//! Wraps another broadcaster and records every message in an append-only file before handing it
+//! on. Messages that fail to broadcast stay in the outbox and get redelivered with exponential
+//! backoff until they are acknowledged or older than the configured cut-off. Messages are told
+//! apart by `OracleMessage::id`, so several keys can share an outbox. This way a short
+//! server outage doesn't leave gaps in the history of signed prices. Messages the server rejected
+//! for good are dropped right away, and a server asking us to back off with `Retry-After` gets
+//! at least that long.
+
+use std::{
+    collections::BTreeMap,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, Utc};
+use eyre::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::message_broadcaster::{
+    response::BroadcastError, MessageBroadcaster, OracleMessage, SignedOracleRegistration,
+};
+
+pub const DEFAULT_OUTBOX_PATH: &str = "outbox.ndjson";
+const REDELIVERY_INTERVAL_SECS: u64 = 12;
+// Number of records no longer needed we tolerate in the file before rewriting it.
+const COMPACTION_SLACK: usize = 64;
+
+/// Where the outbox is kept and how long and how often messages are redelivered.
+#[derive(Clone, Debug)]
+pub struct OutboxConfig {
+    /// Created when missing, messages still pending in it are picked up again.
+    pub path: PathBuf,
+    /// Messages older than this are given up on.
+    pub max_age: Duration,
+    /// Wait before the first redelivery, doubled after every failure up to `max_backoff`.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for OutboxConfig {
+    fn default() -> Self {
+        OutboxConfig {
+            path: PathBuf::from(DEFAULT_OUTBOX_PATH),
+            max_age: Duration::hours(1),
+            initial_backoff: Duration::seconds(12),
+            max_backoff: Duration::minutes(5),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum OutboxRecord {
+    Message {
+        id: String,
+        created_at: DateTime<Utc>,
+        message: Box<OracleMessage>,
+    },
+    Ack {
+        id: String,
+    },
+    Expired {
+        id: String,
+    },
+    Rejected {
+        id: String,
+    },
+}
+
+struct PendingMessage {
+    message: OracleMessage,
+    created_at: DateTime<Utc>,
+    attempts: u32,
+    next_attempt_at: DateTime<Utc>,
+}
+
+struct OutboxState {
+    file: File,
+    /// By message id.
+    pending: BTreeMap<String, PendingMessage>,
+    records_in_file: usize,
+}
+
+struct Outbox {
+    config: OutboxConfig,
+    state: Mutex<OutboxState>,
+}
+
+impl Outbox {
+    fn open(config: OutboxConfig) -> Result<Outbox> {
+        let pending = Self::replay(&config)?;
+        log::info!(
+            "Opened outbox at {} with {} unacknowledged messages",
+            config.path.display(),
+            pending.len()
+        );
+        let file = Self::rewrite(&config.path, &pending)?;
+        Ok(Outbox {
+            state: Mutex::new(OutboxState {
+                file,
+                records_in_file: pending.len(),
+                pending,
+            }),
+            config,
+        })
+    }
+
+    /// Rebuilds the set of unacknowledged messages from the records on disk.
+    fn replay(config: &OutboxConfig) -> Result<BTreeMap<String, PendingMessage>> {
+        let mut pending = BTreeMap::new();
+        if !config.path.exists() {
+            return Ok(pending);
+        }
+        let file = File::open(&config.path)
+            .wrap_err_with(|| format!("Failed to open outbox {}", config.path.display()))?;
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            // A crash mid-write can leave a truncated last line, skip what we can't read.
+            let record = match serde_json::from_str::<OutboxRecord>(&line) {
+                Ok(record) => record,
+                Err(e) => {
+                    log::warn!("Skipping unreadable outbox record - {}", e);
+                    continue;
+                }
+            };
+            match record {
+                OutboxRecord::Message {
+                    id,
+                    created_at,
+                    message,
+                } => {
+                    pending.insert(
+                        id,
+                        PendingMessage {
+                            message: *message,
+                            created_at,
+                            attempts: 0,
+                            next_attempt_at: Utc::now(),
+                        },
+                    );
+                }
+                OutboxRecord::Ack { id }
+                | OutboxRecord::Expired { id }
+                | OutboxRecord::Rejected { id } => {
+                    pending.remove(&id);
+                }
+            }
+        }
+        Ok(pending)
+    }
+
+    /// Atomically replaces the outbox file with one only holding the given messages.
+    fn rewrite(path: &Path, pending: &BTreeMap<String, PendingMessage>) -> Result<File> {
+        let tmp_path = path.with_extension("tmp");
+        let mut tmp_file = File::create(&tmp_path)?;
+        for (id, pending_message) in pending {
+            write_record(
+                &mut tmp_file,
+                &OutboxRecord::Message {
+                    id: id.clone(),
+                    created_at: pending_message.created_at,
+                    message: Box::new(pending_message.message.clone()),
+                },
+            )?;
+        }
+        tmp_file.sync_all()?;
+        std::fs::rename(&tmp_path, path)
+            .wrap_err_with(|| format!("Failed to replace outbox {}", path.display()))?;
+        OpenOptions::new()
+            .append(true)
+            .open(path)
+            .wrap_err_with(|| format!("Failed to open outbox {}", path.display()))
+    }
+
+    fn append(state: &mut OutboxState, record: &OutboxRecord) -> Result<()> {
+        write_record(&mut state.file, record)?;
+        state.file.sync_data()?;
+        state.records_in_file += 1;
+        Ok(())
+    }
+
+    fn compact_if_needed(&self, state: &mut OutboxState) -> Result<()> {
+        if state.records_in_file > state.pending.len() * 2 + COMPACTION_SLACK {
+            log::debug!("Compacting outbox with {} records", state.records_in_file);
+            state.file = Self::rewrite(&self.config.path, &state.pending)?;
+            state.records_in_file = state.pending.len();
+        }
+        Ok(())
+    }
+
+    fn add(&self, message: &OracleMessage) -> Result<()> {
+        let id = message.id();
+        let created_at = Utc::now();
+        let mut state = self.state.lock().unwrap();
+        Self::append(
+            &mut state,
+            &OutboxRecord::Message {
+                id: id.clone(),
+                created_at,
+                message: Box::new(message.clone()),
+            },
+        )?;
+        state.pending.insert(
+            id,
+            PendingMessage {
+                message: message.clone(),
+                created_at,
+                attempts: 0,
+                // The first attempt is the direct broadcast, don't race it with a redelivery.
+                next_attempt_at: created_at + self.config.initial_backoff,
+            },
+        );
+        Ok(())
+    }
+
+    /// Removes the message with `id`, recording why.
+    fn remove(&self, id: &str, record: OutboxRecord) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.pending.remove(id).is_some() {
+            Self::append(&mut state, &record)?;
+            self.compact_if_needed(&mut state)?;
+        }
+        Ok(())
+    }
+
+    fn ack(&self, id: &str) -> Result<()> {
+        self.remove(id, OutboxRecord::Ack { id: id.to_string() })
+    }
+
+    fn expire(&self, id: &str) -> Result<()> {
+        self.remove(id, OutboxRecord::Expired { id: id.to_string() })
+    }
+
+    fn reject(&self, id: &str) -> Result<()> {
+        self.remove(id, OutboxRecord::Rejected { id: id.to_string() })
+    }
+
+    /// Backs off before the next attempt, at least as long as the server asked for.
+    fn schedule_retry(&self, id: &str, retry_after: Option<std::time::Duration>) {
+        let mut state = self.state.lock().unwrap();
+        if let Some(pending_message) = state.pending.get_mut(id) {
+            pending_message.attempts += 1;
+            let mut delay = backoff(&self.config, pending_message.attempts);
+            if let Some(retry_after) = retry_after.and_then(|d| Duration::from_std(d).ok()) {
+                delay = std::cmp::max(delay, retry_after);
+            }
+            pending_message.next_attempt_at = Utc::now() + delay;
+        }
+    }
+
+    /// Messages due for another attempt, and ids of messages that are past the cut-off.
+    fn due(&self, now: DateTime<Utc>) -> (Vec<OracleMessage>, Vec<String>) {
+        let state = self.state.lock().unwrap();
+        let mut due = Vec::new();
+        let mut expired = Vec::new();
+        for (id, pending_message) in state.pending.iter() {
+            if now - pending_message.created_at > self.config.max_age {
+                expired.push(id.clone());
+            } else if pending_message.next_attempt_at <= now {
+                due.push(pending_message.message.clone());
+            }
+        }
+        (due, expired)
+    }
+}
+
+fn write_record(file: &mut File, record: &OutboxRecord) -> Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    file.write_all(&line)?;
+    Ok(())
+}
+
+fn backoff(config: &OutboxConfig, attempts: u32) -> Duration {
+    let factor = 2i32.saturating_pow(attempts.saturating_sub(1).min(16));
+    std::cmp::min(config.initial_backoff * factor, config.max_backoff)
+}
+
+/// Records messages in the outbox before handing them to `inner`, see `run_redelivery`.
+pub struct OutboxMessageBroadcaster<B: MessageBroadcaster> {
+    inner: Arc<B>,
+    outbox: Arc<Outbox>,
+}
+
+impl<B: MessageBroadcaster> OutboxMessageBroadcaster<B> {
+    /// Opens the outbox at `config.path`, errors when it can't be read or created.
+    pub fn new(inner: B, config: OutboxConfig) -> Result<OutboxMessageBroadcaster<B>> {
+        Ok(OutboxMessageBroadcaster {
+            inner: Arc::new(inner),
+            outbox: Arc::new(Outbox::open(config)?),
+        })
+    }
+
+    /// Slots of the messages that have not been acknowledged yet, once per message.
+    pub fn pending_slots(&self) -> Vec<u64> {
+        let state = self.outbox.state.lock().unwrap();
+        let mut slots = state
+            .pending
+            .values()
+            .map(|pending_message| pending_message.message.value_message.message.slot_number)
+            .collect::<Vec<_>>();
+        slots.sort();
+        slots
+    }
+
+    /// Retries every message that is due, and drops the ones past the cut-off.
+    pub async fn redeliver_pending(&self) -> Result<()> {
+        let (due, expired) = self.outbox.due(Utc::now());
+        for id in expired {
+            log::warn!(
+                "Giving up on redelivering message {}, older than {}s",
+                id,
+                self.outbox.config.max_age.num_seconds()
+            );
+            self.outbox.expire(&id)?;
+        }
+        for message in due {
+            let id = message.id();
+            match self.inner.broadcast(&message).await {
+                Ok(()) => {
+                    log::info!("Redelivered message {}", id);
+                    self.outbox.ack(&id)?;
+                }
+                Err(e) if BroadcastError::is_permanent(&e) => {
+                    log::warn!("Message {} rejected for good, dropping it - {:?}", id, e);
+                    self.outbox.reject(&id)?;
+                }
+                Err(e) => {
+                    log::warn!("Failed to redeliver message {} - {:?}", id, e);
+                    self.outbox
+                        .schedule_retry(&id, BroadcastError::retry_after_of(&e));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Keeps redelivering unacknowledged messages, meant to be spawned as a background task.
+    pub async fn run_redelivery(&self) {
+        let mut interval =
+            tokio::time::interval(tokio::time::Duration::from_secs(REDELIVERY_INTERVAL_SECS));
+        loop {
+            interval.tick().await;
+            if let Err(e) = self.redeliver_pending().await {
+                log::error!("Error when redelivering outbox messages - {:?}", e);
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<B: MessageBroadcaster + Send + Sync> MessageBroadcaster for OutboxMessageBroadcaster<B> {
+    async fn broadcast(&self, msg: &OracleMessage) -> Result<()> {
+        self.outbox
+            .add(msg)
+            .wrap_err("Failed to record message in outbox")?;
+        let id = msg.id();
+        match self.inner.broadcast(msg).await {
+            Ok(()) => self.outbox.ack(&id),
+            Err(e) if BroadcastError::is_permanent(&e) => {
+                self.outbox.reject(&id)?;
+                Err(e.wrap_err("Message rejected for good, not redelivering it"))
+            }
+            Err(e) => {
+                // The direct broadcast counts as the first attempt.
+                self.outbox
+                    .schedule_retry(&id, BroadcastError::retry_after_of(&e));
+                Err(e.wrap_err("Failed to broadcast message, left in outbox for redelivery"))
+            }
+        }
+    }
+
+    /// Registrations are rare and operators retry them by hand, so they skip the outbox.
+    async fn broadcast_registration(&self, registration: &SignedOracleRegistration) -> Result<()> {
+        self.inner.broadcast_registration(registration).await
+    }
+}
+
+impl<B: MessageBroadcaster> Clone for OutboxMessageBroadcaster<B> {
+    fn clone(&self) -> Self {
+        OutboxMessageBroadcaster {
+            inner: self.inner.clone(),
+            outbox: self.outbox.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{
+        message_generator::MessageGenerator, price_provider::Price,
+        signature_provider::private_key::PrivateKeySignatureProvider, slot::Slot,
+    };
+
+    /// Fails the first `failures` broadcasts, then succeeds.
+    struct FlakyMessageBroadcaster {
+        failures: usize,
+        attempts: AtomicUsize,
+    }
+
+    impl FlakyMessageBroadcaster {
+        fn new(failures: usize) -> FlakyMessageBroadcaster {
+            FlakyMessageBroadcaster {
+                failures,
+                attempts: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl MessageBroadcaster for FlakyMessageBroadcaster {
+        async fn broadcast(&self, _msg: &OracleMessage) -> Result<()> {
+            if self.attempts.fetch_add(1, Ordering::SeqCst) < self.failures {
+                Err(eyre::eyre!("server unavailable"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    /// Fails every broadcast with the error `error` returns.
+    struct RejectingMessageBroadcaster {
+        error: fn() -> BroadcastError,
+        attempts: AtomicUsize,
+    }
+
+    impl RejectingMessageBroadcaster {
+        fn new(error: fn() -> BroadcastError) -> RejectingMessageBroadcaster {
+            RejectingMessageBroadcaster {
+                error,
+                attempts: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    #[async_trait]
+    impl MessageBroadcaster for RejectingMessageBroadcaster {
+        async fn broadcast(&self, _msg: &OracleMessage) -> Result<()> {
+            self.attempts.fetch_add(1, Ordering::SeqCst);
+            Err((self.error)().into())
+        }
+    }
+
+    fn generate_message(slot: u64) -> OracleMessage {
+        MessageGenerator::new(Box::new(PrivateKeySignatureProvider::random()))
+            .generate_oracle_message(Price { value: 10 }, Slot(slot))
+            .unwrap()
+    }
+
+    fn test_config(dir: &tempfile::TempDir) -> OutboxConfig {
+        OutboxConfig {
+            path: dir.path().join("outbox.ndjson"),
+            initial_backoff: Duration::zero(),
+            ..OutboxConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn acknowledges_successful_broadcast() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let broadcaster =
+            OutboxMessageBroadcaster::new(FlakyMessageBroadcaster::new(0), test_config(&dir))?;
+
+        broadcaster.broadcast(&generate_message(1)).await?;
+
+        assert!(broadcaster.pending_slots().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keeps_failed_broadcast_across_restarts() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let broadcaster =
+            OutboxMessageBroadcaster::new(FlakyMessageBroadcaster::new(1), test_config(&dir))?;
+
+        assert!(broadcaster.broadcast(&generate_message(1)).await.is_err());
+        broadcaster.broadcast(&generate_message(2)).await?;
+        drop(broadcaster);
+
+        let reopened =
+            OutboxMessageBroadcaster::new(FlakyMessageBroadcaster::new(0), test_config(&dir))?;
+        assert_eq!(reopened.pending_slots(), vec![1]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn keeps_messages_of_several_keys_for_a_slot() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let broadcaster =
+            OutboxMessageBroadcaster::new(FlakyMessageBroadcaster::new(1), test_config(&dir))?;
+
+        // Two keys signing the same slot.
+        assert!(broadcaster.broadcast(&generate_message(1)).await.is_err());
+        broadcaster.broadcast(&generate_message(1)).await?;
+        assert_eq!(broadcaster.pending_slots(), vec![1]);
+        drop(broadcaster);
+
+        let reopened =
+            OutboxMessageBroadcaster::new(FlakyMessageBroadcaster::new(0), test_config(&dir))?;
+        assert_eq!(reopened.pending_slots(), vec![1]);
+        reopened.redeliver_pending().await?;
+        assert!(reopened.pending_slots().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn redelivers_until_acknowledged() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let broadcaster =
+            OutboxMessageBroadcaster::new(FlakyMessageBroadcaster::new(2), test_config(&dir))?;
+
+        assert!(broadcaster.broadcast(&generate_message(1)).await.is_err());
+        broadcaster.redeliver_pending().await?;
+        assert_eq!(broadcaster.pending_slots(), vec![1]);
+        broadcaster.redeliver_pending().await?;
+        assert!(broadcaster.pending_slots().is_empty());
+        assert_eq!(broadcaster.inner.attempts.load(Ordering::SeqCst), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drops_messages_past_cut_off() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let config = OutboxConfig {
+            max_age: Duration::zero(),
+            ..test_config(&dir)
+        };
+        let broadcaster =
+            OutboxMessageBroadcaster::new(FlakyMessageBroadcaster::new(usize::MAX), config)?;
+
+        assert!(broadcaster.broadcast(&generate_message(1)).await.is_err());
+        tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+        broadcaster.redeliver_pending().await?;
+
+        assert!(broadcaster.pending_slots().is_empty());
+        assert_eq!(broadcaster.inner.attempts.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn drops_permanently_rejected_messages() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let broadcaster = OutboxMessageBroadcaster::new(
+            RejectingMessageBroadcaster::new(|| {
+                BroadcastError::permanent("bad signature".to_string())
+            }),
+            test_config(&dir),
+        )?;
+
+        assert!(broadcaster.broadcast(&generate_message(1)).await.is_err());
+        broadcaster.redeliver_pending().await?;
+
+        assert!(broadcaster.pending_slots().is_empty());
+        assert_eq!(broadcaster.inner.attempts.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn waits_as_long_as_server_asks() -> Result<()> {
+        let dir = tempfile::tempdir()?;
+        let broadcaster = OutboxMessageBroadcaster::new(
+            RejectingMessageBroadcaster::new(|| BroadcastError {
+                retry_after: Some(std::time::Duration::from_secs(60)),
+                ..BroadcastError::retryable("rate limited".to_string())
+            }),
+            test_config(&dir),
+        )?;
+
+        assert!(broadcaster.broadcast(&generate_message(1)).await.is_err());
+        broadcaster.redeliver_pending().await?;
+
+        assert_eq!(broadcaster.pending_slots(), vec![1]);
+        assert_eq!(broadcaster.inner.attempts.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+}
@@ -0,0 +1,73 @@
+//! # Redis Stream Message Broadcaster
+//! Appends every message to a Redis stream, e.g. `oracle.mainnet.ETH-USD`, for fan-out over an
+//! internal message bus. A broadcast only succeeds once Redis answered `XADD` with the entry id,
+//! so with an outbox in front every message is delivered at least once. Redeliveries can add a
+//! message twice, consumers should deduplicate by the `id` field.
+
+use async_trait::async_trait;
+use eyre::{Context, Result};
+use redis::aio::ConnectionManager;
+use tokio::sync::OnceCell;
+
+use crate::message_broadcaster::{MessageBroadcaster, OracleMessage};
+
+pub struct RedisStreamMessageBroadcaster {
+    server_url: String,
+    stream: String,
+    /// Connected on the first broadcast, the manager reconnects by itself from then on.
+    connection: OnceCell<ConnectionManager>,
+}
+
+impl RedisStreamMessageBroadcaster {
+    /// `server_url` is a `redis://` or `rediss://` url.
+    pub fn new(server_url: &str, stream: &str) -> RedisStreamMessageBroadcaster {
+        RedisStreamMessageBroadcaster {
+            server_url: server_url.to_string(),
+            stream: stream.to_string(),
+            connection: OnceCell::new(),
+        }
+    }
+
+    async fn connection(&self) -> Result<ConnectionManager> {
+        let connection = self
+            .connection
+            .get_or_try_init(|| async {
+                let client = redis::Client::open(self.server_url.as_str())
+                    .wrap_err_with(|| format!("Invalid Redis url {}", self.server_url))?;
+                let connection = ConnectionManager::new(client).await.wrap_err_with(|| {
+                    format!("Failed to connect to Redis at {}", self.server_url)
+                })?;
+                log::info!("Connected to Redis at {}", self.server_url);
+                Ok::<_, eyre::Report>(connection)
+            })
+            .await?;
+        // Clones share the connection.
+        Ok(connection.clone())
+    }
+}
+
+#[async_trait]
+impl MessageBroadcaster for RedisStreamMessageBroadcaster {
+    async fn broadcast(&self, msg: &OracleMessage) -> Result<()> {
+        let slot = msg.value_message.message.slot_number;
+        let entry_id: String = redis::cmd("XADD")
+            .arg(&self.stream)
+            .arg("*")
+            .arg("id")
+            .arg(msg.id())
+            .arg("slot")
+            .arg(slot)
+            .arg("message")
+            .arg(serde_json::to_string(msg)?)
+            .query_async(&mut self.connection().await?)
+            .await
+            .wrap_err_with(|| format!("Failed to add message to stream {}", self.stream))?;
+        log::debug!(
+            "Added message for slot {} to stream {} as {}",
+            slot,
+            self.stream,
+            entry_id
+        );
+        Ok(())
+    }
+}
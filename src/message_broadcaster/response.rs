@@ -0,0 +1,209 @@
+//! # Server Responses
+//! What an oracle server answers to a submission, and how we classify failed broadcasts. A
+//! `BroadcastError` in an error's chain tells whether sending the same message again can help,
+//! errors without one, like connection failures, are assumed to be worth retrying.
+
+use std::{fmt::Display, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubmissionStatus {
+    Accepted,
+    Rejected,
+}
+
+/// Why a server rejected a submission.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RejectionReason {
+    BadSignature,
+    /// The slot is over as far as the server is concerned.
+    LateSlot,
+    /// The key isn't an active validator or isn't registered with the server.
+    UnknownValidator,
+    /// A different message for the same slot and key was accepted before.
+    Conflict,
+    InvalidMessage,
+    Unauthorized,
+    RateLimited,
+    /// A reason this client doesn't know yet.
+    #[serde(other)]
+    Other,
+}
+
+impl RejectionReason {
+    /// Whether sending the same message again can succeed, `None` when only the status code can
+    /// tell.
+    pub fn is_retryable(&self) -> Option<bool> {
+        match self {
+            RejectionReason::RateLimited => Some(true),
+            RejectionReason::Other => None,
+            _ => Some(false),
+        }
+    }
+}
+
+impl Display for RejectionReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let reason = match self {
+            RejectionReason::BadSignature => "bad signature",
+            RejectionReason::LateSlot => "late slot",
+            RejectionReason::UnknownValidator => "unknown validator",
+            RejectionReason::Conflict => "conflicting message",
+            RejectionReason::InvalidMessage => "invalid message",
+            RejectionReason::Unauthorized => "unauthorized",
+            RejectionReason::RateLimited => "rate limited",
+            RejectionReason::Other => "other",
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+/// Body of an error answer to a query, e.g. for a slot without messages.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ErrorResponse {
+    pub error: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub violations: Vec<String>,
+}
+
+/// Body of a server's answer to a message or registration.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SubmissionResponse {
+    pub status: SubmissionStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reason: Option<RejectionReason>,
+    /// Details for humans.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// Every problem found when verifying the message.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub violations: Vec<String>,
+    /// The slot the server thinks it is, to spot clock drift.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub current_slot: Option<u64>,
+}
+
+/// A failed broadcast, classified.
+#[derive(Debug)]
+pub struct BroadcastError {
+    pub retryable: bool,
+    /// How long the server asked us to wait before trying again.
+    pub retry_after: Option<Duration>,
+    pub reason: Option<RejectionReason>,
+    pub message: String,
+}
+
+impl BroadcastError {
+    pub fn retryable(message: String) -> BroadcastError {
+        BroadcastError {
+            retryable: true,
+            retry_after: None,
+            reason: None,
+            message,
+        }
+    }
+
+    pub fn permanent(message: String) -> BroadcastError {
+        BroadcastError {
+            retryable: false,
+            retry_after: None,
+            reason: None,
+            message,
+        }
+    }
+
+    /// The classification anywhere in `report`'s chain, if there is one.
+    pub fn find(report: &eyre::Report) -> Option<&BroadcastError> {
+        report
+            .chain()
+            .find_map(|error| error.downcast_ref::<BroadcastError>())
+    }
+
+    /// Whether retrying `report`'s broadcast is pointless, unclassified errors are retryable.
+    pub fn is_permanent(report: &eyre::Report) -> bool {
+        Self::find(report).is_some_and(|error| !error.retryable)
+    }
+
+    pub fn retry_after_of(report: &eyre::Report) -> Option<Duration> {
+        Self::find(report).and_then(|error| error.retry_after)
+    }
+}
+
+impl Display for BroadcastError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.reason {
+            Some(reason) => write!(f, "{} ({})", self.message, reason)?,
+            None => write!(f, "{}", self.message)?,
+        }
+        if let Some(retry_after) = self.retry_after {
+            write!(f, ", retry after {}s", retry_after.as_secs())?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for BroadcastError {}
+
+/// Reads a `Retry-After` header, either a number of seconds or an HTTP date.
+pub fn parse_retry_after(value: &str, now: DateTime<Utc>) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+    let retry_at = DateTime::parse_from_rfc2822(value).ok()?;
+    // A date in the past means right away.
+    Some(
+        (retry_at.with_timezone(&Utc) - now)
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use eyre::WrapErr;
+
+    use super::*;
+
+    #[test]
+    fn parses_retry_after() {
+        let now = DateTime::parse_from_rfc3339("2015-10-21T07:27:00Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(
+            parse_retry_after("120", now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT", now),
+            Some(Duration::from_secs(60))
+        );
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:00:00 GMT", now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon", now), None);
+    }
+
+    #[test]
+    fn finds_classification_through_context() {
+        let report = Err::<(), _>(BroadcastError::permanent("rejected".to_string()))
+            .wrap_err("Failed to broadcast")
+            .unwrap_err();
+        assert!(BroadcastError::is_permanent(&report));
+        assert!(!BroadcastError::is_permanent(&eyre::eyre!(
+            "connection refused"
+        )));
+    }
+
+    #[test]
+    fn reads_unknown_reasons() {
+        let response: SubmissionResponse =
+            serde_json::from_str(r#"{"status": "rejected", "reason": "solar_flare"}"#).unwrap();
+        assert_eq!(response.reason, Some(RejectionReason::Other));
+    }
+}
@@ -0,0 +1,458 @@
+//! # SQLite Message History
+//! Wraps another broadcaster and keeps every message it hands on in a SQLite database, with the
+//! slot, key, price, interval and how broadcasting it went, so audits can answer what price a
+//! validator signed in a slot. Each slot and key has one row holding the latest attempt, with an
+//! outbox in front redeliveries update it and count the attempts.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use async_trait::async_trait;
+use bls::PublicKey;
+use chrono::{DateTime, Utc};
+use eyre::{Context, Result};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::Serialize;
+
+use crate::message_broadcaster::{MessageBroadcaster, OracleMessage, SignedOracleRegistration};
+
+pub const DEFAULT_HISTORY_PATH: &str = "history.sqlite";
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS messages (
+    slot INTEGER NOT NULL,
+    validator_public_key TEXT NOT NULL,
+    message_version INTEGER NOT NULL,
+    price INTEGER NOT NULL,
+    interval_lower_bound INTEGER,
+    interval_upper_bound INTEGER,
+    interval_size INTEGER,
+    succeeded INTEGER NOT NULL,
+    error TEXT,
+    attempts INTEGER NOT NULL,
+    duration_ms INTEGER NOT NULL,
+    broadcast_at TEXT NOT NULL,
+    message TEXT NOT NULL,
+    PRIMARY KEY (slot, validator_public_key)
+)";
+
+/// One stored message, prices and interval bounds in their fixed precision.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct HistoryRecord {
+    pub slot: u64,
+    pub validator_public_key: String,
+    pub message_version: u8,
+    pub price: u64,
+    /// Inclusive.
+    pub interval_lower_bound: Option<u64>,
+    /// Exclusive.
+    pub interval_upper_bound: Option<u64>,
+    pub interval_size: Option<u64>,
+    pub succeeded: bool,
+    pub error: Option<String>,
+    pub attempts: u32,
+    /// How long the latest attempt took.
+    pub duration_ms: u64,
+    /// When the latest attempt finished.
+    pub broadcast_at: DateTime<Utc>,
+}
+
+/// Which records to return, every field left out matches everything.
+#[derive(Clone, Debug, Default)]
+pub struct HistoryFilter {
+    /// Inclusive.
+    pub from_slot: Option<u64>,
+    /// Inclusive.
+    pub to_slot: Option<u64>,
+    pub validator_public_key: Option<PublicKey>,
+    pub limit: Option<usize>,
+}
+
+pub struct MessageStore {
+    path: PathBuf,
+    connection: Mutex<Connection>,
+}
+
+impl MessageStore {
+    /// Opens the database at `path`, creating it if needed.
+    pub fn open(path: &Path) -> Result<MessageStore> {
+        let connection = Connection::open(path)
+            .wrap_err_with(|| format!("Failed to open message history {}", path.display()))?;
+        connection
+            .execute(SCHEMA, [])
+            .wrap_err("Failed to create message history table")?;
+        Ok(MessageStore {
+            path: path.to_path_buf(),
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Stores the outcome of broadcasting `msg`, replacing an earlier attempt for its slot and key.
+    pub fn record(
+        &self,
+        msg: &OracleMessage,
+        outcome: &Result<()>,
+        duration: std::time::Duration,
+        broadcast_at: DateTime<Utc>,
+    ) -> Result<()> {
+        let interval = msg.interval_bounds();
+        let error = outcome.as_ref().err().map(|e| format!("{:#}", e));
+        self.connection
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT INTO messages (slot, validator_public_key, message_version, price,
+                    interval_lower_bound, interval_upper_bound, interval_size, succeeded, error,
+                    attempts, duration_ms, broadcast_at, message)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, 1, ?10, ?11, ?12)
+                ON CONFLICT (slot, validator_public_key) DO UPDATE SET
+                    succeeded = excluded.succeeded,
+                    error = excluded.error,
+                    attempts = attempts + 1,
+                    duration_ms = excluded.duration_ms,
+                    broadcast_at = excluded.broadcast_at",
+                params![
+                    to_sql_integer(msg.value_message.message.slot_number)?,
+                    msg.validator_public_key.to_string(),
+                    u8::from(msg.version),
+                    to_sql_integer(msg.value_message.message.price.value)?,
+                    interval
+                        .as_ref()
+                        .map(|interval| to_sql_integer(interval.lower_bound))
+                        .transpose()?,
+                    interval
+                        .as_ref()
+                        .map(|interval| to_sql_integer(interval.upper_bound))
+                        .transpose()?,
+                    interval
+                        .as_ref()
+                        .map(|interval| to_sql_integer(interval.interval_size))
+                        .transpose()?,
+                    outcome.is_ok(),
+                    error,
+                    to_sql_integer(duration.as_millis() as u64)?,
+                    broadcast_at.to_rfc3339(),
+                    serde_json::to_string(msg)?,
+                ],
+            )
+            .wrap_err_with(|| {
+                format!(
+                    "Failed to record message for slot {} in {}",
+                    msg.value_message.message.slot_number,
+                    self.path.display()
+                )
+            })?;
+        Ok(())
+    }
+
+    /// Records matching `filter`, ordered by slot.
+    pub fn query(&self, filter: &HistoryFilter) -> Result<Vec<HistoryRecord>> {
+        let connection = self.connection.lock().unwrap();
+        let mut statement = connection.prepare(
+            "SELECT slot, validator_public_key, message_version, price, interval_lower_bound,
+                interval_upper_bound, interval_size, succeeded, error, attempts, duration_ms,
+                broadcast_at
+            FROM messages
+            WHERE (?1 IS NULL OR slot >= ?1)
+                AND (?2 IS NULL OR slot <= ?2)
+                AND (?3 IS NULL OR validator_public_key = ?3)
+            ORDER BY slot, validator_public_key
+            LIMIT ?4",
+        )?;
+        let rows = statement.query_map(
+            params![
+                filter.from_slot.map(to_sql_integer).transpose()?,
+                filter.to_slot.map(to_sql_integer).transpose()?,
+                filter
+                    .validator_public_key
+                    .as_ref()
+                    .map(|public_key| public_key.to_string()),
+                // A negative limit is no limit to SQLite.
+                filter.limit.map_or(-1, |limit| limit as i64),
+            ],
+            |row| {
+                Ok(RawRecord {
+                    slot: row.get(0)?,
+                    validator_public_key: row.get(1)?,
+                    message_version: row.get(2)?,
+                    price: row.get(3)?,
+                    interval_lower_bound: row.get(4)?,
+                    interval_upper_bound: row.get(5)?,
+                    interval_size: row.get(6)?,
+                    succeeded: row.get(7)?,
+                    error: row.get(8)?,
+                    attempts: row.get(9)?,
+                    duration_ms: row.get(10)?,
+                    broadcast_at: row.get(11)?,
+                })
+            },
+        )?;
+        rows.map(|row| row?.into_record())
+            .collect::<Result<Vec<_>>>()
+            .wrap_err_with(|| format!("Failed to read message history {}", self.path.display()))
+    }
+
+    /// The full message stored for `slot` and `validator_public_key`, if there is one.
+    pub fn message(
+        &self,
+        slot: u64,
+        validator_public_key: &PublicKey,
+    ) -> Result<Option<OracleMessage>> {
+        let message: Option<String> = self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT message FROM messages WHERE slot = ?1 AND validator_public_key = ?2",
+                params![to_sql_integer(slot)?, validator_public_key.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        message
+            .map(|message| serde_json::from_str(&message))
+            .transpose()
+            .wrap_err("Failed to parse stored message")
+    }
+}
+
+/// A row as SQLite types it.
+struct RawRecord {
+    slot: i64,
+    validator_public_key: String,
+    message_version: u8,
+    price: i64,
+    interval_lower_bound: Option<i64>,
+    interval_upper_bound: Option<i64>,
+    interval_size: Option<i64>,
+    succeeded: bool,
+    error: Option<String>,
+    attempts: u32,
+    duration_ms: i64,
+    broadcast_at: String,
+}
+
+impl RawRecord {
+    fn into_record(self) -> Result<HistoryRecord> {
+        Ok(HistoryRecord {
+            slot: u64::try_from(self.slot)?,
+            validator_public_key: self.validator_public_key,
+            message_version: self.message_version,
+            price: u64::try_from(self.price)?,
+            interval_lower_bound: self.interval_lower_bound.map(u64::try_from).transpose()?,
+            interval_upper_bound: self.interval_upper_bound.map(u64::try_from).transpose()?,
+            interval_size: self.interval_size.map(u64::try_from).transpose()?,
+            succeeded: self.succeeded,
+            error: self.error,
+            attempts: self.attempts,
+            duration_ms: u64::try_from(self.duration_ms)?,
+            broadcast_at: DateTime::parse_from_rfc3339(&self.broadcast_at)?.with_timezone(&Utc),
+        })
+    }
+}
+
+/// SQLite integers are signed, values past `i64::MAX` don't occur in practice.
+fn to_sql_integer(value: u64) -> Result<i64> {
+    i64::try_from(value).wrap_err_with(|| format!("{} is too large to store", value))
+}
+
+pub struct SqliteMessageBroadcaster<B: MessageBroadcaster> {
+    inner: Arc<B>,
+    store: Arc<MessageStore>,
+}
+
+impl<B: MessageBroadcaster> SqliteMessageBroadcaster<B> {
+    pub fn new(inner: B, path: &Path) -> Result<SqliteMessageBroadcaster<B>> {
+        Ok(SqliteMessageBroadcaster {
+            inner: Arc::new(inner),
+            store: Arc::new(MessageStore::open(path)?),
+        })
+    }
+
+    pub fn store(&self) -> Arc<MessageStore> {
+        self.store.clone()
+    }
+}
+
+#[async_trait]
+impl<B: MessageBroadcaster + Send + Sync> MessageBroadcaster for SqliteMessageBroadcaster<B> {
+    async fn broadcast(&self, msg: &OracleMessage) -> Result<()> {
+        let started_at = Instant::now();
+        let outcome = self.inner.broadcast(msg).await;
+        // Losing the history of a message must not fail its broadcast.
+        if let Err(e) = self
+            .store
+            .record(msg, &outcome, started_at.elapsed(), Utc::now())
+        {
+            log::error!("Failed to record message history - {:?}", e);
+        }
+        outcome
+    }
+
+    async fn broadcast_registration(&self, registration: &SignedOracleRegistration) -> Result<()> {
+        self.inner.broadcast_registration(registration).await
+    }
+}
+
+impl<B: MessageBroadcaster> Clone for SqliteMessageBroadcaster<B> {
+    fn clone(&self) -> Self {
+        SqliteMessageBroadcaster {
+            inner: self.inner.clone(),
+            store: self.store.clone(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use crate::{
+        message_broadcaster::MessageVersion,
+        message_generator::{
+            interval_bounds, MessageGenerator, MessageGeneratorConfig, INTERVAL_SIZE_BASIS_POINTS,
+        },
+        price_provider::Price,
+        signature_provider::private_key::PrivateKeySignatureProvider,
+        slot::Slot,
+    };
+
+    const PRICE: u64 = 1_000_000_000;
+
+    /// Fails the first `failures` broadcasts, then succeeds.
+    struct FlakyMessageBroadcaster {
+        failures: usize,
+        attempts: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl MessageBroadcaster for FlakyMessageBroadcaster {
+        async fn broadcast(&self, _msg: &OracleMessage) -> Result<()> {
+            if self.attempts.fetch_add(1, Ordering::SeqCst) < self.failures {
+                Err(eyre::eyre!("server unavailable"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    fn message_generator(message_version: MessageVersion) -> MessageGenerator {
+        MessageGenerator::new_with_config(
+            Box::new(PrivateKeySignatureProvider::random()),
+            MessageGeneratorConfig {
+                message_version,
+                ..MessageGeneratorConfig::default()
+            },
+        )
+    }
+
+    #[tokio::test]
+    async fn records_outcome_of_every_attempt() {
+        let dir = tempfile::tempdir().unwrap();
+        let broadcaster = SqliteMessageBroadcaster::new(
+            FlakyMessageBroadcaster {
+                failures: 1,
+                attempts: AtomicUsize::new(0),
+            },
+            &dir.path().join("history.sqlite"),
+        )
+        .unwrap();
+        let message_generator = message_generator(MessageVersion::V1);
+        let msg = message_generator
+            .generate_oracle_message(Price { value: PRICE }, Slot(3))
+            .unwrap();
+
+        assert!(broadcaster.broadcast(&msg).await.is_err());
+        let records = broadcaster
+            .store()
+            .query(&HistoryFilter::default())
+            .unwrap();
+        assert_eq!(records.len(), 1);
+        assert!(!records[0].succeeded);
+        assert_eq!(records[0].error.as_deref(), Some("server unavailable"));
+
+        assert!(broadcaster.broadcast(&msg).await.is_ok());
+        let records = broadcaster
+            .store()
+            .query(&HistoryFilter::default())
+            .unwrap();
+        assert_eq!(records.len(), 1);
+        let record = &records[0];
+        assert!(record.succeeded);
+        assert_eq!(record.error, None);
+        assert_eq!(record.attempts, 2);
+        assert_eq!(record.slot, 3);
+        assert_eq!(record.price, PRICE);
+        assert_eq!(
+            record.validator_public_key,
+            msg.validator_public_key.to_string()
+        );
+        let (lower_bound, upper_bound) = interval_bounds(PRICE, INTERVAL_SIZE_BASIS_POINTS);
+        assert_eq!(record.interval_lower_bound, Some(lower_bound));
+        assert_eq!(record.interval_upper_bound, Some(upper_bound));
+    }
+
+    #[tokio::test]
+    async fn filters_by_slot_and_key() {
+        let dir = tempfile::tempdir().unwrap();
+        let broadcaster = SqliteMessageBroadcaster::new(
+            FlakyMessageBroadcaster {
+                failures: 0,
+                attempts: AtomicUsize::new(0),
+            },
+            &dir.path().join("history.sqlite"),
+        )
+        .unwrap();
+        let first_generator = message_generator(MessageVersion::V2);
+        let second_generator = message_generator(MessageVersion::V2);
+        for slot in 1..=5 {
+            for message_generator in [&first_generator, &second_generator] {
+                let msg = message_generator
+                    .generate_oracle_message(Price { value: 1000 + slot }, Slot(slot))
+                    .unwrap();
+                broadcaster.broadcast(&msg).await.unwrap();
+            }
+        }
+        let first_public_key = first_generator.get_public_key().unwrap();
+
+        let records = broadcaster
+            .store()
+            .query(&HistoryFilter {
+                from_slot: Some(2),
+                to_slot: Some(4),
+                validator_public_key: Some(first_public_key.clone()),
+                limit: None,
+            })
+            .unwrap();
+        assert_eq!(
+            records.iter().map(|record| record.slot).collect::<Vec<_>>(),
+            vec![2, 3, 4]
+        );
+        assert!(records.iter().all(|record| record.message_version == 2
+            && record.interval_size.is_some()
+            && record.validator_public_key == first_public_key.to_string()));
+
+        let records = broadcaster
+            .store()
+            .query(&HistoryFilter {
+                limit: Some(3),
+                ..HistoryFilter::default()
+            })
+            .unwrap();
+        assert_eq!(records.len(), 3);
+
+        let stored = broadcaster
+            .store()
+            .message(5, &first_public_key)
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.value_message.message.price.value, 1005);
+        assert!(broadcaster
+            .store()
+            .message(6, &first_public_key)
+            .unwrap()
+            .is_none());
+    }
+}
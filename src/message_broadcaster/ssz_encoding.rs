@@ -0,0 +1,319 @@
+//! # SSZ Encoding
+//! SSZ `Encode` / `Decode` for the signed message wrappers and `OracleMessage`.
+//! These can't be derived: the `bls` key and signature types implement the traits of the older
+//! `eth2_ssz` crate rather than `ethereum_ssz`, so we encode them through their compressed bytes.
+
+use bls::{PublicKey, Signature, PUBLIC_KEY_BYTES_LEN, SIGNATURE_BYTES_LEN};
+use ssz::{Decode, DecodeError, Encode, SszDecoderBuilder, SszEncoder, BYTES_PER_LENGTH_OFFSET};
+
+use crate::message_broadcaster::{
+    IntervalBoundsMessage, IntervalInclusionMessage, MessageVersion, OracleMessage,
+    OracleRegistration, PriceValueMessage, SignedIntervalBoundsMessage,
+    SignedIntervalInclusionMessage, SignedPriceValueMessage, ValidatorInfo, ValidatorStatus,
+};
+
+fn decode_signature(bytes: &[u8]) -> Result<Signature, DecodeError> {
+    Signature::deserialize(bytes)
+        .map_err(|e| DecodeError::BytesInvalid(format!("invalid signature: {:?}", e)))
+}
+
+fn decode_message_version(bytes: &[u8]) -> Result<MessageVersion, DecodeError> {
+    MessageVersion::try_from(u8::from_ssz_bytes(bytes)?).map_err(DecodeError::BytesInvalid)
+}
+
+fn decode_public_key(bytes: &[u8]) -> Result<PublicKey, DecodeError> {
+    PublicKey::deserialize(bytes)
+        .map_err(|e| DecodeError::BytesInvalid(format!("invalid public key: {:?}", e)))
+}
+
+/// A signed wrapper is a fixed length container of the message followed by its signature.
+macro_rules! impl_ssz_for_signed_message {
+    ($signed_message: ident, $message: ident) => {
+        impl Encode for $signed_message {
+            fn is_ssz_fixed_len() -> bool {
+                true
+            }
+
+            fn ssz_fixed_len() -> usize {
+                <$message as Encode>::ssz_fixed_len() + SIGNATURE_BYTES_LEN
+            }
+
+            fn ssz_bytes_len(&self) -> usize {
+                <Self as Encode>::ssz_fixed_len()
+            }
+
+            fn ssz_append(&self, buf: &mut Vec<u8>) {
+                self.message.ssz_append(buf);
+                buf.extend_from_slice(&self.signature.serialize());
+            }
+        }
+
+        impl Decode for $signed_message {
+            fn is_ssz_fixed_len() -> bool {
+                true
+            }
+
+            fn ssz_fixed_len() -> usize {
+                <$message as Decode>::ssz_fixed_len() + SIGNATURE_BYTES_LEN
+            }
+
+            fn from_ssz_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+                let mut builder = SszDecoderBuilder::new(bytes);
+                builder.register_type::<$message>()?;
+                builder.register_type_parameterized(true, SIGNATURE_BYTES_LEN)?;
+                let mut decoder = builder.build()?;
+                Ok($signed_message {
+                    message: decoder.decode_next()?,
+                    signature: decoder.decode_next_with(decode_signature)?,
+                })
+            }
+        }
+    };
+}
+
+impl_ssz_for_signed_message!(SignedPriceValueMessage, PriceValueMessage);
+impl_ssz_for_signed_message!(SignedIntervalInclusionMessage, IntervalInclusionMessage);
+impl_ssz_for_signed_message!(SignedIntervalBoundsMessage, IntervalBoundsMessage);
+
+/// The index followed by the status as a single byte.
+impl Encode for ValidatorInfo {
+    fn is_ssz_fixed_len() -> bool {
+        true
+    }
+
+    fn ssz_fixed_len() -> usize {
+        <u64 as Encode>::ssz_fixed_len() + <u8 as Encode>::ssz_fixed_len()
+    }
+
+    fn ssz_bytes_len(&self) -> usize {
+        <Self as Encode>::ssz_fixed_len()
+    }
+
+    fn ssz_append(&self, buf: &mut Vec<u8>) {
+        self.index.ssz_append(buf);
+        (self.status as u8).ssz_append(buf);
+    }
+}
+
+impl Decode for ValidatorInfo {
+    fn is_ssz_fixed_len() -> bool {
+        true
+    }
+
+    fn ssz_fixed_len() -> usize {
+        <u64 as Decode>::ssz_fixed_len() + <u8 as Decode>::ssz_fixed_len()
+    }
+
+    fn from_ssz_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut builder = SszDecoderBuilder::new(bytes);
+        builder.register_type::<u64>()?;
+        builder.register_type::<u8>()?;
+        let mut decoder = builder.build()?;
+        Ok(ValidatorInfo {
+            index: decoder.decode_next()?,
+            status: decoder.decode_next_with(|bytes| {
+                ValidatorStatus::try_from(u8::from_ssz_bytes(bytes)?)
+                    .map_err(DecodeError::BytesInvalid)
+            })?,
+        })
+    }
+}
+
+impl OracleMessage {
+    fn ssz_fixed_part_len() -> usize {
+        <u8 as Encode>::ssz_fixed_len()
+            + <SignedPriceValueMessage as Encode>::ssz_fixed_len()
+            + BYTES_PER_LENGTH_OFFSET
+            + BYTES_PER_LENGTH_OFFSET
+            + PUBLIC_KEY_BYTES_LEN
+            + BYTES_PER_LENGTH_OFFSET
+    }
+}
+
+impl Encode for OracleMessage {
+    fn is_ssz_fixed_len() -> bool {
+        false
+    }
+
+    fn ssz_bytes_len(&self) -> usize {
+        Self::ssz_fixed_part_len()
+            + self.interval_inclusion_messages.ssz_bytes_len()
+            + self.interval_bounds_message.ssz_bytes_len()
+            + self.validator_info.ssz_bytes_len()
+    }
+
+    fn ssz_append(&self, buf: &mut Vec<u8>) {
+        let mut encoder = SszEncoder::container(buf, Self::ssz_fixed_part_len());
+        encoder.append(&u8::from(self.version));
+        encoder.append(&self.value_message);
+        encoder.append(&self.interval_inclusion_messages);
+        encoder.append(&self.interval_bounds_message);
+        encoder.append_parameterized(true, |buf| {
+            buf.extend_from_slice(&self.validator_public_key.serialize())
+        });
+        encoder.append(&self.validator_info);
+        encoder.finalize();
+    }
+}
+
+impl Decode for OracleMessage {
+    fn is_ssz_fixed_len() -> bool {
+        false
+    }
+
+    fn from_ssz_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut builder = SszDecoderBuilder::new(bytes);
+        builder.register_type::<u8>()?;
+        builder.register_type::<SignedPriceValueMessage>()?;
+        builder.register_type::<Vec<SignedIntervalInclusionMessage>>()?;
+        builder.register_type::<Option<SignedIntervalBoundsMessage>>()?;
+        builder.register_type_parameterized(true, PUBLIC_KEY_BYTES_LEN)?;
+        builder.register_type::<Option<ValidatorInfo>>()?;
+        let mut decoder = builder.build()?;
+        Ok(OracleMessage {
+            version: decoder.decode_next_with(decode_message_version)?,
+            value_message: decoder.decode_next()?,
+            interval_inclusion_messages: decoder.decode_next()?,
+            interval_bounds_message: decoder.decode_next()?,
+            validator_public_key: decoder.decode_next_with(decode_public_key)?,
+            validator_info: decoder.decode_next()?,
+        })
+    }
+}
+
+impl OracleRegistration {
+    fn ssz_fixed_part_len() -> usize {
+        PUBLIC_KEY_BYTES_LEN
+            + SIGNATURE_BYTES_LEN
+            + 20
+            + BYTES_PER_LENGTH_OFFSET
+            + BYTES_PER_LENGTH_OFFSET
+            + <u64 as Encode>::ssz_fixed_len()
+    }
+}
+
+/// Only encoded to be signed, registrations are sent as JSON. Strings are encoded as their UTF-8
+/// bytes.
+impl Encode for OracleRegistration {
+    fn is_ssz_fixed_len() -> bool {
+        false
+    }
+
+    fn ssz_bytes_len(&self) -> usize {
+        Self::ssz_fixed_part_len() + self.operator.len() + self.client_version.len()
+    }
+
+    fn ssz_append(&self, buf: &mut Vec<u8>) {
+        let mut encoder = SszEncoder::container(buf, Self::ssz_fixed_part_len());
+        encoder.append_parameterized(true, |buf| {
+            buf.extend_from_slice(&self.validator_public_key.serialize())
+        });
+        encoder.append_parameterized(true, |buf| {
+            buf.extend_from_slice(&self.proof_of_possession.serialize())
+        });
+        encoder.append_parameterized(true, |buf| {
+            buf.extend_from_slice(self.fee_recipient.as_bytes())
+        });
+        encoder.append(&self.operator.as_bytes().to_vec());
+        encoder.append(&self.client_version.as_bytes().to_vec());
+        encoder.append(&self.timestamp);
+        encoder.finalize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        message_generator::{MessageGenerator, MessageGeneratorConfig},
+        price_provider::{Price, PRECISION_FACTOR},
+        signature_provider::private_key::PrivateKeySignatureProvider,
+        slot::Slot,
+    };
+
+    fn generate_message() -> OracleMessage {
+        generate_message_with_version(MessageVersion::V1)
+    }
+
+    fn generate_message_with_version(version: MessageVersion) -> OracleMessage {
+        MessageGenerator::new_with_config(
+            Box::new(PrivateKeySignatureProvider::random()),
+            MessageGeneratorConfig {
+                message_version: version,
+                ..MessageGeneratorConfig::default()
+            },
+        )
+        .generate_oracle_message(
+            Price {
+                value: 1000 * PRECISION_FACTOR,
+            },
+            Slot(1),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn round_trips_between_json_and_ssz() {
+        let message = generate_message();
+        let json = serde_json::to_value(&message).unwrap();
+
+        let from_json: OracleMessage = serde_json::from_value(json.clone()).unwrap();
+        let ssz_bytes = from_json.as_ssz_bytes();
+        let from_ssz = OracleMessage::from_ssz_bytes(&ssz_bytes).unwrap();
+
+        assert_eq!(serde_json::to_value(&from_ssz).unwrap(), json);
+        assert_eq!(from_ssz.as_ssz_bytes(), ssz_bytes);
+    }
+
+    #[test]
+    fn round_trips_interval_bounds_message() {
+        let message = generate_message_with_version(MessageVersion::V2);
+        let json = serde_json::to_value(&message).unwrap();
+
+        let from_ssz = OracleMessage::from_ssz_bytes(&message.as_ssz_bytes()).unwrap();
+
+        assert_eq!(from_ssz.version, MessageVersion::V2);
+        assert!(from_ssz.interval_bounds_message.is_some());
+        assert_eq!(serde_json::to_value(&from_ssz).unwrap(), json);
+    }
+
+    #[test]
+    fn round_trips_validator_info() {
+        let mut message = generate_message_with_version(MessageVersion::V2);
+        message.validator_info = Some(ValidatorInfo {
+            index: 42,
+            status: ValidatorStatus::ActiveExiting,
+        });
+
+        let from_ssz = OracleMessage::from_ssz_bytes(&message.as_ssz_bytes()).unwrap();
+
+        assert_eq!(from_ssz.validator_info, message.validator_info);
+        assert_eq!(
+            serde_json::to_value(&from_ssz).unwrap()["validator_info"],
+            serde_json::json!({"index": 42, "status": "active_exiting"})
+        );
+    }
+
+    #[test]
+    fn ssz_is_smaller_than_json() {
+        let message = generate_message();
+        let ssz_bytes = message.as_ssz_bytes();
+
+        assert_eq!(ssz_bytes.len(), message.ssz_bytes_len());
+        assert_eq!(
+            ssz_bytes.len(),
+            OracleMessage::ssz_fixed_part_len()
+                + message.interval_inclusion_messages.len()
+                    * <SignedIntervalInclusionMessage as Encode>::ssz_fixed_len()
+                // The union selectors of the absent interval bounds message and validator info.
+                + 2
+        );
+        assert!(ssz_bytes.len() < serde_json::to_vec(&message).unwrap().len());
+    }
+
+    #[test]
+    fn rejects_truncated_ssz() {
+        let ssz_bytes = generate_message().as_ssz_bytes();
+        assert!(OracleMessage::from_ssz_bytes(&ssz_bytes[..ssz_bytes.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,229 @@
+//! # Stdout Message Broadcaster
+//! Writes one compact JSON object per line to stdout, so the client can be piped into jq, vector
+//! or any other process. Either a `MessageSummary` per slot or the full message. Logs go to
+//! stderr, stdout only ever holds these lines.
+
+use std::{
+    fmt::Display,
+    io::Write,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+use async_trait::async_trait;
+use bls::PublicKey;
+use eyre::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::message_broadcaster::{MessageBroadcaster, OracleMessage, SignedOracleRegistration};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StdoutFormat {
+    #[default]
+    Summary,
+    /// The message exactly as sent to servers in JSON.
+    Full,
+}
+
+impl FromStr for StdoutFormat {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "summary" => Ok(StdoutFormat::Summary),
+            "full" => Ok(StdoutFormat::Full),
+            _ => Err(eyre::eyre!(
+                "unknown stdout format {}, expected summary or full",
+                s
+            )),
+        }
+    }
+}
+
+impl Display for StdoutFormat {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StdoutFormat::Summary => write!(f, "summary"),
+            StdoutFormat::Full => write!(f, "full"),
+        }
+    }
+}
+
+/// What a message boils down to, prices and interval bounds in their fixed precision.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageSummary {
+    pub slot: u64,
+    pub price: u64,
+    pub validator_public_key: PublicKey,
+    pub interval_lower_bound: Option<u64>,
+    pub interval_upper_bound: Option<u64>,
+    /// Number of signed interval messages, one per step for V1 and one in total for V2.
+    pub interval_message_count: usize,
+}
+
+impl From<&OracleMessage> for MessageSummary {
+    fn from(msg: &OracleMessage) -> Self {
+        let interval = msg.interval_bounds();
+        MessageSummary {
+            slot: msg.value_message.message.slot_number,
+            price: msg.value_message.message.price.value,
+            validator_public_key: msg.validator_public_key.clone(),
+            interval_lower_bound: interval.as_ref().map(|interval| interval.lower_bound),
+            interval_upper_bound: interval.as_ref().map(|interval| interval.upper_bound),
+            interval_message_count: msg.interval_inclusion_messages.len()
+                + usize::from(msg.interval_bounds_message.is_some()),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct StdoutMessageBroadcaster {
+    format: StdoutFormat,
+    output: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl StdoutMessageBroadcaster {
+    pub fn new(format: StdoutFormat) -> StdoutMessageBroadcaster {
+        Self::new_with_output(format, Box::new(std::io::stdout()))
+    }
+
+    /// Writes the lines to `output` instead of stdout.
+    pub fn new_with_output(
+        format: StdoutFormat,
+        output: Box<dyn Write + Send>,
+    ) -> StdoutMessageBroadcaster {
+        StdoutMessageBroadcaster {
+            format,
+            output: Arc::new(Mutex::new(output)),
+        }
+    }
+
+    /// Writes the whole line at once, so lines of concurrent slots don't interleave.
+    fn write_line(&self, value: &impl Serialize) -> Result<()> {
+        let mut line = serde_json::to_vec(value)?;
+        line.push(b'\n');
+        let mut output = self.output.lock().unwrap();
+        output
+            .write_all(&line)
+            .and_then(|()| output.flush())
+            .wrap_err("Failed to write to stdout")
+    }
+}
+
+#[async_trait]
+impl MessageBroadcaster for StdoutMessageBroadcaster {
+    async fn broadcast(&self, msg: &OracleMessage) -> Result<()> {
+        match self.format {
+            StdoutFormat::Summary => self.write_line(&MessageSummary::from(msg)),
+            StdoutFormat::Full => self.write_line(msg),
+        }
+    }
+
+    async fn broadcast_registration(&self, registration: &SignedOracleRegistration) -> Result<()> {
+        self.write_line(registration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        message_broadcaster::MessageVersion,
+        message_generator::{MessageGenerator, MessageGeneratorConfig},
+        price_provider::Price,
+        signature_provider::private_key::PrivateKeySignatureProvider,
+        slot::Slot,
+    };
+
+    /// Collects what the broadcaster writes.
+    #[derive(Clone, Default)]
+    struct SharedOutput(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl SharedOutput {
+        fn lines(&self) -> Vec<String> {
+            String::from_utf8(self.0.lock().unwrap().clone())
+                .unwrap()
+                .lines()
+                .map(|line| line.to_string())
+                .collect()
+        }
+    }
+
+    fn generate_message(message_version: MessageVersion, slot: u64) -> OracleMessage {
+        MessageGenerator::new_with_config(
+            Box::new(PrivateKeySignatureProvider::random()),
+            MessageGeneratorConfig {
+                message_version,
+                ..MessageGeneratorConfig::default()
+            },
+        )
+        .generate_oracle_message(
+            Price {
+                value: 1_000_000_000,
+            },
+            Slot(slot),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn writes_one_summary_per_line() {
+        let output = SharedOutput::default();
+        let broadcaster = StdoutMessageBroadcaster::new_with_output(
+            StdoutFormat::Summary,
+            Box::new(output.clone()),
+        );
+        let v1_message = generate_message(MessageVersion::V1, 1);
+        let v2_message = generate_message(MessageVersion::V2, 2);
+
+        broadcaster.broadcast(&v1_message).await.unwrap();
+        broadcaster.broadcast(&v2_message).await.unwrap();
+
+        let summaries = output
+            .lines()
+            .iter()
+            .map(|line| serde_json::from_str::<MessageSummary>(line).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(summaries.len(), 2);
+        assert_eq!(summaries[0], MessageSummary::from(&v1_message));
+        assert_eq!(summaries[0].slot, 1);
+        assert_eq!(
+            summaries[0].interval_message_count,
+            v1_message.interval_inclusion_messages.len()
+        );
+        let v2_interval = v2_message.interval_bounds_message.unwrap().message;
+        assert_eq!(summaries[1].interval_message_count, 1);
+        assert_eq!(
+            summaries[1].interval_lower_bound,
+            Some(v2_interval.lower_bound)
+        );
+        assert_eq!(
+            summaries[1].interval_upper_bound,
+            Some(v2_interval.upper_bound)
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_full_messages() {
+        let output = SharedOutput::default();
+        let broadcaster =
+            StdoutMessageBroadcaster::new_with_output(StdoutFormat::Full, Box::new(output.clone()));
+        let msg = generate_message(MessageVersion::V2, 3);
+
+        broadcaster.broadcast(&msg).await.unwrap();
+
+        let lines = output.lines();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0], serde_json::to_string(&msg).unwrap());
+    }
+}
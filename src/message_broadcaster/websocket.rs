@@ -0,0 +1,310 @@
+//! # WebSocket Message Broadcaster
+//! Sends every message as a JSON text frame over one persistent connection, saving the connection
+//! setup and TLS handshake a POST per slot costs. The server answers every message with a
+//! `WebSocketAck` for its slot, a broadcast only succeeds once that arrives. Acks for the same slot,
+//! e.g. for the messages of several keys, are expected in the order the messages were sent. A
+//! background task owns the connection and reconnects with exponential backoff when it drops,
+//! broadcasts while disconnected fail right away so an outbox can redeliver them.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use eyre::Result;
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, oneshot};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+
+use crate::message_broadcaster::{MessageBroadcaster, OracleMessage};
+
+// Broadcasts waiting for the connection task, more than a few means it's stuck.
+const REQUEST_QUEUE_SIZE: usize = 16;
+
+/// What the server answers every message with.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WebSocketAck {
+    pub slot: u64,
+    /// Why the server rejected the message, if it did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct WebSocketConfig {
+    pub ack_timeout: Duration,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for WebSocketConfig {
+    fn default() -> Self {
+        WebSocketConfig {
+            ack_timeout: Duration::from_secs(10),
+            initial_backoff: Duration::from_millis(500),
+            max_backoff: Duration::from_secs(30),
+        }
+    }
+}
+
+struct Request {
+    slot: u64,
+    frame: String,
+    ack: oneshot::Sender<Result<()>>,
+}
+
+#[derive(Clone)]
+pub struct WebSocketMessageBroadcaster {
+    requests: mpsc::Sender<Request>,
+    ack_timeout: Duration,
+}
+
+impl WebSocketMessageBroadcaster {
+    /// Starts connecting to `server_url`, a `ws://` or `wss://` url, in the background. Needs to
+    /// be called within a tokio runtime.
+    pub fn new(server_url: &str) -> WebSocketMessageBroadcaster {
+        Self::new_with_config(server_url, WebSocketConfig::default())
+    }
+
+    pub fn new_with_config(
+        server_url: &str,
+        config: WebSocketConfig,
+    ) -> WebSocketMessageBroadcaster {
+        let (requests, receiver) = mpsc::channel(REQUEST_QUEUE_SIZE);
+        let ack_timeout = config.ack_timeout;
+        tokio::spawn(run_connection(server_url.to_string(), receiver, config));
+        WebSocketMessageBroadcaster {
+            requests,
+            ack_timeout,
+        }
+    }
+}
+
+#[async_trait]
+impl MessageBroadcaster for WebSocketMessageBroadcaster {
+    async fn broadcast(&self, msg: &OracleMessage) -> Result<()> {
+        let slot = msg.value_message.message.slot_number;
+        let (ack, ack_receiver) = oneshot::channel();
+        self.requests
+            .send(Request {
+                slot,
+                frame: serde_json::to_string(msg)?,
+                ack,
+            })
+            .await
+            .map_err(|_| eyre::eyre!("WebSocket connection task stopped"))?;
+        tokio::time::timeout(self.ack_timeout, ack_receiver)
+            .await
+            .map_err(|_| {
+                eyre::eyre!(
+                    "No ack for slot {} within {}ms",
+                    slot,
+                    self.ack_timeout.as_millis()
+                )
+            })?
+            .map_err(|_| eyre::eyre!("WebSocket connection lost before ack for slot {}", slot))?
+    }
+}
+
+/// Keeps a connection up for as long as the broadcaster is around.
+async fn run_connection(
+    server_url: String,
+    mut requests: mpsc::Receiver<Request>,
+    config: WebSocketConfig,
+) {
+    let mut backoff = config.initial_backoff;
+    loop {
+        match connect_async(server_url.as_str()).await {
+            Ok((stream, _)) => {
+                log::info!("Connected to WebSocket server at {}", server_url);
+                backoff = config.initial_backoff;
+                match serve_connection(stream, &mut requests).await {
+                    Ok(()) => return,
+                    Err(e) => log::warn!("WebSocket connection to {} lost - {:#}", server_url, e),
+                }
+            }
+            Err(e) => log::warn!("Failed to connect to {} - {}", server_url, e),
+        }
+
+        let reconnect = tokio::time::sleep(backoff);
+        tokio::pin!(reconnect);
+        loop {
+            tokio::select! {
+                _ = &mut reconnect => break,
+                request = requests.recv() => match request {
+                    Some(request) => {
+                        let _ = request.ack.send(Err(eyre::eyre!(
+                            "Not connected to WebSocket server at {}",
+                            server_url
+                        )));
+                    }
+                    None => return,
+                },
+            }
+        }
+        backoff = std::cmp::min(backoff * 2, config.max_backoff);
+    }
+}
+
+/// Sends requests and resolves them with the acks the server sends back. Returns once the
+/// broadcaster is dropped, errors when the connection fails.
+async fn serve_connection<S>(
+    stream: tokio_tungstenite::WebSocketStream<S>,
+    requests: &mut mpsc::Receiver<Request>,
+) -> Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let (mut sink, mut stream) = stream.split();
+    let mut pending: HashMap<u64, VecDeque<oneshot::Sender<Result<()>>>> = HashMap::new();
+    loop {
+        tokio::select! {
+            request = requests.recv() => {
+                let Some(request) = request else {
+                    let _ = sink.close().await;
+                    return Ok(());
+                };
+                sink.send(Message::Text(request.frame)).await?;
+                // Slots whose broadcasts all gave up waiting don't need their acks anymore. Single
+                // ones stay queued, so the acks still following them line up.
+                pending.retain(|_, acks| !acks.iter().all(|ack| ack.is_closed()));
+                pending.entry(request.slot).or_default().push_back(request.ack);
+            }
+            frame = stream.next() => match frame {
+                Some(Ok(Message::Text(text))) => match serde_json::from_str::<WebSocketAck>(&text) {
+                    Ok(ack) => {
+                        let acks = pending.entry(ack.slot).or_default();
+                        let pending_ack = acks.pop_front();
+                        if acks.is_empty() {
+                            pending.remove(&ack.slot);
+                        }
+                        if let Some(pending_ack) = pending_ack {
+                            let _ = pending_ack.send(match ack.error {
+                                None => Ok(()),
+                                Some(error) => Err(eyre::eyre!(
+                                    "Server rejected message for slot {}: {}",
+                                    ack.slot,
+                                    error
+                                )),
+                            });
+                        }
+                    }
+                    Err(e) => log::warn!("Ignoring unreadable frame from WebSocket server - {}", e),
+                },
+                Some(Ok(Message::Close(_))) | None => eyre::bail!("connection closed by server"),
+                // Pings are answered by tungstenite itself.
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e.into()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{
+        message_generator::MessageGenerator, price_provider::Price,
+        signature_provider::private_key::PrivateKeySignatureProvider, slot::Slot,
+    };
+
+    fn generate_message(slot: u64) -> OracleMessage {
+        MessageGenerator::new(Box::new(PrivateKeySignatureProvider::random()))
+            .generate_oracle_message(Price { value: 10 }, Slot(slot))
+            .unwrap()
+    }
+
+    /// Acks every message, rejecting odd slots. Drops each connection after `messages_per_connection`
+    /// messages, without acking the last one.
+    async fn spawn_ack_server(messages_per_connection: usize) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((tcp_stream, _)) = listener.accept().await {
+                let mut stream = tokio_tungstenite::accept_async(tcp_stream).await.unwrap();
+                let mut received = 0;
+                while let Some(Ok(Message::Text(text))) = stream.next().await {
+                    received += 1;
+                    if received == messages_per_connection {
+                        break;
+                    }
+                    let msg: OracleMessage = serde_json::from_str(&text).unwrap();
+                    let slot = msg.value_message.message.slot_number;
+                    let ack = WebSocketAck {
+                        slot,
+                        error: (slot % 2 == 1).then(|| "odd slot".to_string()),
+                    };
+                    let frame = serde_json::to_string(&ack).unwrap();
+                    stream.send(Message::Text(frame)).await.unwrap();
+                }
+            }
+        });
+        addr
+    }
+
+    fn test_config() -> WebSocketConfig {
+        WebSocketConfig {
+            ack_timeout: Duration::from_secs(1),
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_millis(50),
+        }
+    }
+
+    /// Retries while the broadcaster is still connecting.
+    async fn broadcast_until_acked(broadcaster: &WebSocketMessageBroadcaster, slot: u64) -> bool {
+        for _ in 0..20 {
+            if broadcaster.broadcast(&generate_message(slot)).await.is_ok() {
+                return true;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        false
+    }
+
+    #[tokio::test]
+    async fn waits_for_server_ack() {
+        let addr = spawn_ack_server(usize::MAX).await;
+        let broadcaster =
+            WebSocketMessageBroadcaster::new_with_config(&format!("ws://{}", addr), test_config());
+
+        assert!(broadcast_until_acked(&broadcaster, 2).await);
+        assert!(broadcaster.broadcast(&generate_message(4)).await.is_ok());
+        assert!(broadcaster.broadcast(&generate_message(3)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn waits_for_every_ack_of_a_slot() {
+        let addr = spawn_ack_server(usize::MAX).await;
+        let broadcaster =
+            WebSocketMessageBroadcaster::new_with_config(&format!("ws://{}", addr), test_config());
+        assert!(broadcast_until_acked(&broadcaster, 2).await);
+
+        // Messages of two keys for the same slot.
+        let (first_msg, second_msg) = (generate_message(4), generate_message(4));
+        let (first, second) = tokio::join!(
+            broadcaster.broadcast(&first_msg),
+            broadcaster.broadcast(&second_msg)
+        );
+
+        assert!(first.is_ok());
+        assert!(second.is_ok());
+    }
+
+    #[tokio::test]
+    async fn reconnects_after_connection_drops() {
+        let addr = spawn_ack_server(2).await;
+        let broadcaster =
+            WebSocketMessageBroadcaster::new_with_config(&format!("ws://{}", addr), test_config());
+
+        assert!(broadcast_until_acked(&broadcaster, 2).await);
+        // The server drops the connection instead of acking.
+        assert!(broadcaster.broadcast(&generate_message(4)).await.is_err());
+        assert!(broadcast_until_acked(&broadcaster, 6).await);
+    }
+}
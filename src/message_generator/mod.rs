@@ -1,13 +1,21 @@
+use std::sync::Arc;
+
+use bls::PublicKey;
+use ethers::types::Address;
 use eyre::{Result, WrapErr};
 use ssz::Encode;
 
 use crate::{
+    beacon_node::ValidatorMonitor,
+    http_client::USER_AGENT,
     message_broadcaster::{
-        IntervalInclusionMessage, OracleMessage, PriceValueMessage, SignedIntervalInclusionMessage,
-        SignedPriceValueMessage,
+        IntervalBoundsMessage, IntervalInclusionMessage, MessageVersion, OracleMessage,
+        OracleRegistration, PriceValueMessage, SignedIntervalBoundsMessage,
+        SignedIntervalInclusionMessage, SignedOracleRegistration, SignedPriceValueMessage,
     },
     price_provider::{Price, PRECISION_FACTOR},
     signature_provider::SignatureProvider,
+    slashing_protection::SlashingProtection,
     slot::Slot,
 };
 
@@ -16,51 +24,161 @@ pub const INTERVAL_PRECISION_FACTOR: u64 = 10u64.pow(INTERVAL_STEP_DECIMALS);
 pub const INTERVAL_SIZE_BASIS_POINTS: u64 = 20;
 pub const ONE_IN_BASIS_POINTS: u64 = 10000;
 
+#[derive(Clone, Debug)]
+pub struct MessageGeneratorConfig {
+    pub message_version: MessageVersion,
+    /// Width of the attested interval on either side of the price.
+    pub interval_size_basis_points: u64,
+}
+
+impl Default for MessageGeneratorConfig {
+    fn default() -> Self {
+        MessageGeneratorConfig {
+            message_version: MessageVersion::V1,
+            interval_size_basis_points: INTERVAL_SIZE_BASIS_POINTS,
+        }
+    }
+}
+
+/// Bounds of the interval of `interval_size_basis_points` around the price, the lower bound
+/// inclusive and the upper exclusive, in interval precision.
+pub fn interval_bounds(price_value: u64, interval_size_basis_points: u64) -> (u64, u64) {
+    let scale = |basis_points: u64| {
+        // Widened so extreme prices don't overflow, the result always fits again.
+        (price_value as u128 * basis_points as u128 / ONE_IN_BASIS_POINTS as u128
+            * INTERVAL_PRECISION_FACTOR as u128
+            / PRECISION_FACTOR as u128) as u64
+    };
+    (
+        scale(ONE_IN_BASIS_POINTS - interval_size_basis_points),
+        scale(ONE_IN_BASIS_POINTS + interval_size_basis_points),
+    )
+}
+
+/// Builds and signs the `OracleMessage` for a price and slot.
 pub struct MessageGenerator {
     signature_provider: Box<dyn SignatureProvider + std::marker::Send + std::marker::Sync>,
+    config: MessageGeneratorConfig,
+    slashing_protection: Option<Arc<SlashingProtection>>,
+    validator_monitor: Option<ValidatorMonitor>,
 }
 
 impl MessageGenerator {
     pub fn new(
         signature_provider: Box<dyn SignatureProvider + std::marker::Send + std::marker::Sync>,
+    ) -> MessageGenerator {
+        Self::new_with_config(signature_provider, MessageGeneratorConfig::default())
+    }
+
+    pub fn new_with_config(
+        signature_provider: Box<dyn SignatureProvider + std::marker::Send + std::marker::Sync>,
+        config: MessageGeneratorConfig,
     ) -> MessageGenerator {
         MessageGenerator {
             signature_provider: signature_provider.clone(),
+            config,
+            slashing_protection: None,
+            validator_monitor: None,
+        }
+    }
+
+    /// Checks every price value against the slashing protection history before signing it.
+    pub fn new_with_slashing_protection(
+        signature_provider: Box<dyn SignatureProvider + std::marker::Send + std::marker::Sync>,
+        config: MessageGeneratorConfig,
+        slashing_protection: Arc<SlashingProtection>,
+    ) -> MessageGenerator {
+        MessageGenerator {
+            slashing_protection: Some(slashing_protection),
+            ..Self::new_with_config(signature_provider, config)
+        }
+    }
+
+    pub fn slashing_protection(&self) -> Option<Arc<SlashingProtection>> {
+        self.slashing_protection.clone()
+    }
+
+    /// Attaches the validator's index and status to every message, and refuses to sign while the
+    /// monitor doesn't consider it active.
+    pub fn with_validator_monitor(self, validator_monitor: ValidatorMonitor) -> MessageGenerator {
+        MessageGenerator {
+            validator_monitor: Some(validator_monitor),
+            ..self
         }
     }
 
     pub fn generate_oracle_message(&self, price: Price, slot: Slot) -> Result<OracleMessage> {
-        let interval_inclusion_messages = self
-            .generate_signed_interval_inclusion_messages(price.value, slot)
-            .wrap_err("Failed to generate interval_inclusion_messages")?;
+        let validator_info = self
+            .validator_monitor
+            .as_ref()
+            .map(ValidatorMonitor::validator_info)
+            .transpose()?;
+        let price_value = price.value;
+        // First, so nothing is signed when slashing protection refuses the price.
         let value_message = self
             .generate_signed_price_value_message(price, slot)
             .wrap_err("Failed to generate value message")?;
-        let validator_public_key = self
-            .signature_provider
-            .get_public_key()
-            .wrap_err("Failed to get public key")?;
+        let (interval_inclusion_messages, interval_bounds_message) =
+            match self.config.message_version {
+                MessageVersion::V1 => (
+                    self.generate_signed_interval_inclusion_messages(price_value, slot)
+                        .wrap_err("Failed to generate interval_inclusion_messages")?,
+                    None,
+                ),
+                MessageVersion::V2 => (
+                    Vec::new(),
+                    Some(
+                        self.generate_signed_interval_bounds_message(price_value, slot)
+                            .wrap_err("Failed to generate interval_bounds_message")?,
+                    ),
+                ),
+            };
+        let validator_public_key = self.get_public_key()?;
         Ok(OracleMessage {
+            version: self.config.message_version,
             value_message,
             interval_inclusion_messages,
+            interval_bounds_message,
             validator_public_key,
+            validator_info,
         })
     }
 
-    fn get_upper_bound(&self, price_value: u64) -> u64 {
-        self.convert_precision(
-            price_value * (ONE_IN_BASIS_POINTS + INTERVAL_SIZE_BASIS_POINTS) / ONE_IN_BASIS_POINTS,
-        )
-    }
-
-    fn get_lower_bound(&self, price_value: u64) -> u64 {
-        self.convert_precision(
-            price_value * (ONE_IN_BASIS_POINTS - INTERVAL_SIZE_BASIS_POINTS) / ONE_IN_BASIS_POINTS,
-        )
+    /// Registers our key with a proof of possession, see `OracleRegistration`.
+    pub fn generate_registration(
+        &self,
+        fee_recipient: Address,
+        operator: String,
+    ) -> Result<SignedOracleRegistration> {
+        let validator_public_key = self.get_public_key()?;
+        let proof_of_possession = self
+            .signature_provider
+            .sign(&OracleRegistration::proof_of_possession_message(
+                &validator_public_key,
+            ))
+            .wrap_err("Failed to sign proof of possession")?;
+        let registration = OracleRegistration {
+            validator_public_key,
+            proof_of_possession,
+            fee_recipient,
+            operator,
+            client_version: USER_AGENT.to_string(),
+            timestamp: chrono::Utc::now().timestamp() as u64,
+        };
+        let signature = self
+            .signature_provider
+            .sign(&registration.as_ssz_bytes())
+            .wrap_err("Failed to sign serialized registration")?;
+        Ok(SignedOracleRegistration {
+            message: registration,
+            signature,
+        })
     }
 
-    fn convert_precision(&self, price_value: u64) -> u64 {
-        price_value * INTERVAL_PRECISION_FACTOR / PRECISION_FACTOR
+    pub fn get_public_key(&self) -> Result<PublicKey> {
+        self.signature_provider
+            .get_public_key()
+            .wrap_err("Failed to get public key")
     }
 
     fn generate_signed_interval_inclusion_messages(
@@ -68,8 +186,8 @@ impl MessageGenerator {
         price_value: u64,
         slot_number: Slot,
     ) -> Result<Vec<SignedIntervalInclusionMessage>> {
-        let upper_bound = self.get_upper_bound(price_value);
-        let lower_bound = self.get_lower_bound(price_value);
+        let (lower_bound, upper_bound) =
+            interval_bounds(price_value, self.config.interval_size_basis_points);
         log::debug!(
             "Generating interval messages from {} to {}",
             lower_bound,
@@ -84,7 +202,7 @@ impl MessageGenerator {
         let interval_inclusion_messages = interval_values
             .map(|value| IntervalInclusionMessage {
                 value,
-                interval_size: INTERVAL_SIZE_BASIS_POINTS,
+                interval_size: self.config.interval_size_basis_points,
                 slot_number: slot_number.0,
             })
             .collect::<Vec<IntervalInclusionMessage>>();
@@ -110,6 +228,35 @@ impl MessageGenerator {
             .collect()
     }
 
+    /// Signs the bounds of the interval once, rather than every step within it.
+    fn generate_signed_interval_bounds_message(
+        &self,
+        price_value: u64,
+        slot_number: Slot,
+    ) -> Result<SignedIntervalBoundsMessage> {
+        let (lower_bound, upper_bound) =
+            interval_bounds(price_value, self.config.interval_size_basis_points);
+        let interval_bounds_message = IntervalBoundsMessage {
+            lower_bound,
+            upper_bound,
+            interval_size: self.config.interval_size_basis_points,
+            slot_number: slot_number.0,
+        };
+        log::debug!(
+            "Signing interval bounds message from {} to {}",
+            interval_bounds_message.lower_bound,
+            interval_bounds_message.upper_bound
+        );
+        let signature = self
+            .signature_provider
+            .sign(&interval_bounds_message.as_ssz_bytes())
+            .wrap_err("Failed to sign serialized interval bounds message")?;
+        Ok(SignedIntervalBoundsMessage {
+            message: interval_bounds_message,
+            signature,
+        })
+    }
+
     fn generate_signed_price_value_message(
         &self,
         price: Price,
@@ -119,6 +266,9 @@ impl MessageGenerator {
             price,
             slot_number: slot_number.0,
         };
+        if let Some(slashing_protection) = &self.slashing_protection {
+            slashing_protection.check_and_record(&self.get_public_key()?, &price_value_message)?;
+        }
         let price_value_ssz = price_value_message.as_ssz_bytes();
         let price_value_signature = self
             .signature_provider
@@ -135,6 +285,9 @@ impl Clone for MessageGenerator {
     fn clone(&self) -> Self {
         MessageGenerator {
             signature_provider: self.signature_provider.clone(),
+            config: self.config.clone(),
+            slashing_protection: self.slashing_protection.clone(),
+            validator_monitor: self.validator_monitor.clone(),
         }
     }
 }
@@ -142,7 +295,7 @@ impl Clone for MessageGenerator {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::PrivateKeySignatureProvider;
+    use crate::signature_provider::private_key::PrivateKeySignatureProvider;
 
     #[tokio::test]
     async fn generates_correct_price_value_messsage() {
@@ -231,4 +384,83 @@ mod tests {
             }
         }
     }
+
+    #[tokio::test]
+    async fn generates_correct_interval_bounds_message() {
+        let signature_provider = PrivateKeySignatureProvider::random();
+        let message_generator = MessageGenerator::new_with_config(
+            signature_provider.clone(),
+            MessageGeneratorConfig {
+                message_version: MessageVersion::V2,
+                ..MessageGeneratorConfig::default()
+            },
+        );
+        let price = Price {
+            value: 1000 * PRECISION_FACTOR,
+        };
+        let slot = Slot(1);
+
+        let oracle_message = message_generator
+            .generate_oracle_message(price, slot)
+            .unwrap();
+
+        assert_eq!(oracle_message.version, MessageVersion::V2);
+        assert!(oracle_message.interval_inclusion_messages.is_empty());
+        let interval_bounds_message = oracle_message.interval_bounds_message.unwrap();
+        assert_eq!(
+            interval_bounds_message.message.lower_bound,
+            998 * INTERVAL_PRECISION_FACTOR
+        );
+        assert_eq!(
+            interval_bounds_message.message.upper_bound,
+            1002 * INTERVAL_PRECISION_FACTOR
+        );
+        assert_eq!(interval_bounds_message.message.slot_number, slot.0);
+        assert_eq!(
+            interval_bounds_message.message.interval_size,
+            INTERVAL_SIZE_BASIS_POINTS
+        );
+        assert!(interval_bounds_message.signature.verify(
+            &oracle_message.validator_public_key,
+            signature_provider.get_message_digest(&interval_bounds_message.message.as_ssz_bytes())
+        ));
+    }
+
+    #[test]
+    fn leaves_version_out_of_v1_json() {
+        let message_generator =
+            MessageGenerator::new(Box::new(PrivateKeySignatureProvider::random()));
+        let oracle_message = message_generator
+            .generate_oracle_message(Price { value: 10 }, Slot(1))
+            .unwrap();
+
+        let json = serde_json::to_value(&oracle_message).unwrap();
+        assert!(json.get("version").is_none());
+        assert!(json.get("interval_bounds_message").is_none());
+
+        let parsed: OracleMessage = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.version, MessageVersion::V1);
+    }
+
+    #[test]
+    fn refuses_second_price_for_slot_with_slashing_protection() {
+        let dir = tempfile::tempdir().unwrap();
+        let slashing_protection =
+            SlashingProtection::open(&dir.path().join("slashing_protection.ndjson")).unwrap();
+        let message_generator = MessageGenerator::new_with_slashing_protection(
+            Box::new(PrivateKeySignatureProvider::random()),
+            MessageGeneratorConfig::default(),
+            Arc::new(slashing_protection),
+        );
+
+        message_generator
+            .generate_oracle_message(Price { value: 10 }, Slot(1))
+            .unwrap();
+        message_generator
+            .generate_oracle_message(Price { value: 10 }, Slot(1))
+            .unwrap();
+        assert!(message_generator
+            .generate_oracle_message(Price { value: 11 }, Slot(1))
+            .is_err());
+    }
 }
@@ -0,0 +1,321 @@
+//! # Mock Oracle Server
+//! Local stand-in for the oracle-server, so the whole flow can be tested without running the
+//! separate project. It accepts oracle messages in every encoding the `HttpMessageBroadcaster`
+//! sends, verifies them, keeps them in memory per slot and aggregates the interval inclusion
+//! signatures of the registered validators, like the real server does, see `aggregation`.
+//! Registrations are checked for their proof of possession and the latest one per key is kept,
+//! messages of keys without one are kept but left out of the aggregates, as aggregating them isn't
+//! safe against rogue keys. Submissions are answered with a `SubmissionResponse`. Only built with
+//! the `mock-server` feature.
+
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    body::Bytes,
+    extract::{Path, Query, State},
+    http::{
+        header::{CONTENT_ENCODING, CONTENT_TYPE},
+        HeaderMap, StatusCode,
+    },
+    routing::{get, post},
+    Json, Router,
+};
+use eyre::{Context, Result};
+use serde::Deserialize;
+use ssz::{Decode, Encode};
+
+use crate::{
+    aggregation::{aggregate_slot, SlotAggregate},
+    message_broadcaster::{
+        http::REGISTRATION_PATH,
+        response::{ErrorResponse, RejectionReason, SubmissionResponse, SubmissionStatus},
+        OracleMessage, SignedOracleRegistration,
+    },
+    slot::Slot,
+    verification::{verify_oracle_message, verify_oracle_registration, VerifyOptions, Violation},
+};
+
+pub const DEFAULT_MOCK_ORACLE_SERVER_ADDR: &str = "0.0.0.0:3000";
+
+/// Messages received so far, by slot and then by the validator's public key, and the latest
+/// registration per key.
+#[derive(Clone, Default)]
+pub struct MockOracleServerState {
+    slots: Arc<Mutex<BTreeMap<u64, BTreeMap<String, OracleMessage>>>>,
+    registrations: Arc<Mutex<BTreeMap<String, SignedOracleRegistration>>>,
+}
+
+impl MockOracleServerState {
+    pub fn new() -> MockOracleServerState {
+        MockOracleServerState::default()
+    }
+}
+
+fn error_response(
+    status: StatusCode,
+    error: String,
+    violations: Vec<String>,
+) -> (StatusCode, Json<ErrorResponse>) {
+    (status, Json(ErrorResponse { error, violations }))
+}
+
+type SubmissionResult = std::result::Result<
+    (StatusCode, Json<SubmissionResponse>),
+    (StatusCode, Json<SubmissionResponse>),
+>;
+
+fn accepted() -> SubmissionResult {
+    Ok((
+        StatusCode::OK,
+        Json(SubmissionResponse {
+            status: SubmissionStatus::Accepted,
+            reason: None,
+            message: None,
+            violations: Vec::new(),
+            current_slot: Some(Slot::now().0),
+        }),
+    ))
+}
+
+fn rejected(
+    status: StatusCode,
+    reason: RejectionReason,
+    message: String,
+    violations: Vec<String>,
+) -> (StatusCode, Json<SubmissionResponse>) {
+    (
+        status,
+        Json(SubmissionResponse {
+            status: SubmissionStatus::Rejected,
+            reason: Some(reason),
+            message: Some(message),
+            violations,
+            current_slot: Some(Slot::now().0),
+        }),
+    )
+}
+
+fn decode_oracle_message(headers: &HeaderMap, body: &[u8]) -> Result<OracleMessage> {
+    let is_ssz = headers
+        .get(CONTENT_TYPE)
+        .is_some_and(|content_type| content_type == "application/octet-stream");
+    if !is_ssz {
+        return serde_json::from_slice(body).wrap_err("invalid oracle message JSON");
+    }
+    let is_snappy = headers
+        .get(CONTENT_ENCODING)
+        .is_some_and(|content_encoding| content_encoding == "snappy");
+    let ssz_bytes = if is_snappy {
+        snap::raw::Decoder::new()
+            .decompress_vec(body)
+            .wrap_err("invalid snappy compressed body")?
+    } else {
+        body.to_vec()
+    };
+    OracleMessage::from_ssz_bytes(&ssz_bytes)
+        .map_err(|e| eyre::eyre!("invalid oracle message SSZ: {:?}", e))
+}
+
+async fn post_oracle_message(
+    State(state): State<MockOracleServerState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> SubmissionResult {
+    let oracle_message = decode_oracle_message(&headers, &body).map_err(|e| {
+        rejected(
+            StatusCode::BAD_REQUEST,
+            RejectionReason::InvalidMessage,
+            format!("{:#}", e),
+            Vec::new(),
+        )
+    })?;
+
+    let report = verify_oracle_message(&oracle_message, &VerifyOptions::default());
+    if !report.is_valid() {
+        let bad_signature = report
+            .violations
+            .iter()
+            .any(|violation| matches!(violation, Violation::InvalidSignature { .. }));
+        return Err(rejected(
+            StatusCode::BAD_REQUEST,
+            if bad_signature {
+                RejectionReason::BadSignature
+            } else {
+                RejectionReason::InvalidMessage
+            },
+            "invalid oracle message".to_string(),
+            report.violations.iter().map(ToString::to_string).collect(),
+        ));
+    }
+
+    let slot = oracle_message.value_message.message.slot_number;
+    let validator = oracle_message.validator_public_key.to_string();
+    let mut slots = state.slots.lock().unwrap();
+    let messages = slots.entry(slot).or_default();
+    match messages.get(&validator) {
+        // Clients redeliver messages they aren't sure arrived.
+        Some(existing) if existing.as_ssz_bytes() == oracle_message.as_ssz_bytes() => accepted(),
+        Some(_) => Err(rejected(
+            StatusCode::CONFLICT,
+            RejectionReason::Conflict,
+            format!(
+                "validator {} already sent a different message for slot {}",
+                validator, slot
+            ),
+            Vec::new(),
+        )),
+        None => {
+            log::info!("Accepted message from {} for slot {}", validator, slot);
+            messages.insert(validator, oracle_message);
+            accepted()
+        }
+    }
+}
+
+async fn post_registration(
+    State(state): State<MockOracleServerState>,
+    Json(registration): Json<SignedOracleRegistration>,
+) -> SubmissionResult {
+    verify_oracle_registration(&registration).map_err(|e| {
+        rejected(
+            StatusCode::BAD_REQUEST,
+            RejectionReason::BadSignature,
+            format!("{:#}", e),
+            Vec::new(),
+        )
+    })?;
+
+    let validator = registration.message.validator_public_key.to_string();
+    let mut registrations = state.registrations.lock().unwrap();
+    match registrations.get(&validator) {
+        Some(existing) if existing.message.timestamp > registration.message.timestamp => {
+            Err(rejected(
+                StatusCode::CONFLICT,
+                RejectionReason::Conflict,
+                format!("validator {} has a more recent registration", validator),
+                Vec::new(),
+            ))
+        }
+        _ => {
+            log::info!(
+                "Registered {} for operator {}",
+                validator,
+                registration.message.operator
+            );
+            registrations.insert(validator, registration);
+            accepted()
+        }
+    }
+}
+
+async fn get_registrations(
+    State(state): State<MockOracleServerState>,
+) -> Json<Vec<SignedOracleRegistration>> {
+    Json(
+        state
+            .registrations
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect(),
+    )
+}
+
+async fn get_slots(State(state): State<MockOracleServerState>) -> Json<Vec<u64>> {
+    Json(state.slots.lock().unwrap().keys().copied().collect())
+}
+
+#[derive(Debug, Deserialize)]
+struct AggregateQuery {
+    /// Only return values signed by at least this many validators.
+    #[serde(default = "default_threshold")]
+    threshold: usize,
+}
+
+fn default_threshold() -> usize {
+    1
+}
+
+async fn get_slot(
+    State(state): State<MockOracleServerState>,
+    Path(slot): Path<u64>,
+    Query(query): Query<AggregateQuery>,
+) -> std::result::Result<Json<SlotAggregate>, (StatusCode, Json<ErrorResponse>)> {
+    let oracle_messages = {
+        let slots = state.slots.lock().unwrap();
+        let messages = slots.get(&slot).ok_or_else(|| {
+            error_response(
+                StatusCode::NOT_FOUND,
+                format!("no messages for slot {}", slot),
+                Vec::new(),
+            )
+        })?;
+        let registrations = state.registrations.lock().unwrap();
+        messages
+            .iter()
+            .filter(|(validator, _)| registrations.contains_key(*validator))
+            .map(|(_, message)| message.clone())
+            .collect::<Vec<_>>()
+    };
+    aggregate_slot(slot, &oracle_messages, query.threshold)
+        .map(Json)
+        .map_err(|e| {
+            error_response(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("{:#}", e),
+                Vec::new(),
+            )
+        })
+}
+
+#[derive(Debug, Deserialize)]
+struct MessagesQuery {
+    /// Only return the message signed by this key.
+    validator_public_key: Option<String>,
+}
+
+async fn get_slot_messages(
+    State(state): State<MockOracleServerState>,
+    Path(slot): Path<u64>,
+    Query(query): Query<MessagesQuery>,
+) -> std::result::Result<Json<Vec<OracleMessage>>, StatusCode> {
+    let slots = state.slots.lock().unwrap();
+    let messages = slots.get(&slot).ok_or(StatusCode::NOT_FOUND)?;
+    Ok(Json(
+        messages
+            .iter()
+            .filter(|(validator, _)| match &query.validator_public_key {
+                Some(validator_public_key) => validator_public_key == *validator,
+                None => true,
+            })
+            .map(|(_, message)| message.clone())
+            .collect(),
+    ))
+}
+
+/// Every endpoint of the mock server, keeping what it receives in `state`.
+pub fn router(state: MockOracleServerState) -> Router {
+    Router::new()
+        .route("/post_oracle_message", post(post_oracle_message))
+        .route(&format!("/{}", REGISTRATION_PATH), post(post_registration))
+        .route("/registrations", get(get_registrations))
+        .route("/slots", get(get_slots))
+        .route("/slots/:slot", get(get_slot))
+        .route("/slots/:slot/messages", get(get_slot_messages))
+        .with_state(state)
+}
+
+/// Serves `router` on `addr` until the server fails.
+pub async fn serve(addr: SocketAddr, state: MockOracleServerState) -> Result<()> {
+    log::info!("Starting mock oracle server on: {}", addr);
+    axum::Server::try_bind(&addr)
+        .wrap_err_with(|| format!("Failed to bind mock oracle server to {}", addr))?
+        .serve(router(state).into_make_service())
+        .await
+        .wrap_err("Mock oracle server stopped")
+}
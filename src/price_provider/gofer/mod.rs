@@ -1,3 +1,4 @@
+use crate::http_client::default_client;
 use crate::price_provider::gofer::types::GoferPriceRequest;
 use crate::price_provider::{Price, PriceProvider, PRECISION_FACTOR};
 use async_trait::async_trait;
@@ -8,21 +9,27 @@ mod types;
 pub struct GoferPriceProvider {
     gofer_url: String,
     pair: String,
+    client: reqwest::Client,
 }
 
 impl GoferPriceProvider {
     pub fn new(gofer_url: &str) -> GoferPriceProvider {
-        GoferPriceProvider {
-            gofer_url: gofer_url.to_string(),
-            pair: "ETH/USD".to_string(),
-        }
+        Self::new_with_pair(gofer_url, "ETH/USD".to_string())
     }
 
-    #[allow(dead_code)]
     pub fn new_with_pair(gofer_url: &str, pair: String) -> GoferPriceProvider {
+        Self::new_with_client(gofer_url, pair, default_client())
+    }
+
+    pub fn new_with_client(
+        gofer_url: &str,
+        pair: String,
+        client: reqwest::Client,
+    ) -> GoferPriceProvider {
         GoferPriceProvider {
             gofer_url: gofer_url.to_string(),
             pair,
+            client,
         }
     }
 
@@ -30,9 +37,9 @@ impl GoferPriceProvider {
         let msg = GoferPriceRequest {
             pair: self.pair.clone(),
         };
-        let client = reqwest::Client::new();
         log::debug!("Getting message from gofer at: {:}", self.gofer_url);
-        let response = client
+        let response = self
+            .client
             .post(&self.gofer_url)
             .json(&msg)
             .send()
@@ -65,6 +72,7 @@ impl Clone for GoferPriceProvider {
         GoferPriceProvider {
             gofer_url: self.gofer_url.clone(),
             pair: self.pair.clone(),
+            client: self.client.clone(),
         }
     }
 }
@@ -10,11 +10,13 @@ pub mod gofer;
 pub const PRECISION_DECIMALS: u32 = 6;
 pub const PRECISION_FACTOR: u64 = 10u64.pow(PRECISION_DECIMALS);
 
+/// A price in USD, multiplied by `PRECISION_FACTOR`.
 #[derive(Clone, Debug, Encode, Decode, Serialize, Deserialize)]
 pub struct Price {
     pub value: u64, // TODO: Check if we need to add further info here such as timestamp
 }
 
+/// Source of the current price to attest to.
 #[async_trait]
 pub trait PriceProvider {
     async fn get_price(&self) -> Result<Price>;
@@ -3,9 +3,12 @@ use eyre::Result;
 use bls::{PublicKey, Signature};
 pub mod private_key;
 
+/// Holds the validator key messages are signed with.
 pub trait SignatureProvider {
+    /// Signs the digest of the SSZ serialized `msg`.
     fn sign(&self, msg: &[u8]) -> Result<Signature>;
     fn get_public_key(&self) -> Result<PublicKey>;
+    /// Boxed copy, so generators can hold on to a provider of any type.
     fn clone(&self)
         -> Box<dyn SignatureProvider + std::marker::Send + std::marker::Sync + 'static>;
 }
@@ -1,9 +1,16 @@
-use eyre::Result;
+use std::path::Path;
+
+use eyre::{Context, Result};
 use sha3::{Digest, Sha3_256};
 
 use crate::signature_provider::SignatureProvider;
 use bls::{Hash256, PublicKey, SecretKey, Signature};
 
+/// Digest of a serialized message, this is what actually gets signed.
+pub fn message_digest(msg: &[u8]) -> Hash256 {
+    Hash256::from_slice(&Sha3_256::digest(msg))
+}
+
 pub struct PrivateKeySignatureProvider {
     private_key: SecretKey,
 }
@@ -18,8 +25,37 @@ impl PrivateKeySignatureProvider {
         PrivateKeySignatureProvider { private_key }
     }
 
+    /// Loads the private key from an encrypted keystore, as created by the `keygen` command.
+    pub fn from_keystore(path: &Path, password: &str) -> Result<PrivateKeySignatureProvider> {
+        let private_key_bytes = eth_keystore::decrypt_key(path, password)
+            .map_err(|e| eyre::eyre!("{}", e))
+            .wrap_err_with(|| format!("Failed to decrypt keystore {}", path.display()))?;
+        let private_key = SecretKey::deserialize(&private_key_bytes)
+            .map_err(|e| eyre::eyre!("Invalid private key in keystore: {:?}", e))?;
+        log::debug!(
+            "Loaded private key associated with public key: {:?}",
+            private_key.public_key()
+        );
+        Ok(PrivateKeySignatureProvider { private_key })
+    }
+
+    /// Encrypts the private key into a new keystore file in `dir`, returns the file's path.
+    pub fn write_keystore(&self, dir: &Path, password: &str) -> Result<std::path::PathBuf> {
+        std::fs::create_dir_all(dir)
+            .wrap_err_with(|| format!("Failed to create keystore directory {}", dir.display()))?;
+        let name = eth_keystore::encrypt_key(
+            dir,
+            &mut rand::thread_rng(),
+            self.private_key.serialize().as_bytes(),
+            password,
+            None,
+        )
+        .map_err(|e| eyre::eyre!("Failed to encrypt keystore: {}", e))?;
+        Ok(dir.join(name))
+    }
+
     pub fn get_message_digest(&self, msg: &[u8]) -> Hash256 {
-        Hash256::from_slice(&Sha3_256::digest(msg))
+        message_digest(msg)
     }
 }
 
@@ -55,6 +91,23 @@ mod tests {
         assert!(signature.verify(&public_key, signature_provider.get_message_digest(msg)));
     }
 
+    #[test]
+    fn round_trips_through_keystore() {
+        let dir = tempfile::tempdir().unwrap();
+        let signature_provider = PrivateKeySignatureProvider::random();
+
+        let path = signature_provider
+            .write_keystore(dir.path(), "hunter2")
+            .unwrap();
+
+        let loaded = PrivateKeySignatureProvider::from_keystore(&path, "hunter2").unwrap();
+        assert_eq!(
+            loaded.get_public_key().unwrap(),
+            signature_provider.get_public_key().unwrap()
+        );
+        assert!(PrivateKeySignatureProvider::from_keystore(&path, "wrong").is_err());
+    }
+
     #[tokio::test]
     async fn can_aggregate_signatures_from_multiple_signers() {
         let msg = b"Hello, world!";
@@ -0,0 +1,595 @@
+//! # Slashing Protection
+//! Local history of signed price values, modelled on EIP-3076, so we never sign two different
+//! prices for the same slot, e.g. after a restart mid-slot. Every signature is recorded in an
+//! append-only file before it is made. Only the last `RETAINED_SLOTS` slots are kept per key,
+//! anything older is refused through a low watermark.
+//!
+//! The history is locked for the lifetime of the process, so two local instances can't share it.
+//! Move it between machines with the interchange format of `export` and `import`.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{File, OpenOptions},
+    io::{BufRead, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use bls::{Hash256, PublicKey};
+use eyre::{Context, Result};
+use fs2::FileExt;
+use serde::{Deserialize, Serialize};
+use ssz::Encode;
+
+use crate::{
+    message_broadcaster::PriceValueMessage, signature_provider::private_key::message_digest,
+    slot::BEACON_GENESIS,
+};
+
+pub const DEFAULT_SLASHING_PROTECTION_PATH: &str = "slashing_protection.ndjson";
+pub const INTERCHANGE_FORMAT_VERSION: &str = "5";
+/// One day of slots.
+pub const RETAINED_SLOTS: usize = 7200;
+// Number of records no longer needed we tolerate in the file before rewriting it.
+const COMPACTION_SLACK: usize = 1024;
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum HistoryRecord {
+    Signed {
+        pubkey: PublicKey,
+        slot: u64,
+        /// Missing for imported slots we know were signed, but not what.
+        signing_root: Option<Hash256>,
+    },
+    /// Nothing at or below this slot may be signed.
+    Watermark { pubkey: PublicKey, slot: u64 },
+}
+
+#[derive(Default)]
+struct ValidatorHistory {
+    signed: BTreeMap<u64, Option<Hash256>>,
+    watermark: Option<u64>,
+}
+
+impl ValidatorHistory {
+    /// Drops the oldest slots beyond what we retain, raising the watermark over them.
+    fn prune(&mut self, retained_slots: usize) {
+        while self.signed.len() > retained_slots {
+            let (slot, _) = self.signed.pop_first().unwrap();
+            self.watermark = self.watermark.max(Some(slot));
+        }
+    }
+
+    fn record_count(&self) -> usize {
+        self.signed.len() + self.watermark.iter().count()
+    }
+}
+
+struct HistoryState {
+    file: File,
+    validators: HashMap<[u8; bls::PUBLIC_KEY_BYTES_LEN], (PublicKey, ValidatorHistory)>,
+    records_in_file: usize,
+    retained_slots: usize,
+}
+
+impl HistoryState {
+    fn apply(&mut self, record: HistoryRecord) {
+        match record {
+            HistoryRecord::Signed {
+                pubkey,
+                slot,
+                signing_root,
+            } => {
+                let retained_slots = self.retained_slots;
+                let history = self.history_mut(&pubkey);
+                let signing_root = match history.signed.get(&slot) {
+                    // Conflicting roots for a slot, e.g. from an import, refuse it altogether.
+                    Some(existing) if *existing != signing_root => None,
+                    _ => signing_root,
+                };
+                history.signed.insert(slot, signing_root);
+                history.prune(retained_slots);
+            }
+            HistoryRecord::Watermark { pubkey, slot } => {
+                let history = self.history_mut(&pubkey);
+                history.watermark = history.watermark.max(Some(slot));
+            }
+        }
+    }
+
+    fn history_mut(&mut self, pubkey: &PublicKey) -> &mut ValidatorHistory {
+        &mut self
+            .validators
+            .entry(pubkey.serialize())
+            .or_insert_with(|| (pubkey.clone(), ValidatorHistory::default()))
+            .1
+    }
+
+    fn records(&self) -> Vec<HistoryRecord> {
+        let mut records = Vec::new();
+        for (pubkey, history) in self.validators.values() {
+            if let Some(slot) = history.watermark {
+                records.push(HistoryRecord::Watermark {
+                    pubkey: pubkey.clone(),
+                    slot,
+                });
+            }
+            for (slot, signing_root) in history.signed.iter() {
+                records.push(HistoryRecord::Signed {
+                    pubkey: pubkey.clone(),
+                    slot: *slot,
+                    signing_root: *signing_root,
+                });
+            }
+        }
+        records
+    }
+
+    fn append(&mut self, record: HistoryRecord) -> Result<()> {
+        write_record(&mut self.file, &record)?;
+        self.file.sync_data()?;
+        self.records_in_file += 1;
+        self.apply(record);
+        Ok(())
+    }
+}
+
+fn write_record(file: &mut File, record: &HistoryRecord) -> Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    file.write_all(&line)?;
+    Ok(())
+}
+
+/// EIP-3076 style interchange file, with price values in place of blocks.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct Interchange {
+    pub metadata: InterchangeMetadata,
+    pub data: Vec<InterchangeValidator>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct InterchangeMetadata {
+    pub interchange_format_version: String,
+    /// Unix timestamp of the beacon chain genesis the slots count from, as a string.
+    pub genesis_time: String,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct InterchangeValidator {
+    pub pubkey: PublicKey,
+    pub signed_price_values: Vec<InterchangeSignedPriceValue>,
+}
+
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct InterchangeSignedPriceValue {
+    #[serde(with = "string_u64")]
+    pub slot: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing_root: Option<Hash256>,
+}
+
+/// EIP-3076 encodes integers as strings.
+mod string_u64 {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&value.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+pub struct SlashingProtection {
+    path: PathBuf,
+    state: Mutex<HistoryState>,
+    // Held for as long as we use the history.
+    _lock_file: File,
+}
+
+impl SlashingProtection {
+    pub fn open(path: &Path) -> Result<SlashingProtection> {
+        Self::open_with_retained_slots(path, RETAINED_SLOTS)
+    }
+
+    /// Keeps `retained_slots` slots per key instead of `RETAINED_SLOTS`.
+    pub fn open_with_retained_slots(
+        path: &Path,
+        retained_slots: usize,
+    ) -> Result<SlashingProtection> {
+        let lock_path = path.with_extension("lock");
+        let lock_file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .wrap_err_with(|| format!("Failed to open {}", lock_path.display()))?;
+        lock_file.try_lock_exclusive().wrap_err_with(|| {
+            format!(
+                "Slashing protection history {} is in use by another process",
+                path.display()
+            )
+        })?;
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+        let mut state = HistoryState {
+            file,
+            validators: HashMap::new(),
+            records_in_file: 0,
+            retained_slots,
+        };
+        let mut contents = std::fs::read(path)?;
+        // A crash mid-write can only leave a torn last line, its signature was never made. Cut it
+        // off, or the next record would be appended to it and lost with it on the next restart.
+        if !contents.is_empty() && !contents.ends_with(b"\n") {
+            let complete_len = contents
+                .iter()
+                .rposition(|byte| *byte == b'\n')
+                .map_or(0, |i| i + 1);
+            log::warn!(
+                "Dropping torn last record of slashing protection history {}",
+                path.display()
+            );
+            state.file.set_len(complete_len as u64)?;
+            state.file.sync_data()?;
+            contents.truncate(complete_len);
+        }
+        for (i, line) in contents.lines().enumerate() {
+            let line = line?;
+            // Anything else unreadable could hide a signature we made.
+            let record = serde_json::from_str::<HistoryRecord>(&line).wrap_err_with(|| {
+                format!(
+                    "Unreadable record on line {} of slashing protection history {}",
+                    i + 1,
+                    path.display()
+                )
+            })?;
+            state.apply(record);
+            state.records_in_file += 1;
+        }
+        log::info!(
+            "Opened slashing protection history at {} for {} validators",
+            path.display(),
+            state.validators.len()
+        );
+        let slashing_protection = SlashingProtection {
+            path: path.to_path_buf(),
+            state: Mutex::new(state),
+            _lock_file: lock_file,
+        };
+        slashing_protection.compact_if_needed(&mut slashing_protection.state.lock().unwrap())?;
+        Ok(slashing_protection)
+    }
+
+    /// Records that `pubkey` signs `message`. Errors if that could make it sign two different
+    /// prices for a slot, in which case the message must not be signed.
+    pub fn check_and_record(&self, pubkey: &PublicKey, message: &PriceValueMessage) -> Result<()> {
+        let slot = message.slot_number;
+        let signing_root = message_digest(&message.as_ssz_bytes());
+        let mut state = self.state.lock().unwrap();
+        let history = state.history_mut(pubkey);
+        if let Some(watermark) = history.watermark.filter(|watermark| slot <= *watermark) {
+            eyre::bail!(
+                "Refusing to sign price value for slot {}, at or below the slashing protection watermark {}",
+                slot,
+                watermark
+            );
+        }
+        match history.signed.get(&slot) {
+            // Signing the same message again, e.g. after a restart, is safe.
+            Some(Some(existing)) if *existing == signing_root => return Ok(()),
+            Some(_) => eyre::bail!(
+                "Refusing to sign price value for slot {}, already signed a different price for it",
+                slot
+            ),
+            None => {}
+        }
+        state.append(HistoryRecord::Signed {
+            pubkey: pubkey.clone(),
+            slot,
+            signing_root: Some(signing_root),
+        })?;
+        self.compact_if_needed(&mut state)
+    }
+
+    /// Whether the history shows `pubkey` signed a price value for `slot`.
+    pub fn has_signed(&self, pubkey: &PublicKey, slot: u64) -> bool {
+        self.state
+            .lock()
+            .unwrap()
+            .validators
+            .get(&pubkey.serialize())
+            .is_some_and(|(_, history)| history.signed.contains_key(&slot))
+    }
+
+    pub fn export(&self) -> Interchange {
+        let state = self.state.lock().unwrap();
+        let data = state
+            .validators
+            .values()
+            .map(|(pubkey, history)| {
+                let mut signed_price_values = Vec::new();
+                // Keeps the watermark through the import's "nothing at or below the lowest slot"
+                // rule.
+                if let Some(watermark) = history.watermark {
+                    if !history.signed.contains_key(&watermark) {
+                        signed_price_values.push(InterchangeSignedPriceValue {
+                            slot: watermark,
+                            signing_root: None,
+                        });
+                    }
+                }
+                signed_price_values.extend(history.signed.iter().map(|(slot, signing_root)| {
+                    InterchangeSignedPriceValue {
+                        slot: *slot,
+                        signing_root: *signing_root,
+                    }
+                }));
+                InterchangeValidator {
+                    pubkey: pubkey.clone(),
+                    signed_price_values,
+                }
+            })
+            .collect();
+        Interchange {
+            metadata: InterchangeMetadata {
+                interchange_format_version: INTERCHANGE_FORMAT_VERSION.to_string(),
+                genesis_time: BEACON_GENESIS.timestamp().to_string(),
+            },
+            data,
+        }
+    }
+
+    /// Merges an interchange file into the history. Like EIP-3076 prescribes, nothing at or below
+    /// the lowest imported slot of a key may be signed afterwards.
+    pub fn import(&self, interchange: &Interchange) -> Result<()> {
+        if interchange.metadata.interchange_format_version != INTERCHANGE_FORMAT_VERSION {
+            eyre::bail!(
+                "Unsupported interchange format version {}, expected {}",
+                interchange.metadata.interchange_format_version,
+                INTERCHANGE_FORMAT_VERSION
+            );
+        }
+        let genesis_time = BEACON_GENESIS.timestamp().to_string();
+        if interchange.metadata.genesis_time != genesis_time {
+            eyre::bail!(
+                "Interchange is for a chain with genesis time {}, expected {}",
+                interchange.metadata.genesis_time,
+                genesis_time
+            );
+        }
+        let mut state = self.state.lock().unwrap();
+        for validator in interchange.data.iter() {
+            let lowest_slot = validator
+                .signed_price_values
+                .iter()
+                .map(|signed_price_value| signed_price_value.slot)
+                .min();
+            if let Some(slot) = lowest_slot {
+                state.append(HistoryRecord::Watermark {
+                    pubkey: validator.pubkey.clone(),
+                    slot,
+                })?;
+            }
+            for signed_price_value in validator.signed_price_values.iter() {
+                state.append(HistoryRecord::Signed {
+                    pubkey: validator.pubkey.clone(),
+                    slot: signed_price_value.slot,
+                    signing_root: signed_price_value.signing_root,
+                })?;
+            }
+        }
+        self.compact_if_needed(&mut state)
+    }
+
+    /// Atomically replaces the history file with one only holding what we still need.
+    fn compact_if_needed(&self, state: &mut HistoryState) -> Result<()> {
+        let record_count = state
+            .validators
+            .values()
+            .map(|(_, history)| history.record_count())
+            .sum::<usize>();
+        if state.records_in_file <= record_count * 2 + COMPACTION_SLACK {
+            return Ok(());
+        }
+        log::debug!(
+            "Compacting slashing protection history with {} records",
+            state.records_in_file
+        );
+        let tmp_path = self.path.with_extension("tmp");
+        let mut tmp_file = File::create(&tmp_path)?;
+        for record in state.records() {
+            write_record(&mut tmp_file, &record)?;
+        }
+        tmp_file.sync_all()?;
+        std::fs::rename(&tmp_path, &self.path).wrap_err_with(|| {
+            format!(
+                "Failed to replace slashing protection history {}",
+                self.path.display()
+            )
+        })?;
+        state.file = OpenOptions::new().append(true).open(&self.path)?;
+        state.records_in_file = record_count;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        price_provider::Price, signature_provider::private_key::PrivateKeySignatureProvider,
+        signature_provider::SignatureProvider,
+    };
+
+    fn price_value_message(slot: u64, price: u64) -> PriceValueMessage {
+        PriceValueMessage {
+            price: Price { value: price },
+            slot_number: slot,
+        }
+    }
+
+    fn random_public_key() -> PublicKey {
+        PrivateKeySignatureProvider::random()
+            .get_public_key()
+            .unwrap()
+    }
+
+    #[test]
+    fn refuses_different_price_for_signed_slot() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.ndjson");
+        let pubkey = random_public_key();
+        let slashing_protection = SlashingProtection::open(&path).unwrap();
+
+        slashing_protection
+            .check_and_record(&pubkey, &price_value_message(10, 1000))
+            .unwrap();
+        slashing_protection
+            .check_and_record(&pubkey, &price_value_message(10, 1000))
+            .unwrap();
+        assert!(slashing_protection
+            .check_and_record(&pubkey, &price_value_message(10, 1001))
+            .is_err());
+        // Other keys and slots are unaffected.
+        slashing_protection
+            .check_and_record(&random_public_key(), &price_value_message(10, 1001))
+            .unwrap();
+        slashing_protection
+            .check_and_record(&pubkey, &price_value_message(11, 1001))
+            .unwrap();
+    }
+
+    #[test]
+    fn keeps_history_across_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.ndjson");
+        let pubkey = random_public_key();
+
+        let slashing_protection = SlashingProtection::open(&path).unwrap();
+        slashing_protection
+            .check_and_record(&pubkey, &price_value_message(10, 1000))
+            .unwrap();
+        assert!(SlashingProtection::open(&path).is_err());
+        drop(slashing_protection);
+
+        let slashing_protection = SlashingProtection::open(&path).unwrap();
+        assert!(slashing_protection
+            .check_and_record(&pubkey, &price_value_message(10, 1001))
+            .is_err());
+    }
+
+    #[test]
+    fn keeps_records_written_after_a_torn_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.ndjson");
+        let pubkey = random_public_key();
+        let slashing_protection = SlashingProtection::open(&path).unwrap();
+        slashing_protection
+            .check_and_record(&pubkey, &price_value_message(9, 1000))
+            .unwrap();
+        drop(slashing_protection);
+        // A crash while writing the next record.
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(br#"{"Signed":{"pubkey":"0x"#)
+            .unwrap();
+
+        let slashing_protection = SlashingProtection::open(&path).unwrap();
+        slashing_protection
+            .check_and_record(&pubkey, &price_value_message(10, 1000))
+            .unwrap();
+        drop(slashing_protection);
+
+        let slashing_protection = SlashingProtection::open(&path).unwrap();
+        for slot in [9, 10] {
+            assert!(slashing_protection
+                .check_and_record(&pubkey, &price_value_message(slot, 1001))
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn refuses_history_with_unreadable_records() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.ndjson");
+        let slashing_protection = SlashingProtection::open(&path).unwrap();
+        slashing_protection
+            .check_and_record(&random_public_key(), &price_value_message(10, 1000))
+            .unwrap();
+        drop(slashing_protection);
+        let contents = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, format!("not a record\n{}", contents)).unwrap();
+
+        assert!(SlashingProtection::open(&path).is_err());
+    }
+
+    #[test]
+    fn prunes_old_slots_behind_watermark() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("history.ndjson");
+        let pubkey = random_public_key();
+        let retained_slots = 20;
+        let slashing_protection =
+            SlashingProtection::open_with_retained_slots(&path, retained_slots).unwrap();
+
+        for slot in 0..retained_slots as u64 + 10 {
+            slashing_protection
+                .check_and_record(&pubkey, &price_value_message(slot, 1000))
+                .unwrap();
+        }
+
+        // Slot 9 was pruned, even the same price is refused now.
+        assert!(slashing_protection
+            .check_and_record(&pubkey, &price_value_message(9, 1000))
+            .is_err());
+        let line_count = std::fs::read_to_string(&path).unwrap().lines().count();
+        assert!(line_count <= (retained_slots + 1) * 2 + COMPACTION_SLACK);
+    }
+
+    #[test]
+    fn round_trips_through_interchange() {
+        let dir = tempfile::tempdir().unwrap();
+        let pubkey = random_public_key();
+        let source = SlashingProtection::open(&dir.path().join("source.ndjson")).unwrap();
+        source
+            .check_and_record(&pubkey, &price_value_message(10, 1000))
+            .unwrap();
+        source
+            .check_and_record(&pubkey, &price_value_message(12, 1000))
+            .unwrap();
+
+        let interchange = source.export();
+        let json = serde_json::to_value(&interchange).unwrap();
+        assert_eq!(json["metadata"]["interchange_format_version"], "5");
+        assert_eq!(json["data"][0]["signed_price_values"][0]["slot"], "10");
+
+        let target = SlashingProtection::open(&dir.path().join("target.ndjson")).unwrap();
+        target
+            .import(&serde_json::from_value(json).unwrap())
+            .unwrap();
+
+        assert!(target
+            .check_and_record(&pubkey, &price_value_message(12, 1001))
+            .is_err());
+        // At or below the lowest imported slot everything is refused.
+        assert!(target
+            .check_and_record(&pubkey, &price_value_message(10, 1000))
+            .is_err());
+        target
+            .check_and_record(&pubkey, &price_value_message(11, 1000))
+            .unwrap();
+        assert_eq!(target.export().data[0].signed_price_values.len(), 3);
+    }
+}
@@ -0,0 +1,555 @@
+//! # Verification
+//! Checks an `OracleMessage` the way a server receiving it should: every signature against the
+//! validator's public key, every message for the same slot, and an interval that is contiguous
+//! and brackets the signed price within its declared size. Rather than stopping at the first
+//! problem it reports every violation found.
+//!
+//! `verify_oracle_registration` checks the proof of possession of a registering key.
+
+use std::fmt::Display;
+
+use bls::{PublicKey, Signature};
+use ssz::Encode;
+
+use crate::{
+    message_broadcaster::{
+        MessageVersion, OracleMessage, OracleRegistration, SignedOracleRegistration,
+    },
+    message_generator::{interval_bounds, INTERVAL_PRECISION_FACTOR, ONE_IN_BASIS_POINTS},
+    price_provider::PRECISION_FACTOR,
+    signature_provider::private_key::message_digest,
+    slot::Slot,
+};
+
+#[derive(Clone, Debug, Default)]
+pub struct VerifyOptions {
+    /// Skip the signature checks, e.g. when they've been checked in aggregate already.
+    pub skip_signatures: bool,
+    /// The slot the message has to be for.
+    pub expected_slot: Option<Slot>,
+    /// The interval size the message has to declare.
+    pub expected_interval_size: Option<u64>,
+}
+
+/// Identifies one of the signed messages within an `OracleMessage`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignedMessageRef {
+    Value,
+    IntervalInclusion(usize),
+    IntervalBounds,
+}
+
+impl Display for SignedMessageRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SignedMessageRef::Value => write!(f, "value_message"),
+            SignedMessageRef::IntervalInclusion(i) => {
+                write!(f, "interval_inclusion_messages[{}]", i)
+            }
+            SignedMessageRef::IntervalBounds => write!(f, "interval_bounds_message"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Violation {
+    InvalidSignature {
+        message: SignedMessageRef,
+    },
+    SlotMismatch {
+        message: SignedMessageRef,
+        slot: u64,
+        expected: u64,
+    },
+    /// The version requires an interval the message doesn't have.
+    MissingInterval,
+    /// The message holds an interval its version doesn't allow.
+    UnexpectedInterval {
+        message: SignedMessageRef,
+    },
+    InvalidIntervalSize {
+        message: SignedMessageRef,
+        interval_size: u64,
+        expected: Option<u64>,
+    },
+    /// An interval inclusion value doesn't follow on the previous one.
+    NonContiguousInterval {
+        index: usize,
+        value: u64,
+        expected: u64,
+    },
+    /// The interval doesn't contain the price, or reaches further than its size allows.
+    IntervalOutOfBounds {
+        lower_bound: u64,
+        upper_bound: u64,
+        allowed_lower_bound: u64,
+        allowed_upper_bound: u64,
+        price: u64,
+    },
+}
+
+impl Display for Violation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Violation::InvalidSignature { message } => {
+                write!(f, "{}: invalid signature", message)
+            }
+            Violation::SlotMismatch {
+                message,
+                slot,
+                expected,
+            } => write!(f, "{}: slot {}, expected {}", message, slot, expected),
+            Violation::MissingInterval => write!(f, "message holds no interval"),
+            Violation::UnexpectedInterval { message } => {
+                write!(f, "{}: not allowed in this message version", message)
+            }
+            Violation::InvalidIntervalSize {
+                message,
+                interval_size,
+                expected: Some(expected),
+            } => write!(
+                f,
+                "{}: interval size {}, expected {}",
+                message, interval_size, expected
+            ),
+            Violation::InvalidIntervalSize {
+                message,
+                interval_size,
+                expected: None,
+            } => write!(
+                f,
+                "{}: interval size {} out of range 1..{}",
+                message, interval_size, ONE_IN_BASIS_POINTS
+            ),
+            Violation::NonContiguousInterval {
+                index,
+                value,
+                expected,
+            } => write!(
+                f,
+                "{}: value {}, expected {}",
+                SignedMessageRef::IntervalInclusion(*index),
+                value,
+                expected
+            ),
+            Violation::IntervalOutOfBounds {
+                lower_bound,
+                upper_bound,
+                allowed_lower_bound,
+                allowed_upper_bound,
+                price,
+            } => write!(
+                f,
+                "interval {}..{} must contain price {} within {}..{}",
+                lower_bound, upper_bound, price, allowed_lower_bound, allowed_upper_bound
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct VerificationReport {
+    pub violations: Vec<Violation>,
+}
+
+impl VerificationReport {
+    pub fn is_valid(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+impl Display for VerificationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for violation in self.violations.iter() {
+            writeln!(f, "{}", violation)?;
+        }
+        Ok(())
+    }
+}
+
+fn verify_signature(
+    violations: &mut Vec<Violation>,
+    public_key: &PublicKey,
+    message: SignedMessageRef,
+    ssz_bytes: &[u8],
+    signature: &Signature,
+) {
+    if !signature.verify(public_key, message_digest(ssz_bytes)) {
+        violations.push(Violation::InvalidSignature { message });
+    }
+}
+
+fn verify_interval_size(
+    violations: &mut Vec<Violation>,
+    options: &VerifyOptions,
+    message: SignedMessageRef,
+    interval_size: u64,
+) -> bool {
+    let is_valid = interval_size > 0
+        && interval_size < ONE_IN_BASIS_POINTS
+        && options.expected_interval_size.unwrap_or(interval_size) == interval_size;
+    if !is_valid {
+        violations.push(Violation::InvalidIntervalSize {
+            message,
+            interval_size,
+            expected: options.expected_interval_size,
+        });
+    }
+    is_valid
+}
+
+/// Checks the interval `lower_bound..upper_bound` contains the price and stays within the
+/// interval size around it.
+fn verify_interval_bounds(
+    violations: &mut Vec<Violation>,
+    price_value: u64,
+    interval_size: u64,
+    lower_bound: u64,
+    upper_bound: u64,
+) {
+    let (allowed_lower_bound, allowed_upper_bound) = interval_bounds(price_value, interval_size);
+    let price =
+        (price_value as u128 * INTERVAL_PRECISION_FACTOR as u128 / PRECISION_FACTOR as u128) as u64;
+    if lower_bound < allowed_lower_bound
+        || upper_bound > allowed_upper_bound
+        || price < lower_bound
+        || price >= upper_bound
+    {
+        violations.push(Violation::IntervalOutOfBounds {
+            lower_bound,
+            upper_bound,
+            allowed_lower_bound,
+            allowed_upper_bound,
+            price,
+        });
+    }
+}
+
+fn verify_slot(
+    violations: &mut Vec<Violation>,
+    message: SignedMessageRef,
+    slot: u64,
+    expected: u64,
+) {
+    if slot != expected {
+        violations.push(Violation::SlotMismatch {
+            message,
+            slot,
+            expected,
+        });
+    }
+}
+
+/// Checks everything that makes an `OracleMessage` valid, returning all violations found.
+pub fn verify_oracle_message(
+    oracle_message: &OracleMessage,
+    options: &VerifyOptions,
+) -> VerificationReport {
+    let mut violations = Vec::new();
+    let public_key = &oracle_message.validator_public_key;
+    let value_message = &oracle_message.value_message.message;
+    let slot = value_message.slot_number;
+    let price_value = value_message.price.value;
+
+    if let Some(expected_slot) = options.expected_slot {
+        verify_slot(
+            &mut violations,
+            SignedMessageRef::Value,
+            slot,
+            expected_slot.0,
+        );
+    }
+    if !options.skip_signatures {
+        verify_signature(
+            &mut violations,
+            public_key,
+            SignedMessageRef::Value,
+            &value_message.as_ssz_bytes(),
+            &oracle_message.value_message.signature,
+        );
+    }
+
+    let interval_inclusion_messages = &oracle_message.interval_inclusion_messages;
+    match oracle_message.version {
+        MessageVersion::V1 if interval_inclusion_messages.is_empty() => {
+            violations.push(Violation::MissingInterval)
+        }
+        MessageVersion::V1 => {}
+        MessageVersion::V2 => {
+            if !interval_inclusion_messages.is_empty() {
+                violations.push(Violation::UnexpectedInterval {
+                    message: SignedMessageRef::IntervalInclusion(0),
+                });
+            }
+            if oracle_message.interval_bounds_message.is_none() {
+                violations.push(Violation::MissingInterval)
+            }
+        }
+    }
+
+    let mut previous_value = None;
+    for (i, signed_message) in interval_inclusion_messages.iter().enumerate() {
+        let message_ref = SignedMessageRef::IntervalInclusion(i);
+        let message = &signed_message.message;
+        verify_slot(&mut violations, message_ref, message.slot_number, slot);
+        if let Some(previous_value) = previous_value {
+            let expected = u64::saturating_add(previous_value, 1);
+            if message.value != expected {
+                violations.push(Violation::NonContiguousInterval {
+                    index: i,
+                    value: message.value,
+                    expected,
+                });
+            }
+        }
+        previous_value = Some(message.value);
+        if !options.skip_signatures {
+            verify_signature(
+                &mut violations,
+                public_key,
+                message_ref,
+                &message.as_ssz_bytes(),
+                &signed_message.signature,
+            );
+        }
+    }
+    if let (Some(first), Some(last)) = (
+        interval_inclusion_messages.first(),
+        interval_inclusion_messages.last(),
+    ) {
+        let interval_size = first.message.interval_size;
+        let mut sizes_valid = true;
+        for (i, signed_message) in interval_inclusion_messages.iter().enumerate() {
+            if signed_message.message.interval_size != interval_size {
+                sizes_valid = false;
+                violations.push(Violation::InvalidIntervalSize {
+                    message: SignedMessageRef::IntervalInclusion(i),
+                    interval_size: signed_message.message.interval_size,
+                    expected: Some(interval_size),
+                });
+            }
+        }
+        if verify_interval_size(
+            &mut violations,
+            options,
+            SignedMessageRef::IntervalInclusion(0),
+            interval_size,
+        ) && sizes_valid
+        {
+            verify_interval_bounds(
+                &mut violations,
+                price_value,
+                interval_size,
+                first.message.value,
+                last.message.value.saturating_add(1),
+            );
+        }
+    }
+
+    if let Some(signed_message) = &oracle_message.interval_bounds_message {
+        let message_ref = SignedMessageRef::IntervalBounds;
+        let message = &signed_message.message;
+        if oracle_message.version == MessageVersion::V1 {
+            violations.push(Violation::UnexpectedInterval {
+                message: message_ref,
+            });
+        }
+        verify_slot(&mut violations, message_ref, message.slot_number, slot);
+        if verify_interval_size(&mut violations, options, message_ref, message.interval_size) {
+            verify_interval_bounds(
+                &mut violations,
+                price_value,
+                message.interval_size,
+                message.lower_bound,
+                message.upper_bound,
+            );
+        }
+        if !options.skip_signatures {
+            verify_signature(
+                &mut violations,
+                public_key,
+                message_ref,
+                &message.as_ssz_bytes(),
+                &signed_message.signature,
+            );
+        }
+    }
+
+    VerificationReport { violations }
+}
+
+/// Checks the proof of possession and signature of a registration, errors on the first invalid one.
+pub fn verify_oracle_registration(registration: &SignedOracleRegistration) -> eyre::Result<()> {
+    let public_key = &registration.message.validator_public_key;
+    if !registration.message.proof_of_possession.verify(
+        public_key,
+        message_digest(&OracleRegistration::proof_of_possession_message(public_key)),
+    ) {
+        eyre::bail!("invalid proof of possession for {}", public_key);
+    }
+    if !registration.signature.verify(
+        public_key,
+        message_digest(&registration.message.as_ssz_bytes()),
+    ) {
+        eyre::bail!("invalid registration signature for {}", public_key);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        message_generator::{MessageGenerator, MessageGeneratorConfig},
+        price_provider::Price,
+        signature_provider::{private_key::PrivateKeySignatureProvider, SignatureProvider},
+    };
+
+    fn generate_message(message_version: MessageVersion) -> OracleMessage {
+        MessageGenerator::new_with_config(
+            Box::new(PrivateKeySignatureProvider::random()),
+            MessageGeneratorConfig {
+                message_version,
+                ..MessageGeneratorConfig::default()
+            },
+        )
+        .generate_oracle_message(
+            Price {
+                value: 1000 * PRECISION_FACTOR,
+            },
+            Slot(1),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn accepts_generated_messages() {
+        for message_version in [MessageVersion::V1, MessageVersion::V2] {
+            let report = verify_oracle_message(
+                &generate_message(message_version),
+                &VerifyOptions {
+                    expected_slot: Some(Slot(1)),
+                    ..VerifyOptions::default()
+                },
+            );
+            assert!(report.is_valid(), "{}", report);
+        }
+    }
+
+    #[test]
+    fn reports_every_violation() {
+        let mut oracle_message = generate_message(MessageVersion::V1);
+        oracle_message.value_message.message.price.value += 1;
+        oracle_message.interval_inclusion_messages[2]
+            .message
+            .slot_number = 5;
+        oracle_message.interval_inclusion_messages.remove(10);
+
+        let report = verify_oracle_message(&oracle_message, &VerifyOptions::default());
+
+        assert_eq!(
+            report.violations,
+            vec![
+                Violation::InvalidSignature {
+                    message: SignedMessageRef::Value
+                },
+                Violation::SlotMismatch {
+                    message: SignedMessageRef::IntervalInclusion(2),
+                    slot: 5,
+                    expected: 1,
+                },
+                Violation::InvalidSignature {
+                    message: SignedMessageRef::IntervalInclusion(2)
+                },
+                Violation::NonContiguousInterval {
+                    index: 10,
+                    value: 99_811,
+                    expected: 99_810,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_interval_wider_than_its_size() {
+        let mut oracle_message = generate_message(MessageVersion::V2);
+        if let Some(interval_bounds_message) = oracle_message.interval_bounds_message.as_mut() {
+            interval_bounds_message.message.lower_bound -= 1;
+        }
+
+        let report = verify_oracle_message(
+            &oracle_message,
+            &VerifyOptions {
+                skip_signatures: true,
+                ..VerifyOptions::default()
+            },
+        );
+
+        assert_eq!(
+            report.violations,
+            vec![Violation::IntervalOutOfBounds {
+                lower_bound: 99_799,
+                upper_bound: 100_200,
+                allowed_lower_bound: 99_800,
+                allowed_upper_bound: 100_200,
+                price: 100_000,
+            }]
+        );
+    }
+
+    #[test]
+    fn checks_interval_matches_version_and_options() {
+        let mut oracle_message = generate_message(MessageVersion::V1);
+        oracle_message.interval_bounds_message =
+            generate_message(MessageVersion::V2).interval_bounds_message;
+
+        let report = verify_oracle_message(
+            &oracle_message,
+            &VerifyOptions {
+                skip_signatures: true,
+                expected_interval_size: Some(10),
+                ..VerifyOptions::default()
+            },
+        );
+
+        assert_eq!(
+            report.violations,
+            vec![
+                Violation::InvalidIntervalSize {
+                    message: SignedMessageRef::IntervalInclusion(0),
+                    interval_size: 20,
+                    expected: Some(10),
+                },
+                Violation::UnexpectedInterval {
+                    message: SignedMessageRef::IntervalBounds
+                },
+                Violation::InvalidIntervalSize {
+                    message: SignedMessageRef::IntervalBounds,
+                    interval_size: 20,
+                    expected: Some(10),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn checks_registration_proof_of_possession() {
+        let message_generator =
+            MessageGenerator::new(Box::new(PrivateKeySignatureProvider::random()));
+        let registration = message_generator
+            .generate_registration(Default::default(), "operator".to_string())
+            .unwrap();
+        verify_oracle_registration(&registration).unwrap();
+
+        // A proof of possession made for another key, as in a rogue-key attack.
+        let mut rogue_registration = registration.clone();
+        rogue_registration.message.validator_public_key = PrivateKeySignatureProvider::random()
+            .get_public_key()
+            .unwrap();
+        assert!(verify_oracle_registration(&rogue_registration).is_err());
+
+        let mut tampered_registration = registration;
+        tampered_registration.message.operator = "someone else".to_string();
+        assert!(verify_oracle_registration(&tampered_registration).is_err());
+    }
+}
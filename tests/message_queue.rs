@@ -0,0 +1,176 @@
+//! Publishing to a locally spawned nats-server and redis-server. The tests need both on the
+//! `PATH`, so they're ignored by default, run them with
+//! `cargo test --test message_queue -- --ignored`.
+
+use std::{
+    net::TcpListener,
+    path::Path,
+    process::{Child, Command, Stdio},
+    time::Duration,
+};
+
+use oracle_client::{
+    message_broadcaster::{
+        nats::NatsMessageBroadcaster, redis_stream::RedisStreamMessageBroadcaster,
+    },
+    message_generator::MessageGenerator,
+    signature_provider::private_key::PrivateKeySignatureProvider,
+    MessageBroadcaster, OracleMessage, Price, Slot,
+};
+
+/// Kills the server when the test is done.
+struct Server(Child);
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Starts `program` and waits until it listens on `port`.
+async fn spawn_server(program: &str, args: &[&str], port: u16) -> Server {
+    let child = match Command::new(program)
+        .args(args)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+    {
+        Ok(child) => Server(child),
+        Err(e) => panic!("failed to start {}, is it installed? {}", program, e),
+    };
+    for _ in 0..50 {
+        if tokio::net::TcpStream::connect(("127.0.0.1", port))
+            .await
+            .is_ok()
+        {
+            return child;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("{} didn't start listening on port {}", program, port);
+}
+
+fn generate_message(slot: u64) -> OracleMessage {
+    MessageGenerator::new(Box::new(PrivateKeySignatureProvider::random()))
+        .generate_oracle_message(
+            Price {
+                value: 1_000_000_000,
+            },
+            Slot(slot),
+        )
+        .unwrap()
+}
+
+async fn spawn_nats_server(store_dir: &Path) -> (Server, String) {
+    let port = free_port();
+    let server = spawn_server(
+        "nats-server",
+        &[
+            "--jetstream",
+            "--port",
+            &port.to_string(),
+            "--store_dir",
+            store_dir.to_str().unwrap(),
+        ],
+        port,
+    )
+    .await;
+    (server, format!("nats://127.0.0.1:{}", port))
+}
+
+#[tokio::test]
+#[ignore = "needs nats-server, run with --ignored"]
+async fn publishes_to_nats_jetstream() {
+    let store_dir = tempfile::tempdir().unwrap();
+    let (_server, server_url) = spawn_nats_server(store_dir.path()).await;
+    let jetstream = async_nats::jetstream::new(async_nats::connect(&server_url).await.unwrap());
+    let mut stream = jetstream
+        .create_stream(async_nats::jetstream::stream::Config {
+            name: "ORACLE".to_string(),
+            subjects: vec!["oracle.>".to_string()],
+            allow_direct: true,
+            ..Default::default()
+        })
+        .await
+        .unwrap();
+    let broadcaster = NatsMessageBroadcaster::new(&server_url, "oracle.mainnet.ETH-USD");
+    let msg = generate_message(5);
+
+    broadcaster.broadcast(&msg).await.unwrap();
+    // A redelivery is acked but not stored twice.
+    broadcaster.broadcast(&msg).await.unwrap();
+
+    assert_eq!(stream.info().await.unwrap().state.messages, 1);
+    let stored = stream.direct_get(1).await.unwrap();
+    assert_eq!(stored.subject.as_str(), "oracle.mainnet.ETH-USD");
+    let stored_msg: OracleMessage = serde_json::from_slice(&stored.payload).unwrap();
+    assert_eq!(stored_msg.id(), msg.id());
+}
+
+#[tokio::test]
+#[ignore = "needs nats-server, run with --ignored"]
+async fn fails_without_jetstream_stream() {
+    let store_dir = tempfile::tempdir().unwrap();
+    let (_server, server_url) = spawn_nats_server(store_dir.path()).await;
+    let broadcaster = NatsMessageBroadcaster::new(&server_url, "oracle.mainnet.ETH-USD");
+
+    assert!(broadcaster.broadcast(&generate_message(5)).await.is_err());
+}
+
+#[tokio::test]
+#[ignore = "needs redis-server, run with --ignored"]
+async fn adds_to_redis_stream() {
+    let port = free_port();
+    let _server = spawn_server(
+        "redis-server",
+        &[
+            "--port",
+            &port.to_string(),
+            "--save",
+            "",
+            "--appendonly",
+            "no",
+        ],
+        port,
+    )
+    .await;
+    let server_url = format!("redis://127.0.0.1:{}", port);
+    let broadcaster = RedisStreamMessageBroadcaster::new(&server_url, "oracle.mainnet.ETH-USD");
+    let messages = [generate_message(5), generate_message(6)];
+
+    for msg in &messages {
+        broadcaster.broadcast(msg).await.unwrap();
+    }
+
+    let mut connection = redis::Client::open(server_url.as_str())
+        .unwrap()
+        .get_async_connection()
+        .await
+        .unwrap();
+    let entries: redis::streams::StreamRangeReply = redis::cmd("XRANGE")
+        .arg("oracle.mainnet.ETH-USD")
+        .arg("-")
+        .arg("+")
+        .query_async(&mut connection)
+        .await
+        .unwrap();
+    assert_eq!(entries.ids.len(), 2);
+    for (entry, msg) in entries.ids.iter().zip(&messages) {
+        assert_eq!(entry.get::<String>("id"), Some(msg.id()));
+        let stored_msg: OracleMessage =
+            serde_json::from_str(&entry.get::<String>("message").unwrap()).unwrap();
+        assert_eq!(
+            stored_msg.value_message.message.slot_number,
+            msg.value_message.message.slot_number
+        );
+    }
+}
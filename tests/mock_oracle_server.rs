@@ -0,0 +1,232 @@
+//! Several clients submitting to the mock oracle server, end to end over HTTP.
+
+use std::net::SocketAddr;
+
+use ethers::types::Address;
+
+use oracle_client::{
+    aggregation::SlotAggregate,
+    message_broadcaster::{
+        http::{HttpMessageBroadcaster, HttpMessageFeed, MessageEncoding},
+        response::{BroadcastError, RejectionReason, SubmissionResponse},
+        MessageFeed,
+    },
+    message_generator::MessageGenerator,
+    mock_oracle_server::{router, MockOracleServerState},
+    price_provider::PRECISION_FACTOR,
+    signature_provider::private_key::PrivateKeySignatureProvider,
+    MessageBroadcaster, OracleMessage, Price, SignatureProvider, SignedOracleRegistration, Slot,
+};
+
+async fn spawn_mock_oracle_server() -> SocketAddr {
+    let server = axum::Server::bind(&"127.0.0.1:0".parse().unwrap())
+        .serve(router(MockOracleServerState::new()).into_make_service());
+    let addr = server.local_addr();
+    tokio::spawn(server);
+    addr
+}
+
+fn generate_message(signature_provider: &PrivateKeySignatureProvider, price: u64) -> OracleMessage {
+    MessageGenerator::new(signature_provider.clone())
+        .generate_oracle_message(
+            Price {
+                value: price * PRECISION_FACTOR,
+            },
+            Slot(100),
+        )
+        .unwrap()
+}
+
+async fn register(
+    broadcaster: &HttpMessageBroadcaster,
+    signature_provider: &PrivateKeySignatureProvider,
+) {
+    let registration = MessageGenerator::new(signature_provider.clone())
+        .generate_registration(Address::repeat_byte(0x11), "test operator".to_string())
+        .unwrap();
+    broadcaster
+        .broadcast_registration(&registration)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn aggregates_messages_from_several_clients() {
+    let addr = spawn_mock_oracle_server().await;
+    let server_url = format!("http://{}/post_oracle_message", addr);
+    let signature_providers = (0..3)
+        .map(|_| PrivateKeySignatureProvider::random())
+        .collect::<Vec<_>>();
+
+    // Every client uses a different encoding and sees a slightly different price.
+    for ((signature_provider, encoding), price) in signature_providers
+        .iter()
+        .zip([
+            MessageEncoding::Json,
+            MessageEncoding::Ssz,
+            MessageEncoding::SszSnappy,
+        ])
+        .zip([1000, 1001, 1002])
+    {
+        let broadcaster = HttpMessageBroadcaster::new_with_encoding(&server_url, encoding);
+        register(&broadcaster, signature_provider).await;
+        broadcaster
+            .broadcast(&generate_message(signature_provider, price))
+            .await
+            .unwrap();
+    }
+    // Accepted, but not aggregated without a registration.
+    let unregistered = PrivateKeySignatureProvider::random();
+    HttpMessageBroadcaster::new_with_url(&server_url)
+        .broadcast(&generate_message(&unregistered, 1000))
+        .await
+        .unwrap();
+
+    let slots: Vec<u64> = reqwest::get(format!("http://{}/slots", addr))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(slots, vec![100]);
+
+    let slot_aggregate: SlotAggregate = reqwest::get(format!("http://{}/slots/100", addr))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(slot_aggregate.validator_public_keys.len(), 3);
+    assert!(!slot_aggregate
+        .validator_public_keys
+        .contains(&unregistered.get_public_key().unwrap()));
+    assert!(slot_aggregate.verify().is_empty());
+    let values = slot_aggregate
+        .interval_value_aggregates
+        .iter()
+        .map(|aggregate| aggregate.message.value)
+        .collect::<Vec<_>>();
+    // 998.00 to 1004.00, only 999.99 to 1002.00 is covered by all three intervals.
+    assert_eq!(values.first(), Some(&99_800));
+    assert_eq!(values.last(), Some(&100_399));
+
+    let slot_aggregate: SlotAggregate =
+        reqwest::get(format!("http://{}/slots/100?threshold=3", addr))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+    assert!(slot_aggregate.verify().is_empty());
+    let values = slot_aggregate
+        .interval_value_aggregates
+        .iter()
+        .map(|aggregate| aggregate.message.value)
+        .collect::<Vec<_>>();
+    assert_eq!(values.first(), Some(&99_999));
+    assert_eq!(values.last(), Some(&100_199));
+}
+
+#[tokio::test]
+async fn rejects_invalid_and_conflicting_messages() {
+    let addr = spawn_mock_oracle_server().await;
+    let server_url = format!("http://{}/post_oracle_message", addr);
+    let broadcaster = HttpMessageBroadcaster::new_with_url(&server_url);
+    let signature_provider = PrivateKeySignatureProvider::random();
+
+    let mut tampered_message = generate_message(&signature_provider, 1000);
+    tampered_message.value_message.message.price.value += 1;
+    let response = reqwest::Client::new()
+        .post(&server_url)
+        .json(&tampered_message)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), reqwest::StatusCode::BAD_REQUEST);
+    let rejection: SubmissionResponse = response.json().await.unwrap();
+    assert_eq!(rejection.reason, Some(RejectionReason::BadSignature));
+    assert_eq!(
+        rejection.violations,
+        vec!["value_message: invalid signature"]
+    );
+
+    let message = generate_message(&signature_provider, 1000);
+    broadcaster.broadcast(&message).await.unwrap();
+    // Redelivering the same message is fine, a different one for the same slot is not.
+    broadcaster.broadcast(&message).await.unwrap();
+    let conflict = broadcaster
+        .broadcast(&generate_message(&signature_provider, 1001))
+        .await
+        .unwrap_err();
+    assert_eq!(
+        BroadcastError::find(&conflict).unwrap().reason,
+        Some(RejectionReason::Conflict)
+    );
+
+    let messages: Vec<OracleMessage> = reqwest::get(format!("http://{}/slots/100/messages", addr))
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    assert_eq!(messages.len(), 1);
+    assert_eq!(
+        messages[0].validator_public_key,
+        signature_provider.get_public_key().unwrap()
+    );
+}
+
+#[tokio::test]
+async fn serves_messages_as_feed() {
+    let addr = spawn_mock_oracle_server().await;
+    let signature_provider = PrivateKeySignatureProvider::random();
+    HttpMessageBroadcaster::new_with_url(&format!("http://{}/post_oracle_message", addr))
+        .broadcast(&generate_message(&signature_provider, 1000))
+        .await
+        .unwrap();
+
+    let feed = HttpMessageFeed::new(&format!("http://{}", addr));
+
+    let public_key = signature_provider.get_public_key().unwrap();
+    assert!(feed.has_message(100, &public_key).await.unwrap());
+    assert!(!feed.has_message(101, &public_key).await.unwrap());
+    let other_public_key = PrivateKeySignatureProvider::random()
+        .get_public_key()
+        .unwrap();
+    assert!(!feed.has_message(100, &other_public_key).await.unwrap());
+}
+
+#[tokio::test]
+async fn accepts_registrations_with_proof_of_possession() {
+    let addr = spawn_mock_oracle_server().await;
+    let broadcaster =
+        HttpMessageBroadcaster::new_with_url(&format!("http://{}/post_oracle_message", addr));
+    let message_generator = MessageGenerator::new(Box::new(PrivateKeySignatureProvider::random()));
+    let registration = message_generator
+        .generate_registration(Address::repeat_byte(0x11), "test operator".to_string())
+        .unwrap();
+
+    broadcaster
+        .broadcast_registration(&registration)
+        .await
+        .unwrap();
+
+    // A key registered with someone else's proof of possession.
+    let mut rogue_registration = registration.clone();
+    rogue_registration.message.validator_public_key = PrivateKeySignatureProvider::random()
+        .get_public_key()
+        .unwrap();
+    assert!(broadcaster
+        .broadcast_registration(&rogue_registration)
+        .await
+        .is_err());
+
+    let registrations: Vec<SignedOracleRegistration> =
+        reqwest::get(format!("http://{}/registrations", addr))
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+    assert_eq!(registrations, vec![registration]);
+}